@@ -1,12 +1,20 @@
 /// Abstract Syntax Tree (AST) for a simple programming language.
 /// This module defines the structure of the AST nodes.
+use std::convert::TryFrom;
+use std::fmt;
+
 #[derive(Debug, Clone)]
 /// Represents the different types of expressions in the language.
 pub enum Expr {
     /// A numeric literal (e.g., 42)
     Number(i32),
+    /// A floating-point literal (e.g., 2.5)
+    Float(f64),
     /// A variable reference (e.g., x)
     Variable(String),
+    /// A variable reference the resolver has located: the variable in slot `slot` of the scope
+    /// `depth` scopes out from the innermost one (see `resolver`)
+    Local { name: String, depth: usize, slot: usize },
     /// A boolean literal (e.g., true or false)
     Boolean(bool),
     /// A character literal (e.g., 'a')
@@ -15,6 +23,14 @@ pub enum Expr {
     ArrayLiteral(Vec<Expr>),
     /// An array index expression (e.g., arr[0])
     ArrayIndex(Box<Expr>, Box<Expr>),
+    /// A slice of an array or string from `start` up to but not including `end` (e.g., arr[1..3])
+    Slice { target: Box<Expr>, start: Box<Expr>, end: Box<Expr> },
+    /// A struct literal naming its fields (e.g., Point { x: 1, y: 2 }); fields left out are zero
+    StructLiteral { name: String, fields: Vec<(String, Expr)> },
+    /// A struct field access (e.g., p.x)
+    Member(Box<Expr>, String),
+    /// A call of a function value that isn't named directly (e.g., handlers[0](3))
+    Call { callee: Box<Expr>, args: Vec<Expr> },
     /// A string literal (e.g., "Hello")
     StringLiteral(String),
     /// Pre-increment operation (e.g., ++x)
@@ -52,12 +68,20 @@ pub enum Expr {
     EnumValue(String, String),
     /// SizeOf operator to get the size of a type
     SizeOf(Type),
+    /// SizeOf operator applied to a variable whose size is only known at runtime (e.g., sizeof(arr))
+    SizeOfValue(Box<Expr>),
     /// Type casting (e.g., (int)x)
     Cast(Type, Box<Expr>),
     /// Address-of operator (e.g., &x)
     AddressOf(Box<Expr>),
     /// Dereference operator (e.g., *x)
     Deref(Box<Expr>),
+    /// Block expression evaluated in its own scope (e.g., { let a = 2; a * 3 })
+    /// Its value is the final expression, which has no semicolon.
+    Block {
+        stmts: Vec<Stmt>,
+        value: Box<Expr>,
+    },
 }
 
 /// Represents the different binary operators in the language.
@@ -72,6 +96,8 @@ pub enum BinOp {
     Mul,
     /// Division operator (e.g., a / b)
     Div,
+    /// Exponentiation operator (e.g., a ** b), which groups to the right
+    Pow,
     /// Equality operator (e.g., a == b)
     Equal,
     /// Inequality operator (e.g., a != b)
@@ -104,11 +130,44 @@ pub enum BinOp {
     Shr,
 }
 
+impl fmt::Display for BinOp {
+    /// Formats the operator as it is written in source code (e.g., `+`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Pow => "**",
+            BinOp::Equal => "==",
+            BinOp::NotEqual => "!=",
+            BinOp::LessThan => "<",
+            BinOp::GreaterThan => ">",
+            BinOp::LessEqual => "<=",
+            BinOp::GreaterEqual => ">=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::Assign => "=",
+            BinOp::Mod => "%",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 /// Represents the different unary operators in the language.
 #[derive(Debug, Clone)]
 pub enum UnOp {
     /// Logical NOT operator (e.g., !x)
     Not,
+    /// Arithmetic negation (e.g., -x)
+    Neg,
+    /// Bitwise NOT operator (e.g., ~x)
+    BitNot,
 }
 
 /// Represents runtime values (integers and strings).
@@ -128,29 +187,86 @@ pub enum Type {
     Int,
     /// Character type
     Char,
+    /// Floating-point type (values are stored as `f64`)
+    Float,
     /// Pointer type (e.g., int* or char*)
     Pointer(Box<Type>),
     /// Void type (for functions that do not return a value)
     Void,
-    /// Array type (e.g., int[3])
+    /// Array type (e.g., int[3]). `int[2][3]` is an array of 2 arrays of 3 ints, as in C.
     Array(Box<Type>, usize),
+    /// Struct type with its name and fields in declaration order (e.g., struct Point { int x; int y; })
+    Struct(String, Vec<(String, Type)>),
+}
+
+impl fmt::Display for Type {
+    /// Formats the type as it is written in source code (e.g., `int*` or `char[4]`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Float => write!(f, "float"),
+            Type::Pointer(inner) => write!(f, "{}*", inner),
+            Type::Void => write!(f, "void"),
+            Type::Array(elem, len) => write!(f, "{}[{}]", elem, len),
+            Type::Struct(name, _) => write!(f, "struct {}", name),
+        }
+    }
+}
+
+impl Type {
+    /// Returns the size of the type in bytes, as reported by `sizeof`, or `None` if it is too
+    /// large for an `int`. Structs are the sum of their fields' sizes; fields are not padded.
+    pub fn size(&self) -> Option<i32> {
+        match self {
+            Type::Int => Some(4),
+            Type::Char => Some(1),
+            Type::Float => Some(4), // As in C, even though values are stored with double precision
+            Type::Pointer(_) => Some(8),
+            Type::Void => Some(0),
+            Type::Array(elem_type, len) => elem_type.size()?.checked_mul(i32::try_from(*len).ok()?), // Nested arrays multiply out
+            Type::Struct(_, fields) => fields.iter().try_fold(0i32, |total, (_, t)| total.checked_add(t.size()?)),
+        }
+    }
+
+    /// Returns the value a variable of this type holds when declared without an initializer
+    /// (e.g., `let a: int[4];`): zero, or an empty initializer list that arrays and structs
+    /// are zero-padded from.
+    pub fn default_initializer(&self) -> Expr {
+        match self {
+            Type::Float => Expr::Float(0.0),
+            Type::Pointer(inner) if **inner == Type::Char => Expr::StringLiteral(String::new()),
+            Type::Array(_, _) | Type::Struct(_, _) => Expr::ArrayLiteral(Vec::new()),
+            _ => Expr::Number(0),
+        }
+    }
 }
 
 /// Represents the different types of statements in the language.
 /// This includes control flow, variable declarations, and functions.
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Stmt {
     /// Return statement (e.g., return 42;)
     Return(Expr),
-    /// Print statement (e.g., print("Hello");)
-    Print(Expr),
+    /// Print statement, which writes a value without ending the line (e.g., print("Hello");),
+    /// or with `newline` set, ends it after the value (e.g., println("Hello");)
+    Print { value: Expr, newline: bool },
     /// Expression statement (e.g., x = 42;)
     ExprStmt(Expr),
-    /// Block of statements (e.g., { ... })
+    /// Block of statements (e.g., { ... }), which has its own scope
     Block(Vec<Stmt>),
+    /// The declarations of one `let` statement declaring several variables (e.g., let a = 1, b = 2;).
+    /// Unlike a block, they are declared in the enclosing scope.
+    LetGroup(Vec<Stmt>),
     /// Variable declaration (e.g., let x = 42;)
     #[allow(dead_code)]
     Let { name: String, value: Expr, var_type: Option<Type> },
+    /// Constant declaration, a global that can't be assigned (e.g., const LIMIT = 10;)
+    Const { name: String, value: Expr },
+    /// Destructuring declaration that unpacks an array into variables (e.g., let [a, b] = f();),
+    /// optionally collecting the remaining elements into a rest variable (e.g., let [head, ...tail] = arr;)
+    LetArray { names: Vec<String>, rest: Option<String>, value: Expr },
     /// Assignment statement (e.g., x = 42;)
     #[allow(dead_code)]
     Assign {
@@ -168,11 +284,76 @@ pub enum Stmt {
         condition: Expr,
         body: Box<Stmt>,
     },
+    /// Do-while loop, which runs its body before checking the condition (e.g., do { ... } while (x > 0);)
+    DoWhile {
+        body: Box<Stmt>,
+        condition: Expr,
+    },
+    /// For loop (e.g., for (let i = 0; i < n; i = i + 1) { ... })
+    /// Each clause may be left out; a missing condition is always true.
+    For {
+        init: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        update: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    /// Switch statement (e.g., switch (x) { case 1: ... default: ... })
+    /// Each case breaks implicitly; a case with no statements shares the body of the next one.
+    Switch {
+        scrutinee: Expr,
+        cases: Vec<(Expr, Vec<Stmt>)>,
+        default: Option<Vec<Stmt>>,
+    },
+    /// Try statement (e.g., try { ... } catch (e) { ... })
+    /// A runtime error in `body` runs `handler` instead, with the error message bound to `name`.
+    Try {
+        body: Box<Stmt>,
+        name: String,
+        handler: Box<Stmt>,
+    },
+    /// A statement annotated with the source line it starts on.
+    /// The parser wraps each statement of a program or block in one of these.
+    Located {
+        line: usize,
+        stmt: Box<Stmt>,
+    },
     /// Function definition (e.g., function foo() { ... })
+    /// A variadic function (`int sum(count, ...)`) receives its extra arguments in an array named `args`.
     Function {
         name: String,
-        params: Vec<String>,
+        params: Vec<Param>,
+        variadic: bool,
         body: Box<Stmt>,
         return_type: Option<Type>,
     },
 }
+
+impl Stmt {
+    /// Returns the statement without its source line annotation, if it has one.
+    pub fn unlocated(&self) -> &Stmt {
+        match self {
+            Stmt::Located { stmt, .. } => stmt.unlocated(),
+            stmt => stmt,
+        }
+    }
+}
+
+/// Represents a function parameter.
+///
+/// Arguments are passed by value: the callee receives a copy, so changes to an array
+/// parameter are not visible to the caller. A parameter marked with `ref`
+/// (e.g. `void fill(ref arr)`) is passed by reference instead: the caller must pass a
+/// variable, and the parameter's final value is written back to it when the call returns.
+///
+/// A parameter may have a default value (e.g. `int greet(name, times = 1)`), used when a
+/// call leaves out that argument. Defaults are evaluated in the callee's scope, so they can
+/// refer to earlier parameters, and only trailing parameters may have them.
+#[derive(Debug, Clone)]
+pub struct Param {
+    /// The parameter name.
+    pub name: String,
+    /// Whether the parameter is passed by reference (`ref`).
+    pub by_ref: bool,
+    /// The value to use when the argument is omitted, if any.
+    pub default: Option<Expr>,
+}
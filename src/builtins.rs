@@ -0,0 +1,399 @@
+/// Built-in functions available to C4 programs.
+/// Builtins are looked up only when no user-defined function has the same name,
+/// so a program may still define its own `contains`, `index_of`, etc.
+use crate::vm::{RuntimeError, Value, Vm};
+use std::convert::TryInto;
+
+impl Vm {
+    /// Calls a builtin that modifies its first argument, an array variable.
+    ///
+    /// # Parameters
+    /// - `name`: The name of the builtin (see `is_mutating_builtin`).
+    /// - `args`: The evaluated arguments, starting with the array.
+    ///
+    /// # Returns
+    /// The result of the call and the modified array, which the caller writes back
+    /// into the variable, as for a `ref` parameter.
+    pub(crate) fn call_mutating_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<(Value, Value), RuntimeError> {
+        match name {
+            // pop(arr): removes and returns the last element
+            "pop" => match expect_args::<1>(name, args)? {
+                [Value::Array(mut elements)] => match elements.pop() {
+                    Some(last) => Ok((last, Value::Array(elements))),
+                    None => Err(RuntimeError::Other("pop() from an empty array".to_string())),
+                },
+                [v] => Err(RuntimeError::TypeMismatch(format!("pop() requires an array, got {}", v))),
+            },
+            // push(arr, v): appends `v` and returns the new length
+            "push" => match expect_args::<2>(name, args)? {
+                [Value::Array(mut elements), value] => {
+                    elements.push(value);
+                    self.check_size("array", elements.len())?;
+                    Ok((Value::Int(elements.len() as i32), Value::Array(elements)))
+                }
+                [v, _] => Err(RuntimeError::TypeMismatch(format!("push() requires an array, got {}", v))),
+            },
+            _ => unreachable!("'{}' is not a mutating builtin", name),
+        }
+    }
+
+    /// Calls the builtin function `name` with already-evaluated arguments.
+    ///
+    /// # Parameters
+    /// - `name`: The name of the builtin.
+    /// - `args`: The evaluated arguments.
+    ///
+    /// # Returns
+    /// `Some` with the result, or `None` if there is no builtin with that name.
+    pub(crate) fn call_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, RuntimeError> {
+        let result = match name {
+            // len(v): the number of elements of an array, or of characters of a string
+            "len" => match expect_args::<1>(name, args)? {
+                [Value::Array(elements)] => Value::Int(elements.len() as i32),
+                [Value::Str(s)] => Value::Int(s.chars().count() as i32),
+                [v] => return Err(RuntimeError::TypeMismatch(format!("len() requires an array or a string, got {}", v))),
+            },
+            // contains(arr, v): whether `v` is an element of `arr` (or a substring of a string)
+            "contains" => {
+                let [haystack, needle] = expect_args::<2>(name, args)?;
+                Value::Bool(find(&haystack, &needle)?.is_some())
+            }
+            // index_of(arr, v): index of the first match, or -1 if absent
+            "index_of" => {
+                let [haystack, needle] = expect_args::<2>(name, args)?;
+                Value::Int(find(&haystack, &needle)?.map_or(-1, |i| i as i32))
+            }
+            // sum(arr) / product(arr): fold an integer array (0 and 1 for an empty array)
+            "sum" | "product" => {
+                let [arr] = expect_args::<1>(name, args)?;
+                let mut elements = int_elements(name, &arr)?.into_iter();
+                let total = match name {
+                    "sum" => elements.try_fold(0, i32::checked_add),
+                    _ => elements.try_fold(1, i32::checked_mul),
+                };
+                Value::Int(total.ok_or_else(|| RuntimeError::Other(format!("Integer overflow in {}()", name)))?)
+            }
+            // min/max accept either an integer array or two integers
+            "min" | "max" => {
+                let values = match args.as_slice() {
+                    [arr @ Value::Array(_)] => int_elements(name, arr)?,
+                    [Value::Int(a), Value::Int(b)] => vec![*a, *b],
+                    _ => {
+                        let got = args.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ");
+                        return Err(RuntimeError::TypeMismatch(format!("Function '{}' expected an array or two integers, got ({})", name, got)));
+                    }
+                };
+                let result = if name == "min" { values.iter().min() } else { values.iter().max() };
+                match result {
+                    Some(n) => Value::Int(*n),
+                    None => return Err(RuntimeError::Other(format!("{}() of an empty array", name))),
+                }
+            }
+            // map(arr, f): a new array of `f` applied to each element
+            // filter(arr, pred): the elements for which `pred` returns a truthy value
+            "map" | "filter" => {
+                let (elements, func) = match expect_args::<2>(name, args)? {
+                    [Value::Array(elements), Value::Func(func)] => (elements, func),
+                    [a, b] => return Err(RuntimeError::TypeMismatch(format!("{}() requires an array and a function, got {} and {}", name, a, b))),
+                };
+                let mut results = Vec::with_capacity(elements.len());
+                for element in elements {
+                    let value = self.call_function(&func, vec![element.clone()])?;
+                    match name {
+                        "map" => results.push(value),
+                        _ if value.is_truthy() => results.push(element),
+                        _ => {}
+                    }
+                }
+                Value::Array(results)
+            }
+            // fdiv(a, b): true (non-truncating) division of two numbers, returning a float.
+            // Division by zero follows IEEE 754: `fdiv(1, 0)` is infinity and `fdiv(0, 0)` is NaN.
+            "fdiv" => {
+                let [a, b] = expect_args::<2>(name, args)?;
+                Value::Float(as_float(name, &a)? / as_float(name, &b)?)
+            }
+            // repeat(s, n): `s` repeated `n` times
+            "repeat" => {
+                let (s, n) = match expect_args::<2>(name, args)? {
+                    [Value::Str(s), Value::Int(n)] if n >= 0 => (s, n as usize),
+                    [a, b] => {
+                        return Err(RuntimeError::TypeMismatch(format!("repeat() requires a string and a non-negative count, got {} and {}", a, b)))
+                    }
+                };
+                self.check_size("string", s.len().saturating_mul(n))?; // Before allocating the result
+                Value::Str(s.repeat(n))
+            }
+            // split(s, sep): the pieces of `s` between occurrences of `sep`; an empty `sep` splits
+            // the string into its characters
+            "split" => {
+                let (s, sep) = match expect_args::<2>(name, args)? {
+                    [Value::Str(s), Value::Str(sep)] => (s, sep),
+                    [a, b] => return Err(RuntimeError::TypeMismatch(format!("split() requires two strings, got {} and {}", a, b))),
+                };
+                let pieces: Vec<Value> = if sep.is_empty() {
+                    s.chars().map(|c| Value::Str(c.to_string())).collect()
+                } else {
+                    s.split(sep.as_str()).map(|piece| Value::Str(piece.to_string())).collect()
+                };
+                Value::Array(pieces)
+            }
+            // join(arr, sep): the elements of `arr` separated by `sep`; numbers are written out
+            // as they would print, but nested arrays are an error
+            "join" => {
+                let (elements, sep) = match expect_args::<2>(name, args)? {
+                    [Value::Array(elements), Value::Str(sep)] => (elements, sep),
+                    [a, b] => return Err(RuntimeError::TypeMismatch(format!("join() requires an array and a string, got {} and {}", a, b))),
+                };
+                let parts = elements.into_iter().map(|e| match e {
+                    Value::Str(s) => Ok(s),
+                    Value::Array(_) => Err(RuntimeError::TypeMismatch(format!("join() cannot join nested array {}", e))),
+                    other => Ok(other.to_string()),
+                }).collect::<Result<Vec<String>, _>>()?;
+                Value::Str(parts.join(&sep))
+            }
+            // floor_div(a, b) / ceil_div(a, b): integer division rounding down or up, rather than
+            // toward zero like `/` (so `floor_div(-7, 2)` is -4 and `ceil_div(-7, 2)` is -3)
+            "floor_div" | "ceil_div" => {
+                let (a, b) = match expect_args::<2>(name, args)? {
+                    [Value::Int(a), Value::Int(b)] => (a, b),
+                    [a, b] => return Err(RuntimeError::TypeMismatch(format!("{}() requires integer arguments, got {} and {}", name, a, b))),
+                };
+                if b == 0 {
+                    return Err(RuntimeError::DivisionByZero);
+                }
+                let (q, r) = match (a.checked_div(b), a.checked_rem(b)) {
+                    (Some(q), Some(r)) => (q, r),
+                    _ => return Err(RuntimeError::Other(format!("Integer overflow in {}({}, {})", name, a, b))), // `i32::MIN` by -1
+                };
+                let inexact_negative = r != 0 && (r < 0) != (b < 0); // True quotient is below `q`
+                Value::Int(match (name, inexact_negative) {
+                    ("floor_div", true) => q - 1,
+                    ("ceil_div", false) if r != 0 => q + 1,
+                    _ => q,
+                })
+            }
+            // printf(fmt, ...): print a C-style formatted string, returning the number of characters printed
+            "printf" => {
+                let mut args = args.into_iter();
+                let text = match args.next() {
+                    Some(Value::Str(format)) => format_printf(&format, &args.collect::<Vec<_>>())?,
+                    Some(other) => return Err(RuntimeError::TypeMismatch(format!("printf() requires a format string, got {}", other))),
+                    None => return Err(RuntimeError::TypeMismatch("printf() requires a format string".to_string())),
+                };
+                self.write_output(&text)?;
+                Value::Int(text.chars().count() as i32)
+            }
+            // print_base(n, base): print an integer in base 2, 8, 10 or 16, with a leading `-` if negative;
+            // like `print` it leaves the line open, and `println_base` ends it
+            "print_base" | "println_base" => {
+                let mut text = match expect_args::<2>(name, args)? {
+                    [Value::Int(n), Value::Int(base)] => format_in_base(n, base)?,
+                    [a, b] => return Err(RuntimeError::TypeMismatch(format!("{}() requires two integers, got {} and {}", name, a, b))),
+                };
+                if name == "println_base" {
+                    text.push('\n');
+                }
+                self.write_output(&text)?;
+                Value::Int(0)
+            }
+            // eprint(v): print a value like `println`, but to standard error, for diagnostics
+            "eprint" => {
+                let [value] = expect_args::<1>(name, args)?;
+                let text = format!("{}\n", self.format_for_print(&value));
+                self.write_error_output(&text)?;
+                Value::Int(0)
+            }
+            // malloc(n): the address of `n` fresh memory cells, each holding 0
+            "malloc" => match expect_args::<1>(name, args)? {
+                [Value::Int(n)] if n > 0 => Value::Ptr(self.allocate(n as usize)?),
+                [v] => return Err(RuntimeError::TypeMismatch(format!("malloc() requires a positive number of cells, got {}", v))),
+            },
+            // free(p): releases cells allocated by `malloc`; freeing the null pointer does nothing
+            "free" => match expect_args::<1>(name, args)? {
+                [Value::Ptr(addr)] => {
+                    self.release(addr)?;
+                    Value::Int(0)
+                }
+                [v] => return Err(RuntimeError::TypeMismatch(format!("free() requires a pointer, got {}", v))),
+            },
+            _ => return Ok(None),
+        };
+        self.check_value_size(&result)?;
+        Ok(Some(result))
+    }
+}
+
+/// Checks that a builtin received exactly `N` arguments and returns them as an array.
+fn expect_args<const N: usize>(name: &str, args: Vec<Value>) -> Result<[Value; N], RuntimeError> {
+    let got = args.len();
+    args.try_into().map_err(|_| RuntimeError::ArgumentCount { function: name.to_string(), expected: N.to_string(), got })
+}
+
+/// Writes an integer in base 2, 8, 10 or 16 (lowercase digits), e.g. `-ff` for -255 in base 16.
+fn format_in_base(n: i32, base: i32) -> Result<String, RuntimeError> {
+    let sign = if n < 0 { "-" } else { "" };
+    let magnitude = n.unsigned_abs(); // Also correct for i32::MIN
+    let digits = match base {
+        2 => format!("{:b}", magnitude),
+        8 => format!("{:o}", magnitude),
+        10 => magnitude.to_string(),
+        16 => format!("{:x}", magnitude),
+        _ => return Err(RuntimeError::Other(format!("print_base() supports bases 2, 8, 10 and 16, got {}", base))),
+    };
+    Ok(format!("{}{}", sign, digits))
+}
+
+/// Returns whether `name` is a builtin that modifies the array variable passed as its first
+/// argument (e.g., `pop(arr)`). Such builtins are called with `call_mutating_builtin`.
+pub(crate) fn is_mutating_builtin(name: &str) -> bool {
+    matches!(name, "pop" | "push")
+}
+
+/// Extracts the elements of an integer array, for the array reductions.
+fn int_elements(name: &str, arr: &Value) -> Result<Vec<i32>, RuntimeError> {
+    match arr {
+        Value::Array(elements) => elements.iter().map(|e| match e {
+            Value::Int(i) => Ok(*i),
+            other => Err(RuntimeError::TypeMismatch(format!("{}() requires an array of integers, found {}", name, other))),
+        }).collect(),
+        other => Err(RuntimeError::TypeMismatch(format!("{}() requires an array, got {}", name, other))),
+    }
+}
+
+/// Converts a numeric argument to a float.
+fn as_float(name: &str, v: &Value) -> Result<f64, RuntimeError> {
+    match v {
+        Value::Int(i) => Ok(f64::from(*i)),
+        Value::Float(x) => Ok(*x),
+        other => Err(RuntimeError::TypeMismatch(format!("{}() requires numeric arguments, got {}", name, other))),
+    }
+}
+
+/// Searches `haystack` for `needle`.
+/// Arrays are searched element by element using `Value` equality; strings are searched
+/// for `needle` as a substring, and the index is counted in characters.
+fn find(haystack: &Value, needle: &Value) -> Result<Option<usize>, RuntimeError> {
+    match (haystack, needle) {
+        (Value::Array(elements), _) => Ok(elements.iter().position(|e| e == needle)),
+        (Value::Str(s), Value::Str(sub)) => Ok(s.find(sub.as_str()).map(|byte| s[..byte].chars().count())),
+        (Value::Str(_), v) => Err(RuntimeError::TypeMismatch(format!("Cannot search a string for non-string value {}", v))),
+        (v, _) => Err(RuntimeError::TypeMismatch(format!("Cannot search in non-array value {}", v))),
+    }
+}
+
+/// Formats the arguments of a `printf` call.
+/// Supports `%d` (an integer; booleans print as 1 or 0), `%c` (the character with the given code,
+/// so chars and ints both work), `%s` (a string) and `%%` (a literal `%`). Other characters are
+/// copied as they are; any other conversion is an error naming its position in the format string.
+pub(crate) fn format_printf(format: &str, args: &[Value]) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = format.chars().enumerate();
+    while let Some((pos, c)) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let spec = match chars.next() {
+            Some((_, '%')) => { // `%%` is a literal percent sign and takes no argument
+                out.push('%');
+                continue;
+            }
+            Some((_, spec @ ('d' | 'c' | 's'))) => spec,
+            Some((_, other)) => {
+                return Err(RuntimeError::Other(format!(
+                    "printf: unknown conversion '%{}' at position {} in format string", other, pos
+                )))
+            }
+            None => {
+                return Err(RuntimeError::Other(format!(
+                    "printf: incomplete conversion '%' at position {} in format string", pos
+                )))
+            }
+        };
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => return Err(RuntimeError::Other(format!("printf: not enough arguments for '%{}'", spec))),
+        };
+        match (spec, arg) {
+            ('d', Value::Int(i)) => out.push_str(&i.to_string()),
+            ('d', Value::Bool(b)) => out.push_str(if *b { "1" } else { "0" }),
+            ('c', Value::Int(code)) => match std::char::from_u32(*code as u32) {
+                Some(ch) if *code >= 0 => out.push(ch),
+                _ => return Err(RuntimeError::TypeMismatch(format!("printf: '%c' expects a character code, got {}", code))),
+            },
+            ('s', Value::Str(s)) => out.push_str(s),
+            ('d', other) => return Err(RuntimeError::TypeMismatch(format!("printf: '%d' expects an integer, got {}", other))),
+            ('c', other) => return Err(RuntimeError::TypeMismatch(format!("printf: '%c' expects a character code, got {}", other))),
+            (_, other) => return Err(RuntimeError::TypeMismatch(format!("printf: '%s' expects a string, got {}", other))),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that `%c` prints the character for an integer code or a char literal's value.
+    #[test]
+    fn test_printf_char_from_code() {
+        assert_eq!(format_printf("%c%c!", &[Value::Int(72), Value::Int('i' as i32)]), Ok("Hi!".to_string()));
+    }
+
+    /// Tests that `%d` prints booleans as 1 and 0, alongside `%s`.
+    #[test]
+    fn test_printf_bool_as_int() {
+        let args = [Value::Str("ok".to_string()), Value::Bool(true), Value::Bool(false)];
+        assert_eq!(format_printf("%s: %d %d", &args), Ok("ok: 1 0".to_string()));
+    }
+
+    /// Tests that `%c` rejects a string argument.
+    #[test]
+    fn test_printf_char_rejects_string() {
+        assert_eq!(
+            format_printf("%c", &[Value::Str("A".to_string())]),
+            Err(RuntimeError::TypeMismatch("printf: '%c' expects a character code, got \"A\"".to_string()))
+        );
+    }
+
+    /// Tests that a missing argument is reported.
+    #[test]
+    fn test_printf_missing_argument() {
+        let err = format_printf("%d + %d", &[Value::Int(1)]).unwrap_err();
+        assert_eq!(err.to_string(), "printf: not enough arguments for '%d'");
+    }
+
+    /// Tests that `%%` prints a percent sign without using an argument.
+    #[test]
+    fn test_printf_literal_percent() {
+        assert_eq!(format_printf("100%%", &[]), Ok("100%".to_string()));
+        assert_eq!(format_printf("%d%% of %s", &[Value::Int(50), Value::Str("x".to_string())]), Ok("50% of x".to_string()));
+    }
+
+    /// Tests that an unknown conversion is reported with its position.
+    #[test]
+    fn test_printf_unknown_conversion() {
+        let err = format_printf("ab %q", &[Value::Int(1)]).unwrap_err();
+        assert_eq!(err.to_string(), "printf: unknown conversion '%q' at position 3 in format string");
+    }
+
+    /// Tests writing integers in each supported base, including negative values.
+    #[test]
+    fn test_format_in_base() {
+        assert_eq!(format_in_base(255, 16), Ok("ff".to_string()));
+        assert_eq!(format_in_base(10, 2), Ok("1010".to_string()));
+        assert_eq!(format_in_base(8, 8), Ok("10".to_string()));
+        assert_eq!(format_in_base(-255, 16), Ok("-ff".to_string()));
+        assert_eq!(format_in_base(i32::MIN, 16), Ok("-80000000".to_string()));
+        assert_eq!(format_in_base(0, 2), Ok("0".to_string()));
+    }
+
+    /// Tests that an unsupported base is an error.
+    #[test]
+    fn test_format_in_unsupported_base() {
+        let err = format_in_base(5, 3).unwrap_err();
+        assert_eq!(err.to_string(), "print_base() supports bases 2, 8, 10 and 16, got 3");
+    }
+}
@@ -0,0 +1,144 @@
+/// Structured error reports for editor integrations.
+/// A `Diagnostic` captures where an error happened and what went wrong, and can be
+/// rendered as JSON so that editors and LSP shims can consume it directly.
+use crate::lexer::LexError;
+use crate::parser::ParseError;
+use crate::vm::RuntimeError;
+use std::fmt;
+
+/// A single error report with its source position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The line the error was reported at, if known.
+    pub line: Option<usize>,
+    /// The column the error was reported at, if known.
+    pub col: Option<usize>,
+    /// The error message, without the trailing position.
+    pub message: String,
+    /// The severity of the report (currently always "error").
+    pub severity: &'static str,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{ \"line\": {}, \"col\": {}, \"message\": \"{}\", \"severity\": \"{}\" }}",
+            json_number(self.line),
+            json_number(self.col),
+            escape_json(&self.message),
+            self.severity
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Formats the diagnostic as a human-readable message with its position, if known.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.col) {
+            (Some(line), Some(col)) => write!(f, "{} at line {}, column {}", self.message, line, col),
+            (Some(line), None) => write!(f, "{} at line {}", self.message, line),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    /// Converts a syntax error into a diagnostic at the same position.
+    fn from(err: ParseError) -> Self {
+        Diagnostic { line: Some(err.line), col: Some(err.col), message: err.message, severity: "error" }
+    }
+}
+
+impl From<LexError> for Diagnostic {
+    /// Converts a lexing error into a diagnostic at the same position.
+    fn from(err: LexError) -> Self {
+        Diagnostic { line: Some(err.line), col: Some(err.col), message: err.message, severity: "error" }
+    }
+}
+
+impl From<RuntimeError> for Diagnostic {
+    /// Converts a runtime error into a diagnostic with its full message. Runtime errors carry no
+    /// position of their own; callers fill in the line of the statement that failed.
+    fn from(err: RuntimeError) -> Self {
+        Diagnostic { line: None, col: None, message: err.to_string(), severity: "error" }
+    }
+}
+
+/// Renders a list of diagnostics as JSON: a single object for one error, an array otherwise.
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    if diagnostics.len() == 1 {
+        diagnostics[0].to_json()
+    } else {
+        let items = diagnostics.iter().map(|d| d.to_json()).collect::<Vec<_>>().join(", ");
+        format!("[{}]", items)
+    }
+}
+
+// Renders an optional position as a JSON number or `null`.
+fn json_number(n: Option<usize>) -> String {
+    n.map_or_else(|| String::from("null"), |n| n.to_string())
+}
+
+// Escapes a string for inclusion in a JSON string literal.
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    /// Tests the JSON shape of a known syntax error.
+    #[test]
+    fn test_syntax_error_json() {
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(Lexer::new("let x = 1"), &mut vm);
+        let diag = Diagnostic::from(parser.parse().unwrap_err());
+
+        assert_eq!(diag.line, Some(1));
+        assert_eq!(
+            diag.to_json(),
+            "{ \"line\": 1, \"col\": 10, \"message\": \"Expected ';' after let\", \"severity\": \"error\" }"
+        );
+    }
+
+    /// Tests that runtime errors without a position render `null` positions.
+    #[test]
+    fn test_runtime_error_json() {
+        let diag = Diagnostic::from(RuntimeError::DivisionByZero);
+        assert_eq!(
+            diag.to_json(),
+            "{ \"line\": null, \"col\": null, \"message\": \"Division by zero\", \"severity\": \"error\" }"
+        );
+    }
+
+    /// Tests that multiple diagnostics are rendered as a JSON array.
+    #[test]
+    fn test_multiple_errors_json_array() {
+        let diags = vec![
+            Diagnostic::from(ParseError::new("First", 1, 2)),
+            Diagnostic::from(ParseError::new("Second \"quoted\"", 3, 4)),
+        ];
+        assert_eq!(
+            to_json(&diags),
+            "[{ \"line\": 1, \"col\": 2, \"message\": \"First\", \"severity\": \"error\" }, \
+             { \"line\": 3, \"col\": 4, \"message\": \"Second \\\"quoted\\\"\", \"severity\": \"error\" }]"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+/// The error type returned by the `run_source` and `eval` entry points.
+/// Each phase returns its own error type (`LexError`, `ParseError`, `RuntimeError`), which
+/// `Error` keeps as it is, so callers can tell the phases and the kinds of error apart.
+use crate::diagnostic::Diagnostic;
+use crate::lexer::LexError;
+use crate::parser::ParseError;
+use crate::vm::RuntimeError;
+use std::fmt;
+
+/// An error from running a program, by the phase that reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The source could not be split into tokens (e.g. an unterminated string literal).
+    Lex(LexError),
+    /// The tokens do not form a valid program (e.g. a missing `;`).
+    Parse(ParseError),
+    /// The program failed while running (e.g. a division by zero).
+    Runtime {
+        /// What went wrong.
+        error: RuntimeError,
+        /// The line of the statement being executed, if known.
+        line: Option<usize>,
+    },
+}
+
+impl Error {
+    /// Returns the message and position of the error.
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Error::Lex(err) => Diagnostic::from(err.clone()),
+            Error::Parse(err) => Diagnostic::from(err.clone()),
+            Error::Runtime { error, line } => Diagnostic { line: *line, ..Diagnostic::from(error.clone()) },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    /// Formats the error as its message with its position, if known.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.diagnostic())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval, run_source};
+    use crate::vm::Value;
+
+    /// Tests that a lexing error is reported as `Error::Lex` with its position.
+    #[test]
+    fn test_lex_error() {
+        match run_source("let s = \"open;") {
+            Err(Error::Lex(err)) => {
+                assert_eq!(err.message, "Unterminated string literal");
+                assert_eq!(err.line, 1);
+            }
+            other => panic!("expected a lex error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// Tests that a syntax error is reported as `Error::Parse` with its position.
+    #[test]
+    fn test_parse_error() {
+        match eval("let x = 1;\nlet y = 2") {
+            Err(Error::Parse(err)) => {
+                assert_eq!(err.message, "Expected ';' after let");
+                assert_eq!((err.line, err.col), (2, 10)); // Where the missing `;` should be
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    /// Tests that a runtime error is reported as `Error::Runtime` at the failing statement's line.
+    #[test]
+    fn test_runtime_error() {
+        let err = eval("let x = 1;\nint f(n) {\n  return n / 0;\n}\nreturn f(x);").unwrap_err();
+        assert_eq!(err, Error::Runtime { error: RuntimeError::DivisionByZero, line: Some(3) });
+        let expected = Diagnostic { line: Some(3), col: None, message: "Division by zero".to_string(), severity: "error" };
+        assert_eq!(err.diagnostic(), expected);
+        assert_eq!(err.to_string(), "Division by zero at line 3");
+    }
+
+    /// Tests that a runtime message quoting a string that looks like a position is kept whole,
+    /// at the line of the failing statement rather than the position in the string.
+    #[test]
+    fn test_runtime_error_quoting_position() {
+        let err = eval("let s = \"x at line 99, column 7\";\nreturn -s;").unwrap_err();
+        let diag = err.diagnostic();
+        assert_eq!(diag.message, "Cannot negate non-numeric value \"x at line 99, column 7\"");
+        assert_eq!((diag.line, diag.col), (Some(2), None));
+        assert_eq!(err.to_string(), "Cannot negate non-numeric value \"x at line 99, column 7\" at line 2");
+    }
+
+    /// Tests that callers can tell runtime errors apart by their kind.
+    #[test]
+    fn test_runtime_error_kind() {
+        match eval("let a = [1, 2];
+return a[5];") {
+            Err(Error::Runtime { error: RuntimeError::IndexOutOfBounds { len, idx }, line }) => {
+                assert_eq!((len, idx, line), (2, 5, Some(2)));
+            }
+            other => panic!("expected an out-of-bounds error, got {:?}", other),
+        }
+        assert!(matches!(eval("return missing;"), Err(Error::Runtime { error: RuntimeError::UndefinedVariable(_), .. })));
+    }
+
+    /// Tests that a program without errors gives its result, if it has one.
+    #[test]
+    fn test_eval_ok() {
+        assert_eq!(eval("return 6 * 7;"), Ok(Some(Value::Int(42))));
+        assert_eq!(eval("let x = 1;"), Ok(None));
+        assert_eq!(eval(""), Ok(None));
+    }
+}
@@ -0,0 +1,370 @@
+/// Constant folding for the abstract syntax tree.
+/// This pass runs after parsing and replaces expressions whose value is known at
+/// compile time (such as `sizeof(int)` or `2 * 3`) with number literals, so the
+/// VM doesn't have to evaluate them on every execution.
+///
+/// Named constants such as enum members fold too (`A * 2` becomes `10` when `A = 5`),
+/// unless the program also uses the name as a variable, since variables shadow constants.
+use crate::ast::{BinOp, Expr, Stmt, UnOp};
+use std::collections::{HashMap, HashSet};
+
+/// Folds constant expressions in every statement of a program.
+///
+/// # Parameters
+/// - `stmts`: The program.
+/// - `constants`: The named constants known at compile time (e.g., `Vm::constants` after parsing).
+pub fn fold_program(stmts: Vec<Stmt>, constants: &HashMap<String, i32>) -> Vec<Stmt> {
+    let mut variables = HashSet::new();
+    for stmt in &stmts {
+        collect_variables(stmt, &mut variables);
+    }
+    let constants = constants.iter()
+        .filter(|(name, _)| !variables.contains(*name))
+        .map(|(name, value)| (name.clone(), *value))
+        .collect();
+    fold_stmts(stmts, &constants)
+}
+
+// Adds the name of every variable a statement may declare or assign, including parameters
+// and assignments inside expressions, to `names`.
+fn collect_variables(stmt: &Stmt, names: &mut HashSet<String>) {
+    match stmt {
+        Stmt::Return(expr) | Stmt::Print { value: expr, .. } | Stmt::ExprStmt(expr) => collect_assigned(expr, names),
+        Stmt::Block(stmts) | Stmt::LetGroup(stmts) => stmts.iter().for_each(|s| collect_variables(s, names)),
+        Stmt::Let { name, value, .. } | Stmt::Assign { name, value } | Stmt::Const { name, value } => {
+            names.insert(name.clone());
+            collect_assigned(value, names);
+        }
+        Stmt::LetArray { names: bound, rest, value } => {
+            names.extend(bound.iter().chain(rest).cloned());
+            collect_assigned(value, names);
+        }
+        Stmt::If { condition, then_branch, else_branch } => {
+            collect_assigned(condition, names);
+            collect_variables(then_branch, names);
+            if let Some(else_branch) = else_branch {
+                collect_variables(else_branch, names);
+            }
+        }
+        Stmt::Try { body, name, handler } => {
+            names.insert(name.clone());
+            collect_variables(body, names);
+            collect_variables(handler, names);
+        }
+        Stmt::While { condition, body } | Stmt::DoWhile { body, condition } => {
+            collect_assigned(condition, names);
+            collect_variables(body, names);
+        }
+        Stmt::For { init, condition, update, body } => {
+            if let Some(init) = init {
+                collect_variables(init, names);
+            }
+            condition.iter().chain(update).for_each(|e| collect_assigned(e, names));
+            collect_variables(body, names);
+        }
+        Stmt::Switch { scrutinee, cases, default } => {
+            collect_assigned(scrutinee, names);
+            for (value, body) in cases {
+                collect_assigned(value, names);
+                body.iter().for_each(|s| collect_variables(s, names));
+            }
+            default.iter().flatten().for_each(|s| collect_variables(s, names));
+        }
+        Stmt::Located { stmt, .. } => collect_variables(stmt, names),
+        Stmt::Function { params, body, .. } => {
+            for param in params {
+                names.insert(param.name.clone());
+                if let Some(default) = &param.default {
+                    collect_assigned(default, names);
+                }
+            }
+            names.insert("args".to_string()); // Bound in variadic functions
+            collect_variables(body, names);
+        }
+    }
+}
+
+// Adds the names of variables assigned by `name = ...` inside an expression to `names`.
+fn collect_assigned(expr: &Expr, names: &mut HashSet<String>) {
+    match expr {
+        Expr::BinaryOp { .. } => {
+            // Walk a chain like `a + b - c` down its left operands, so long chains don't recurse per operator
+            let mut current = expr;
+            while let Expr::BinaryOp { op, left, right } = current {
+                if let (BinOp::Assign, Expr::Variable(name)) = (op, &**left) {
+                    names.insert(name.clone());
+                }
+                collect_assigned(right, names);
+                current = left;
+            }
+            collect_assigned(current, names);
+        }
+        Expr::ArrayLiteral(exprs) | Expr::FunctionCall { args: exprs, .. } => {
+            exprs.iter().for_each(|e| collect_assigned(e, names));
+        }
+        Expr::Call { callee, args } => {
+            collect_assigned(callee, names);
+            args.iter().for_each(|e| collect_assigned(e, names));
+        }
+        Expr::ArrayIndex(a, b) => {
+            collect_assigned(a, names);
+            collect_assigned(b, names);
+        }
+        Expr::StructLiteral { fields, .. } => fields.iter().for_each(|(_, e)| collect_assigned(e, names)),
+        Expr::Slice { target, start, end } => {
+            collect_assigned(target, names);
+            collect_assigned(start, names);
+            collect_assigned(end, names);
+        }
+        Expr::Ternary { condition, then_branch, else_branch } => {
+            collect_assigned(condition, names);
+            collect_assigned(then_branch, names);
+            collect_assigned(else_branch, names);
+        }
+        Expr::Member(e, _) | Expr::PreInc(e) | Expr::PreDec(e) | Expr::PostInc(e) | Expr::PostDec(e)
+            | Expr::UnaryOp { expr: e, .. } | Expr::SizeOfValue(e) | Expr::Cast(_, e)
+            | Expr::AddressOf(e) | Expr::Deref(e) => collect_assigned(e, names),
+        Expr::Block { stmts, value } => {
+            stmts.iter().for_each(|s| collect_variables(s, names));
+            collect_assigned(value, names);
+        }
+        Expr::Number(_) | Expr::Float(_) | Expr::Variable(_) | Expr::Local { .. } | Expr::Boolean(_) | Expr::Char(_)
+            | Expr::StringLiteral(_) | Expr::EnumValue(..) | Expr::SizeOf(_) => {}
+    }
+}
+
+// Folds every statement of a block.
+fn fold_stmts(stmts: Vec<Stmt>, constants: &HashMap<String, i32>) -> Vec<Stmt> {
+    stmts.into_iter().map(|stmt| fold_stmt(stmt, constants)).collect()
+}
+
+// Folds constant expressions inside a statement.
+fn fold_stmt(stmt: Stmt, constants: &HashMap<String, i32>) -> Stmt {
+    match stmt {
+        Stmt::Return(expr) => Stmt::Return(fold_expr(expr, constants)),
+        Stmt::Print { value, newline } => Stmt::Print { value: fold_expr(value, constants), newline },
+        Stmt::ExprStmt(expr) => Stmt::ExprStmt(fold_expr(expr, constants)),
+        Stmt::Block(stmts) => Stmt::Block(fold_stmts(stmts, constants)),
+        Stmt::LetGroup(stmts) => Stmt::LetGroup(fold_stmts(stmts, constants)),
+        Stmt::Let { name, value, var_type } => Stmt::Let { name, value: fold_expr(value, constants), var_type },
+        Stmt::Const { name, value } => Stmt::Const { name, value: fold_expr(value, constants) },
+        Stmt::LetArray { names, rest, value } => Stmt::LetArray { names, rest, value: fold_expr(value, constants) },
+        Stmt::Assign { name, value } => Stmt::Assign { name, value: fold_expr(value, constants) },
+        Stmt::If { condition, then_branch, else_branch } => Stmt::If {
+            condition: fold_expr(condition, constants),
+            then_branch: Box::new(fold_stmt(*then_branch, constants)),
+            else_branch: else_branch.map(|s| Box::new(fold_stmt(*s, constants))),
+        },
+        Stmt::While { condition, body } => Stmt::While {
+            condition: fold_expr(condition, constants),
+            body: Box::new(fold_stmt(*body, constants)),
+        },
+        Stmt::Try { body, name, handler } => Stmt::Try {
+            body: Box::new(fold_stmt(*body, constants)),
+            name,
+            handler: Box::new(fold_stmt(*handler, constants)),
+        },
+        Stmt::DoWhile { body, condition } => Stmt::DoWhile {
+            body: Box::new(fold_stmt(*body, constants)),
+            condition: fold_expr(condition, constants),
+        },
+        Stmt::For { init, condition, update, body } => Stmt::For {
+            init: init.map(|s| Box::new(fold_stmt(*s, constants))),
+            condition: condition.map(|e| fold_expr(e, constants)),
+            update: update.map(|e| fold_expr(e, constants)),
+            body: Box::new(fold_stmt(*body, constants)),
+        },
+        Stmt::Switch { scrutinee, cases, default } => Stmt::Switch {
+            scrutinee: fold_expr(scrutinee, constants),
+            cases: cases.into_iter().map(|(value, body)| (fold_expr(value, constants), fold_stmts(body, constants))).collect(),
+            default: default.map(|body| fold_stmts(body, constants)),
+        },
+        Stmt::Located { line, stmt } => Stmt::Located { line, stmt: Box::new(fold_stmt(*stmt, constants)) },
+        Stmt::Function { name, params, variadic, body, return_type } => Stmt::Function {
+            name,
+            params,
+            variadic,
+            body: Box::new(fold_stmt(*body, constants)),
+            return_type,
+        },
+    }
+}
+
+/// Folds an expression, returning a `Number` literal when its value is a compile-time constant.
+/// Variables named in `constants` are replaced by their values.
+pub fn fold_expr(expr: Expr, constants: &HashMap<String, i32>) -> Expr {
+    match expr {
+        Expr::SizeOf(t) => match t.size() {
+            Some(size) => Expr::Number(size), // Sizes are always known at compile time
+            None => Expr::SizeOf(t), // Too large for an `int`; the parser reports these
+        },
+        Expr::Variable(name) => match constants.get(&name) {
+            Some(value) => Expr::Number(*value), // A named constant (e.g., an enum member)
+            None => Expr::Variable(name),
+        },
+        Expr::BinaryOp { op, left, right } => {
+            // Fold a chain like `a + b - c` from its innermost left operand outwards,
+            // so long chains don't recurse once per operator
+            let mut rights = vec![(op, *right)];
+            let mut left = *left;
+            while let Expr::BinaryOp { op, left: inner, right } = left {
+                rights.push((op, *right));
+                left = *inner;
+            }
+            let mut folded = fold_expr(left, constants);
+            for (op, right) in rights.into_iter().rev() {
+                folded = fold_binary_node(op, folded, fold_expr(right, constants));
+            }
+            folded
+        }
+        Expr::UnaryOp { op, expr } => match (op, fold_expr(*expr, constants)) {
+            (UnOp::Not, Expr::Number(n)) => Expr::Boolean(n == 0),
+            (UnOp::Neg, Expr::Number(n)) if n != i32::MIN => Expr::Number(-n), // Overflow is left for the VM
+            (UnOp::BitNot, Expr::Number(n)) => Expr::Number(!n),
+            (op, expr) => Expr::UnaryOp { op, expr: Box::new(expr) },
+        },
+        Expr::Ternary { condition, then_branch, else_branch } => Expr::Ternary {
+            condition: Box::new(fold_expr(*condition, constants)),
+            then_branch: Box::new(fold_expr(*then_branch, constants)),
+            else_branch: Box::new(fold_expr(*else_branch, constants)),
+        },
+        Expr::ArrayLiteral(elements) => Expr::ArrayLiteral(elements.into_iter().map(|e| fold_expr(e, constants)).collect()),
+        Expr::ArrayIndex(array, index) => Expr::ArrayIndex(array, Box::new(fold_expr(*index, constants))),
+        Expr::StructLiteral { name, fields } => Expr::StructLiteral {
+            name,
+            fields: fields.into_iter().map(|(f, e)| (f, fold_expr(e, constants))).collect(),
+        },
+        Expr::Slice { target, start, end } => Expr::Slice {
+            target: Box::new(fold_expr(*target, constants)),
+            start: Box::new(fold_expr(*start, constants)),
+            end: Box::new(fold_expr(*end, constants)),
+        },
+        Expr::FunctionCall { name, args } => Expr::FunctionCall {
+            name,
+            args: args.into_iter().map(|e| fold_expr(e, constants)).collect(),
+        },
+        Expr::Call { callee, args } => Expr::Call {
+            callee,
+            args: args.into_iter().map(|e| fold_expr(e, constants)).collect(),
+        },
+        Expr::Cast(t, expr) => Expr::Cast(t, Box::new(fold_expr(*expr, constants))),
+        Expr::Deref(expr) => Expr::Deref(Box::new(fold_expr(*expr, constants))),
+        Expr::Block { stmts, value } => Expr::Block {
+            stmts: fold_stmts(stmts, constants),
+            value: Box::new(fold_expr(*value, constants)),
+        },
+        other => other, // Literals, variables, and lvalue-based operators are left as they are
+    }
+}
+
+// Rebuilds a binary operation from folded operands, folding it too when both are constants.
+fn fold_binary_node(op: BinOp, left: Expr, right: Expr) -> Expr {
+    if let (Expr::Number(l), Expr::Number(r)) = (&left, &right) {
+        if let Some(folded) = fold_binary(&op, *l, *r) {
+            return folded;
+        }
+    }
+    Expr::BinaryOp { op, left: Box::new(left), right: Box::new(right) }
+}
+
+// Evaluates a binary operator on two constants.
+// Comparisons and logical operators give booleans, like in the VM.
+// Returns `None` when the result must be left to the VM (overflow, division by zero, assignment).
+fn fold_binary(op: &BinOp, l: i32, r: i32) -> Option<Expr> {
+    let result = match op {
+        BinOp::Add => l.checked_add(r),
+        BinOp::Sub => l.checked_sub(r),
+        BinOp::Mul => l.checked_mul(r),
+        BinOp::Div => l.checked_div(r),
+        BinOp::Mod => l.checked_rem(r),
+        BinOp::Pow if r >= 0 => l.checked_pow(r as u32),
+        BinOp::Pow => None, // A negative exponent is an error
+        BinOp::Equal => return Some(Expr::Boolean(l == r)),
+        BinOp::NotEqual => return Some(Expr::Boolean(l != r)),
+        BinOp::LessThan => return Some(Expr::Boolean(l < r)),
+        BinOp::GreaterThan => return Some(Expr::Boolean(l > r)),
+        BinOp::LessEqual => return Some(Expr::Boolean(l <= r)),
+        BinOp::GreaterEqual => return Some(Expr::Boolean(l >= r)),
+        BinOp::And => return Some(Expr::Boolean(l != 0 && r != 0)),
+        BinOp::Or => return Some(Expr::Boolean(l != 0 || r != 0)),
+        BinOp::BitAnd => Some(l & r),
+        BinOp::BitOr => Some(l | r),
+        BinOp::BitXor => Some(l ^ r),
+        BinOp::Shl => l.checked_shl(r as u32),
+        BinOp::Shr => l.checked_shr(r as u32),
+        BinOp::Assign => None,
+    };
+    result.map(Expr::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    /// Parses and folds a program.
+    fn fold(code: &str) -> Vec<Stmt> {
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(Lexer::new(code), &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        fold_program(stmts, &vm.constants)
+    }
+
+    /// Tests that `sizeof` expressions fold into a single literal.
+    #[test]
+    fn test_fold_sizeof_sum() {
+        let stmts = fold("return sizeof(char) + sizeof(int);");
+        assert!(matches!(stmts[0].unlocated(), Stmt::Return(Expr::Number(5))));
+    }
+
+    /// Tests that folded sizes take part in further folding.
+    #[test]
+    fn test_fold_sizeof_product() {
+        let stmts = fold("let n = sizeof(int) * 10;");
+        assert!(matches!(stmts[0].unlocated(), Stmt::Let { value: Expr::Number(40), .. }));
+    }
+
+    /// Tests that division by zero is left for the VM to report.
+    #[test]
+    fn test_fold_keeps_division_by_zero() {
+        let stmts = fold("return 1 / 0;");
+        assert!(matches!(stmts[0].unlocated(), Stmt::Return(Expr::BinaryOp { op: BinOp::Div, .. })));
+    }
+
+    /// Tests that a long chain of operators folds without recursing per operator.
+    #[test]
+    fn test_fold_long_chain() {
+        let stmts = fold(&format!("return 0{};", " + 1".repeat(100_000)));
+        assert!(matches!(stmts[0].unlocated(), Stmt::Return(Expr::Number(100_000))));
+    }
+
+    /// Tests that negated constants fold into negative literals.
+    #[test]
+    fn test_fold_negation() {
+        let stmts = fold("return 2 * -3;");
+        assert!(matches!(stmts[0].unlocated(), Stmt::Return(Expr::Number(-6))));
+    }
+
+    /// Tests that constant comparisons fold into booleans.
+    #[test]
+    fn test_fold_comparison() {
+        let stmts = fold("return 2 * 3 > 5;");
+        assert!(matches!(stmts[0].unlocated(), Stmt::Return(Expr::Boolean(true))));
+    }
+
+    /// Tests that expressions using enum constants fold.
+    #[test]
+    fn test_fold_enum_constant() {
+        let stmts = fold("enum { A = 5, B }; return A * 2 + B;");
+        assert!(matches!(stmts[1].unlocated(), Stmt::Return(Expr::Number(16))));
+    }
+
+    /// Tests that a constant isn't folded where a variable of the same name may shadow it.
+    #[test]
+    fn test_fold_keeps_shadowed_constant() {
+        let stmts = fold("enum { A = 5 }; int f(A) { return A * 2; } return A;");
+        assert!(matches!(stmts[2].unlocated(), Stmt::Return(Expr::Variable(_))));
+    }
+}
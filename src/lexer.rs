@@ -1,12 +1,14 @@
 /// Represents the types of tokens the lexer can generate.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Num(i32), // Integer number
+    Float(f64), // Floating-point number (e.g., 2.5 or 1.0e3)
     Identifier(String), // Variable or function name
     Return, // 'return' keyword
     If, // 'if' keyword
     Else, // 'else' keyword
     While, // 'while' keyword
+    For, // 'for' keyword
     Let, // 'let' keyword
     OpenParen, // '(' character
     CloseParen, // ')' character
@@ -19,6 +21,7 @@ pub enum Token {
     Add, // '+' operator
     Sub, // '-' operator
     Mul, // '*' operator
+    Pow, // '**' exponentiation operator
     Div, // '/' operator
     Equal, // '==' operator
     NotEqual, // '!=' operator
@@ -38,10 +41,16 @@ pub enum Token {
     Not, // '!' logical NOT
     Print, // 'print' keyword
     Enum, // 'enum' keyword
+    Struct, // 'struct' keyword
     StringLiteral(String), // String literal
     Sizeof, // 'sizeof' keyword
     Colon, // ':' character
+    Ellipsis, // '...' marking a variadic parameter list
+    DotDot, // '..' in a slice (e.g., arr[1..3])
+    Dot, // '.' for struct field access
+    #[allow(dead_code)]
     AddressOf, // '&' address-of operator
+    #[allow(dead_code)]
     Deref, // '*' dereference operator
     PlusPlus, // '++' increment operator
     MinusMinus, // '--' decrement operator
@@ -53,14 +62,108 @@ pub enum Token {
     BitNot, // '~' bitwise NOT
     Shl, // '<<' bitwise shift left
     Shr, // '>>' bitwise shift right
+    Ref, // 'ref' parameter marker
+    Switch, // 'switch' keyword
+    Case, // 'case' keyword
+    Default, // 'default' keyword
+    Do, // 'do' keyword
+    Const, // 'const' keyword
+    Try, // 'try' keyword
+    Catch, // 'catch' keyword
+    Println, // 'println' keyword
+}
+
+impl Token {
+    /// Returns the source spelling of a keyword token, or `None` for any other token.
+    pub fn keyword(&self) -> Option<&'static str> {
+        match self {
+            Token::Return => Some("return"),
+            Token::If => Some("if"),
+            Token::Else => Some("else"),
+            Token::While => Some("while"),
+            Token::For => Some("for"),
+            Token::Let => Some("let"),
+            Token::True => Some("true"),
+            Token::False => Some("false"),
+            Token::Fn => Some("fn"),
+            Token::Print => Some("print"),
+            Token::Enum => Some("enum"),
+            Token::Struct => Some("struct"),
+            Token::Sizeof => Some("sizeof"),
+            Token::Ref => Some("ref"),
+            Token::Switch => Some("switch"),
+            Token::Case => Some("case"),
+            Token::Default => Some("default"),
+            Token::Do => Some("do"),
+            Token::Const => Some("const"),
+            Token::Try => Some("try"),
+            Token::Catch => Some("catch"),
+            Token::Println => Some("println"),
+            _ => None,
+        }
+    }
+}
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// An error in the source text itself, such as an unterminated string literal,
+/// with the position it was found at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    /// What went wrong (e.g. "Unterminated string literal").
+    pub message: String,
+    /// The line the error was found at.
+    pub line: usize,
+    /// The column the error was found at.
+    pub col: usize,
+}
+
+impl LexError {
+    /// Creates a lexing error with the given message and position.
+    pub fn new(message: impl Into<String>, line: usize, col: usize) -> Self {
+        LexError { message: message.into(), line, col }
+    }
+}
+
+impl fmt::Display for LexError {
+    /// Formats the error as its message followed by its position.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}, column {}", self.message, self.line, self.col)
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// A token together with the position of its first character.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    /// The token.
+    pub token: Token,
+    /// The line the token starts on.
+    pub line: usize,
+    /// The column the token starts at.
+    pub col: usize,
+    /// The byte offset of the token's first character in the input.
+    pub offset: usize,
 }
 
 /// Lexer that tokenizes the input code.
+///
+/// The lexer also acts as the preprocessor: a `#define NAME body` line defines an
+/// object-like macro, and each later use of `NAME` is replaced by the tokens of `body`.
 pub struct Lexer {
     input: Vec<char>, // The input source code as a list of characters
     pos: usize, // Current position in the input
+    offset: usize, // Current byte offset in the input (differs from `pos` after non-ASCII characters)
     line: usize, // Current line number
     col: usize, // Current column number
+    nested_comments: bool, // Whether block comments nest (off by default, as in C)
+    macros: HashMap<String, Vec<Token>>, // Macros defined with `#define`, by name
+    expansion: VecDeque<Token>, // Tokens of a macro expansion still to be returned
+    finished: bool, // Whether iteration has yielded `Eof` and stopped
+    token_start: (usize, usize), // Line and column where the last token returned started
+    token_start_offset: usize, // Byte offset where the last token returned started
 }
 
 impl Lexer {
@@ -69,16 +172,51 @@ impl Lexer {
         Self {
             input: input.chars().collect(), // Convert input string into a character vector
             pos: 0, // Start at the first character
+            offset: 0, // Start at the first byte
             line: 1, // Start at line 1
             col: 1, // Start at column 1
+            nested_comments: false, // C block comments don't nest
+            macros: HashMap::new(),
+            expansion: VecDeque::new(),
+            finished: false,
+            token_start: (1, 1),
+            token_start_offset: 0,
         }
     }
 
-    /// Returns the next token in the input.
-    pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace_and_comments(); // Skip any whitespace or comments
+    /// Enables or disables nested block comments.
+    /// When enabled, `/* /* */ */` is a single comment: each `/*` must be closed by its own `*/`.
+    /// This is off by default for compatibility with C, where the first `*/` ends the comment.
+    #[allow(dead_code)]
+    pub fn set_nested_comments(&mut self, enabled: bool) {
+        self.nested_comments = enabled;
+    }
+
+    /// Returns the next token in the input, with the position it starts at.
+    /// Tokens from a macro expansion are placed at the macro's use.
+    pub fn next_spanned(&mut self) -> Result<Spanned, LexError> {
+        let token = self.next_token()?;
+        let (line, col) = self.token_start();
+        Ok(Spanned { token, line, col, offset: self.token_start_offset })
+    }
+
+    /// Returns the line and column where the token last returned by `next_token` starts.
+    /// Unlike `get_position`, which is past the end of that token, this points at its first character.
+    pub fn token_start(&self) -> (usize, usize) {
+        self.token_start
+    }
+
+    /// Returns the next token in the input, or the error that stops it from being read
+    /// (e.g. an unterminated string literal).
+    pub fn next_token(&mut self) -> Result<Token, LexError> {
+        if let Some(token) = self.expansion.pop_front() {
+            return Ok(token); // Finish the macro expansion in progress
+        }
+        self.skip_whitespace_and_comments()?; // Skip any whitespace or comments
+        self.token_start = (self.line, self.col);
+        self.token_start_offset = self.offset;
 
-        if let Some(ch) = self.current_char() {
+        let token = if let Some(ch) = self.current_char() {
             match ch {
                 '"' => { // Handle string literals
                     self.advance(); // Move past the opening quote
@@ -92,9 +230,12 @@ impl Lexer {
                                 match escaped {
                                     'n' => string.push('\n'),
                                     't' => string.push('\t'),
+                                    'r' => string.push('\r'),
+                                    '0' => string.push('\0'),
+                                    'x' => string.push(self.hex_escape()?),
                                     '"' => string.push('"'),
                                     '\\' => string.push('\\'),
-                                    _ => panic!("Unknown escape sequence \\{} at line {}, col {}", escaped, self.line, self.col),
+                                    _ => return Err(LexError::new(format!("Unknown escape sequence \\{}", escaped), self.line, self.col)),
                                 }
                             }
                         } else {
@@ -103,7 +244,7 @@ impl Lexer {
                         self.advance();
                     }
                     if self.current_char() != Some('"') {
-                        panic!("Unterminated string literal at line {}, col {}", self.line, self.col);
+                        return Err(LexError::new("Unterminated string literal", self.line, self.col));
                     }
                     self.advance(); // Move past the closing quote
                     Token::StringLiteral(string) // Return a string literal token
@@ -111,20 +252,30 @@ impl Lexer {
 
                 '\'' => { // Handle character literals
                     self.advance(); // Move past the opening quote
-                    let ch = self.current_char().unwrap_or_else(|| {
-                        panic!("Unterminated character literal at line {}, col {}", self.line, self.col)
-                    });
+                    let ch = self.current_char()
+                        .ok_or_else(|| LexError::new("Unterminated character literal", self.line, self.col))?;
                     self.advance();
                     if self.current_char() != Some('\'') {
-                        panic!("Expected closing quote at line {}, col {}", self.line, self.col);
+                        return Err(LexError::new("Expected closing quote", self.line, self.col));
                     }
                     self.advance(); // Move past the closing quote
                     Token::Char(ch) // Return a character token
                 }
 
-                '0'..='9' => self.number(), // Number literals
+                '0'..='9' => self.number()?, // Number literals
 
-                'a'..='z' | 'A'..='Z' | '_' => self.identifier_or_keyword(), // Identifiers or keywords
+                c if c.is_alphabetic() || c == '_' => match self.identifier_or_keyword() { // Identifiers or keywords
+                    Token::Identifier(name) if self.macros.contains_key(&name) => {
+                        self.expansion = self.expand_macro(&name, &mut Vec::new()).into();
+                        return self.next_token(); // An empty macro expands to nothing
+                    }
+                    token => token,
+                },
+
+                '#' => { // Preprocessor directive
+                    self.directive()?;
+                    return self.next_token();
+                }
 
                 '+' => { // Handle addition or increment
                     self.advance();
@@ -146,6 +297,11 @@ impl Lexer {
                     }
                 }
 
+                '*' if self.match_char('*') => {
+                    self.advance();
+                    self.advance(); // Move past '**'
+                    Token::Pow // Exponentiation, or two dereferences or pointer levels where an operand or type is expected
+                }
                 '*' => {
                     self.advance();
                     Token::Mul // Return multiplication operator
@@ -161,7 +317,7 @@ impl Lexer {
                             }
                             self.advance();
                         }
-                        self.next_token() // Continue processing after the comment
+                        return self.next_token(); // Continue processing after the comment
                     } else {
                         Token::Div // Return division operator
                     }
@@ -259,6 +415,18 @@ impl Lexer {
                 ',' => { self.advance(); Token::Comma } // Comma
                 ':' => { self.advance(); Token::Colon } // Colon
                 '?' => { self.advance(); Token::QuestionMark } // Question mark
+                '.' if self.match_char('.') && self.input.get(self.pos + 2) == Some(&'.') => {
+                    self.advance();
+                    self.advance();
+                    self.advance(); // Move past '...'
+                    Token::Ellipsis
+                }
+                '.' if self.match_char('.') => {
+                    self.advance();
+                    self.advance(); // Move past '..'
+                    Token::DotDot
+                }
+                '.' => { self.advance(); Token::Dot } // Struct field access
 
                 _ => { // Unknown character
                     self.advance();
@@ -267,54 +435,212 @@ impl Lexer {
             }
         } else {
             Token::Eof // End of file token
-        }
+        };
+        Ok(token)
     }
 
     // Parses a number from the current input.
-    fn number(&mut self) -> Token {
-        let mut value = 0;
+    // A `0x`/`0X` prefix makes it hexadecimal and `0b`/`0B` binary; otherwise it is decimal.
+    // A decimal number with a fractional part (e.g., `2.5` or `1.0e-3`) is a float.
+    fn number(&mut self) -> Result<Token, LexError> {
+        let radix = match (self.current_char(), self.input.get(self.pos + 1)) {
+            (Some('0'), Some('x')) | (Some('0'), Some('X')) => 16,
+            (Some('0'), Some('b')) | (Some('0'), Some('B')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            return self.prefixed_number(radix);
+        }
+        if self.is_float_literal() {
+            return self.float();
+        }
+        let (line, col) = (self.line, self.col);
+        let mut digits = String::new();
         while let Some(ch) = self.current_char() {
             if ch.is_ascii_digit() {
-                value = value * 10 + (ch as i32 - '0' as i32); // Construct the number
+                digits.push(ch);
                 self.advance();
             } else {
                 break; // End of number
             }
         }
-        Token::Num(value) // Return the number token
+        let value = digits.chars().try_fold(0i32, |value, ch| {
+            value.checked_mul(10)?.checked_add(ch as i32 - '0' as i32) // Construct the number
+        });
+        match value {
+            Some(value) => Ok(Token::Num(value)), // Return the number token
+            None => Err(LexError::new(format!("Integer literal '{}' is too large", digits), line, col)),
+        }
     }
 
-    // Parses an identifier or keyword from the current input.
-    fn identifier_or_keyword(&mut self) -> Token {
+    // Reads a run of letters, digits and underscores, which may be empty.
+    // Letters and digits from any script count (e.g., `größe`), not only ASCII ones.
+    fn word(&mut self) -> String {
         let start = self.pos;
         while let Some(ch) = self.current_char() {
-            if ch.is_ascii_alphanumeric() || ch == '_' {
+            if ch.is_alphanumeric() || ch == '_' {
                 self.advance();
             } else {
                 break;
             }
         }
-        let word: String = self.input[start..self.pos].iter().collect(); // Extract the word
+        self.input[start..self.pos].iter().collect() // Extract the word
+    }
 
+    // Handles a preprocessor directive starting at the current `#`, up to the end of its line.
+    // Only `#define NAME body` is supported; redefining a macro with a different body is an error.
+    fn directive(&mut self) -> Result<(), LexError> {
+        let (line, col) = (self.line, self.col);
+        self.advance(); // Move past '#'
+        while matches!(self.current_char(), Some(' ') | Some('\t')) {
+            self.advance();
+        }
+        let directive = self.word();
+        if directive != "define" {
+            return Err(LexError::new(format!("Unknown preprocessor directive '#{}'", directive), line, col));
+        }
+        while matches!(self.current_char(), Some(' ') | Some('\t')) {
+            self.advance();
+        }
+        let name = self.word();
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(LexError::new("Expected macro name after '#define'", line, col));
+        }
+        if let Some(keyword) = Lexer::new(&name).identifier_or_keyword().keyword() {
+            return Err(LexError::new(format!("Cannot #define keyword '{}'", keyword), line, col));
+        }
+
+        // The body is the rest of the line, lexed on its own
+        let start = self.pos;
+        while !matches!(self.current_char(), Some('\n') | None) {
+            self.advance();
+        }
+        let text: String = self.input[start..self.pos].iter().collect();
+        let mut body_lexer = Lexer::new(&text);
+        let mut body = Vec::new();
+        loop {
+            match body_lexer.next_token()? {
+                Token::Eof => break,
+                token => body.push(token),
+            }
+        }
+
+        if let Some(existing) = self.macros.get(&name) {
+            if *existing != body {
+                return Err(LexError::new(format!("Macro '{}' redefined with a different value", name), line, col));
+            }
+        }
+        self.macros.insert(name, body);
+        Ok(())
+    }
+
+    // Returns the tokens a macro expands to, expanding the macros used in its body as well.
+    // `active` holds the macros being expanded, so a macro that refers to itself stops there.
+    fn expand_macro(&self, name: &str, active: &mut Vec<String>) -> Vec<Token> {
+        active.push(name.to_string());
+        let mut tokens = Vec::new();
+        for token in &self.macros[name] {
+            match token {
+                Token::Identifier(inner) if self.macros.contains_key(inner) && !active.contains(inner) => {
+                    tokens.extend(self.expand_macro(inner, active));
+                }
+                token => tokens.push(token.clone()),
+            }
+        }
+        active.pop();
+        tokens
+    }
+
+    // Checks whether the decimal number at the current position has a fractional part:
+    // its digits are followed by a `.` and another digit.
+    fn is_float_literal(&self) -> bool {
+        let mut i = self.pos;
+        while self.input.get(i).is_some_and(|c| c.is_ascii_digit()) {
+            i += 1;
+        }
+        self.input.get(i) == Some(&'.') && self.input.get(i + 1).is_some_and(|c| c.is_ascii_digit())
+    }
+
+    // Parses a float literal: digits, a `.`, more digits and an optional exponent (e.g., `e-3`).
+    fn float(&mut self) -> Result<Token, LexError> {
+        let start = self.pos;
+        while self.current_char().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.advance();
+        }
+        if matches!(self.current_char(), Some('e') | Some('E')) {
+            let (line, col) = (self.line, self.col);
+            self.advance();
+            if matches!(self.current_char(), Some('+') | Some('-')) {
+                self.advance();
+            }
+            if !self.current_char().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(LexError::new("Expected digits in float exponent", line, col));
+            }
+            while self.current_char().is_some_and(|c| c.is_ascii_digit()) {
+                self.advance();
+            }
+        }
+        let text: String = self.input[start..self.pos].iter().collect();
+        text.parse()
+            .map(Token::Float)
+            .map_err(|_| LexError::new(format!("Invalid float literal '{}'", text), self.line, self.col))
+    }
+
+    // Parses a hexadecimal or binary literal, starting at its `0x` or `0b` prefix.
+    fn prefixed_number(&mut self, radix: u32) -> Result<Token, LexError> {
+        let (line, col) = (self.line, self.col);
+        let prefix: String = self.input[self.pos..self.pos + 2].iter().collect();
+        self.advance();
+        self.advance(); // Move past the prefix
+        let digits = self.word(); // Read letters too, so a stray one is reported instead of lexed separately
+        if digits.is_empty() {
+            return Err(LexError::new(format!("Expected digits after '{}'", prefix), line, col));
+        }
+        let mut value: i32 = 0;
+        for ch in digits.chars() {
+            let digit = ch.to_digit(radix)
+                .ok_or_else(|| LexError::new(format!("Invalid digit '{}' in '{}{}'", ch, prefix, digits), line, col))?;
+            value = value
+                .checked_mul(radix as i32)
+                .and_then(|v| v.checked_add(digit as i32))
+                .ok_or_else(|| LexError::new(format!("Integer literal '{}{}' is too large", prefix, digits), line, col))?;
+        }
+        Ok(Token::Num(value))
+    }
+
+    // Parses an identifier or keyword from the current input.
+    fn identifier_or_keyword(&mut self) -> Token {
+        let word = self.word();
         match word.as_str() {
             "return" => Token::Return,
             "if" => Token::If,
             "else" => Token::Else,
             "while" => Token::While,
+            "for" => Token::For,
             "let" => Token::Let,
             "true" => Token::True,
             "false" => Token::False,
             "fn" => Token::Fn,
             "print" => Token::Print,
             "enum" => Token::Enum,
+            "struct" => Token::Struct,
             "sizeof" => Token::Sizeof,
+            "ref" => Token::Ref,
+            "switch" => Token::Switch,
+            "case" => Token::Case,
+            "default" => Token::Default,
+            "do" => Token::Do,
+            "const" => Token::Const,
+            "try" => Token::Try,
+            "catch" => Token::Catch,
+            "println" => Token::Println,
             "void" => Token::Identifier("void".to_string()),
             _ => Token::Identifier(word), // Return identifier token for variable names
         }
     }
 
     // Skips whitespace characters like spaces and newlines.
-    fn skip_whitespace_and_comments(&mut self) {
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), LexError> {
         loop {
             self.skip_whitespace(); // Skip whitespace
             if self.current_char() == Some('/') && self.match_char('/') { // Check for comments
@@ -326,22 +652,49 @@ impl Lexer {
                     }
                     self.advance();
                 }
+            } else if self.current_char() == Some('/') && self.match_char('*') { // Block comment
+                self.skip_block_comment()?;
             } else {
-                break; // End of whitespace and comments
+                return Ok(()); // End of whitespace and comments
             }
         }
     }
 
+    // Skips a block comment starting at the current `/*`.
+    // In nested mode, every `/*` inside the comment opens another level that needs its own `*/`.
+    // Reaching the end of the input inside the comment is an error at the comment's opening `/*`.
+    fn skip_block_comment(&mut self) -> Result<(), LexError> {
+        let (start_line, start_col) = (self.line, self.col);
+        self.advance();
+        self.advance(); // Move past '/*'
+        let mut depth = 1;
+        while let Some(c) = self.current_char() {
+            if c == '*' && self.match_char('/') {
+                self.advance();
+                self.advance(); // Move past '*/'
+                depth -= 1;
+                if depth == 0 {
+                    break; // End of the outermost comment
+                }
+            } else if self.nested_comments && c == '/' && self.match_char('*') {
+                self.advance();
+                self.advance(); // Move past the inner '/*'
+                depth += 1;
+            } else {
+                self.advance(); // `advance` keeps line/col up to date across newlines
+            }
+        }
+        if depth > 0 {
+            return Err(LexError::new("Unterminated block comment", start_line, start_col));
+        }
+        Ok(())
+    }
+
     // Skips whitespace characters like spaces and newlines.
     fn skip_whitespace(&mut self) {
         while let Some(ch) = self.current_char() {
-            if ch == '\n' {
-                self.line += 1; // Increment line number on newline
-                self.col = 1; // Reset column to 1
-                self.advance();
-            } else if ch.is_whitespace() {
-                self.col += 1; // Increment column number
-                self.advance();
+            if ch.is_whitespace() {
+                self.advance(); // `advance` keeps the line and column up to date
             } else {
                 break;
             }
@@ -356,6 +709,7 @@ impl Lexer {
     // Advances to the next character in the input.
     fn advance(&mut self) {
         if let Some(ch) = self.input.get(self.pos) {
+            self.offset += ch.len_utf8(); // Keep the byte offset in step with the character position
             if *ch == '\n' {
                 self.line += 1; // Increment line number on newline
                 self.col = 1; // Reset column to 1
@@ -366,13 +720,355 @@ impl Lexer {
         self.pos += 1; // Move to next character
     }
 
+    // Reads the two hex digits of a `\xNN` escape, leaving the last one as the current character.
+    // The escape stands for the character with that code (e.g., `\x41` is `A`).
+    fn hex_escape(&mut self) -> Result<char, LexError> {
+        let mut code = 0;
+        for _ in 0..2 {
+            self.advance();
+            match self.current_char().and_then(|c| c.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(LexError::new("Expected two hex digits after \\x", self.line, self.col)),
+            }
+        }
+        Ok(char::from(code as u8))
+    }
+
     // Returns the current character in the input.
     fn current_char(&self) -> Option<char> {
         self.input.get(self.pos).copied()
     }
 
     // Returns the current line and column position in the input.
+    #[allow(dead_code)]
     pub fn get_position(&self) -> (usize, usize) {
         (self.line, self.col)
     }
+
+    // Returns the current byte offset in the input, at the same point as `get_position`.
+    // Editor integrations (e.g. LSP) use byte offsets to map positions onto source ranges.
+    #[allow(dead_code)]
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// Iterates over the tokens of the input, e.g. `Lexer::new("let x = 1;").collect::<Result<Vec<_>, _>>()`.
+/// The last item yielded is `Eof` or the first error, after which the iterator stops.
+impl Iterator for Lexer {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Result<Token, LexError>> {
+        if self.finished {
+            return None;
+        }
+        let token = self.next_token();
+        self.finished = matches!(token, Ok(Token::Eof) | Err(_));
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects all tokens of the input, up to but not including `Eof`.
+    fn tokens(lexer: &mut Lexer) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token().unwrap() {
+                Token::Eof => return tokens,
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    /// Lexes the input up to its first error and returns that error.
+    fn lex_error(input: &str) -> LexError {
+        let mut lexer = Lexer::new(input);
+        loop {
+            match lexer.next_token() {
+                Ok(Token::Eof) => panic!("expected a lexing error"),
+                Ok(_) => {}
+                Err(err) => return err,
+            }
+        }
+    }
+
+    /// Tests that block comments are skipped, including across lines.
+    #[test]
+    fn test_block_comment() {
+        let mut lexer = Lexer::new("1 /* one\ntwo */ + 2");
+        assert_eq!(tokens(&mut lexer), vec![Token::Num(1), Token::Add, Token::Num(2)]);
+    }
+
+    /// Tests that block comments don't nest by default, as in C.
+    #[test]
+    fn test_block_comments_do_not_nest_by_default() {
+        let mut lexer = Lexer::new("/* a /* b */ c */ 1");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![Token::Identifier("c".to_string()), Token::Mul, Token::Div, Token::Num(1)]
+        );
+    }
+
+    /// Tests that each newline advances the line number exactly once.
+    #[test]
+    fn test_line_numbers() {
+        let mut lexer = Lexer::new("a\n\nb\n  c");
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.get_position().0, 1);
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.get_position().0, 3);
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.get_position().0, 4);
+    }
+
+    /// Tests that nested block comments are closed by the outer `*/` when enabled.
+    #[test]
+    fn test_nested_block_comments() {
+        let mut lexer = Lexer::new("/* a /* b */ c */ 1");
+        lexer.set_nested_comments(true);
+        assert_eq!(tokens(&mut lexer), vec![Token::Num(1)]);
+    }
+
+    /// Tests that spaces before a token advance the column by one each.
+    #[test]
+    fn test_column_after_spaces() {
+        let mut lexer = Lexer::new("    x  \t y");
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("x".to_string()));
+        assert_eq!(lexer.get_position(), (1, 6)); // `x` sits in column 5
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("y".to_string()));
+        assert_eq!(lexer.get_position(), (1, 11));
+    }
+
+    /// Tests that `...` is a single token.
+    #[test]
+    fn test_ellipsis() {
+        let mut lexer = Lexer::new("(n, ...)");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![Token::OpenParen, Token::Identifier("n".to_string()), Token::Comma, Token::Ellipsis, Token::CloseParen]
+        );
+    }
+
+    /// Tests that the offset counts bytes, including multi-byte characters in strings.
+    #[test]
+    fn test_byte_offset() {
+        let mut lexer = Lexer::new("x = \"é\";\ny");
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("x".to_string()));
+        assert_eq!(lexer.get_offset(), 1);
+        lexer.next_token().unwrap(); // =
+        lexer.next_token().unwrap(); // "é", two bytes in UTF-8
+        assert_eq!(lexer.get_offset(), 8);
+        lexer.next_token().unwrap(); // ;
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("y".to_string()));
+        assert_eq!(lexer.get_offset(), 11);
+        assert_eq!(lexer.get_position(), (2, 2));
+    }
+
+    /// Tests that a `#define` line is replaced by nothing and its uses by the macro body,
+    /// including macros used inside another macro's body.
+    #[test]
+    fn test_define_substitution() {
+        let mut lexer = Lexer::new("#define N 4\n#define TWICE_N (N * 2)\nTWICE_N + N");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![
+                Token::OpenParen, Token::Num(4), Token::Mul, Token::Num(2), Token::CloseParen,
+                Token::Add, Token::Num(4),
+            ]
+        );
+    }
+
+    /// Tests that a macro referring to itself is expanded only once.
+    #[test]
+    fn test_define_self_reference() {
+        let mut lexer = Lexer::new("#define x x + 1\nx");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![Token::Identifier("x".to_string()), Token::Add, Token::Num(1)]
+        );
+    }
+
+    /// Tests that redefining a macro with the same body is allowed, as in C.
+    #[test]
+    fn test_define_identical_redefinition() {
+        let mut lexer = Lexer::new("#define N 4\n#define N  4\nN");
+        assert_eq!(tokens(&mut lexer), vec![Token::Num(4)]);
+    }
+
+    /// Tests that redefining a macro with a different body is an error.
+    #[test]
+    fn test_define_conflicting_redefinition() {
+        assert_eq!(lex_error("#define N 4\n#define N 5\nN"), LexError::new("Macro 'N' redefined with a different value", 2, 1));
+    }
+
+    /// Tests that directives other than `#define` are rejected.
+    #[test]
+    fn test_unknown_directive() {
+        assert_eq!(lex_error("#include <stdio.h>\n"), LexError::new("Unknown preprocessor directive '#include'", 1, 1));
+    }
+
+    /// Tests hexadecimal and binary literals with either case of prefix and digits.
+    #[test]
+    fn test_hex_and_binary_literals() {
+        let mut lexer = Lexer::new("0xFF 0Xff 0x1aB 0b1010 0B0 0 42");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![Token::Num(255), Token::Num(255), Token::Num(427), Token::Num(10), Token::Num(0), Token::Num(0), Token::Num(42)]
+        );
+    }
+
+    /// Tests that a prefix without digits is an error.
+    #[test]
+    fn test_hex_literal_without_digits() {
+        assert_eq!(lex_error("let x = 0x;"), LexError::new("Expected digits after '0x'", 1, 9));
+    }
+
+    /// Tests that a digit outside the literal's base is an error.
+    #[test]
+    fn test_binary_literal_invalid_digit() {
+        assert_eq!(lex_error("0b102"), LexError::new("Invalid digit '2' in '0b102'", 1, 1));
+    }
+
+    /// Tests that a decimal literal too large for an `int` is an error at its position, not a wrapped value.
+    #[test]
+    fn test_decimal_literal_too_large() {
+        assert_eq!(tokens(&mut Lexer::new("2147483647")), vec![Token::Num(i32::MAX)]);
+        assert_eq!(lex_error("let x = 99999999999;"), LexError::new("Integer literal '99999999999' is too large", 1, 9));
+        // The `-` is a separate token, so `i32::MIN` is written `-2147483647 - 1`, as in C
+        assert_eq!(lex_error("-2147483648"), LexError::new("Integer literal '2147483648' is too large", 1, 2));
+    }
+
+    /// Tests float literals, with and without an exponent, next to integers.
+    #[test]
+    fn test_float_literals() {
+        let mut lexer = Lexer::new("2.5 0.125 1.5e3 2.0E-1 7");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![Token::Float(2.5), Token::Float(0.125), Token::Float(1500.0), Token::Float(0.2), Token::Num(7)]
+        );
+    }
+
+    /// Tests collecting the tokens of a statement through the `Iterator` implementation.
+    #[test]
+    fn test_lexer_iterator() {
+        let tokens: Result<Vec<Token>, LexError> = Lexer::new("let x = 1;").collect();
+        assert_eq!(
+            tokens,
+            Ok(vec![Token::Let, Token::Identifier("x".to_string()), Token::Assign, Token::Num(1), Token::Semicolon, Token::Eof])
+        );
+        let mut lexer = Lexer::new("");
+        assert_eq!(lexer.next(), Some(Ok(Token::Eof)));
+        assert_eq!(lexer.next(), None); // Stops after `Eof`
+        let mut lexer = Lexer::new("1 \"open");
+        assert_eq!(lexer.next(), Some(Ok(Token::Num(1))));
+        assert_eq!(lexer.next(), Some(Err(LexError::new("Unterminated string literal", 1, 8))));
+        assert_eq!(lexer.next(), None); // And after an error
+    }
+
+    /// Tests that spanned tokens carry the position of their first character.
+    #[test]
+    fn test_next_spanned() {
+        let mut lexer = Lexer::new("let  x =\n  42;");
+        let mut spans = Vec::new();
+        loop {
+            let spanned = lexer.next_spanned().unwrap();
+            if spanned.token == Token::Eof {
+                break;
+            }
+            spans.push((spanned.line, spanned.col));
+        }
+        assert_eq!(spans, vec![(1, 1), (1, 6), (1, 8), (2, 3), (2, 5)]);
+    }
+
+    /// Tests that spanned tokens carry the byte offset of their first character, counting multi-byte characters.
+    #[test]
+    fn test_next_spanned_offset() {
+        let mut lexer = Lexer::new("s = \"é\";\n  größe");
+        let offsets: Vec<usize> = std::iter::from_fn(|| Some(lexer.next_spanned().unwrap()))
+            .take_while(|spanned| spanned.token != Token::Eof)
+            .map(|spanned| spanned.offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 2, 4, 8, 12]);
+    }
+
+    /// Tests that `token_start` points at the first character of a token, not past its end.
+    #[test]
+    fn test_token_start() {
+        let mut lexer = Lexer::new("let 123 = 1;");
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.next_token().unwrap(), Token::Num(123));
+        assert_eq!(lexer.token_start(), (1, 5));
+        assert_eq!(lexer.get_position(), (1, 8));
+    }
+
+    /// Tests that a block comment within a line is skipped and columns continue after it.
+    #[test]
+    fn test_single_line_block_comment() {
+        let mut lexer = Lexer::new("a /* note */ b");
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("a".to_string()));
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("b".to_string()));
+        assert_eq!(lexer.token_start(), (1, 14));
+    }
+
+    /// Tests that lines are counted inside a multi-line block comment.
+    #[test]
+    fn test_multi_line_block_comment_position() {
+        let mut lexer = Lexer::new("/* one\n   two\n*/  x");
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("x".to_string()));
+        assert_eq!(lexer.token_start(), (3, 5));
+    }
+
+    /// Tests that an unterminated block comment is reported where it starts.
+    #[test]
+    fn test_unterminated_block_comment() {
+        assert_eq!(lex_error("x\n  /* never closed\n"), LexError::new("Unterminated block comment", 2, 3));
+    }
+
+    /// Tests the `\r`, `\0` and `\xNN` escape sequences in string literals.
+    #[test]
+    fn test_string_escapes() {
+        let mut lexer = Lexer::new(r#""a\x41b" "\0" "\r\n" "\xff""#);
+        assert_eq!(lexer.next_token().unwrap(), Token::StringLiteral("aAb".to_string()));
+        assert_eq!(lexer.next_token().unwrap(), Token::StringLiteral("\0".to_string()));
+        assert_eq!(lexer.next_token().unwrap(), Token::StringLiteral("\r\n".to_string()));
+        assert_eq!(lexer.next_token().unwrap(), Token::StringLiteral("\u{ff}".to_string()));
+    }
+
+    /// Tests that `\x` without two hex digits is reported at the offending character.
+    #[test]
+    fn test_invalid_hex_escape() {
+        let err = lex_error(r#""\x4g""#);
+        assert_eq!(err, LexError::new("Expected two hex digits after \\x", 1, 5));
+        assert_eq!(err.to_string(), "Expected two hex digits after \\x at line 1, column 5");
+    }
+
+    /// Tests identifiers written with non-ASCII letters and digits.
+    #[test]
+    fn test_unicode_identifiers() {
+        let mut lexer = Lexer::new("größe π2 переменная");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![
+                Token::Identifier("größe".to_string()),
+                Token::Identifier("π2".to_string()),
+                Token::Identifier("переменная".to_string()),
+            ]
+        );
+    }
+
+    /// Tests that `..` is one token, distinct from `.` and `...`, and doesn't start a float.
+    #[test]
+    fn test_dot_dot() {
+        let mut lexer = Lexer::new("a[1..3] ... .");
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![
+                Token::Identifier("a".to_string()), Token::OpenBracket, Token::Num(1), Token::DotDot, Token::Num(3),
+                Token::CloseBracket, Token::Ellipsis, Token::Dot,
+            ]
+        );
+    }
 }
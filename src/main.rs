@@ -1,40 +1,159 @@
 /// This program reads a C4 source file, tokenizes it using the lexer, parses it into
 /// an abstract syntax tree (AST) using the parser, and executes the resulting statements
 /// using the virtual machine (VM).
+///
+/// The program is read from the file named on the command line; without one, it is read from
+/// stdin when stdin is piped (e.g. `cat prog.c4 | compiler`), and otherwise the example file
+/// `examples/compiler.c4` is run.
+///
+/// Command-line options:
+/// - `--eval <code>`: run the given source code instead of a file and print its result.
+/// - `--json-errors`: report parse and runtime errors as JSON objects on stderr
+///   (see the `diagnostic` module) instead of plain text.
+/// - `--time`: after running, report on stderr how long parsing and execution took and how
+///   many statements were executed.
+/// - `--repl`: start an interactive session that runs each line as it is entered (see the `repl` module).
 mod ast;
+mod builtins;
+mod diagnostic;
+mod error;
+mod fold;
 mod lexer;
 mod parser;
+mod repl;
+mod resolver;
 mod vm;
 
-use lexer::Lexer;
-use parser::Parser;
-use std::fs;
-use vm::Vm;
-
+use error::Error;
+use std::time::{Duration, Instant};
+use std::io::{IsTerminal, Read};
+use std::{env, fs, io, process};
+use vm::{Value, Vm};
 
 fn main() {
-    // Read the source code from the C4 file.
-    let source_code =
-        fs::read_to_string("examples/compiler.c4").expect("Failed to read C4 source file");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let json_errors = args.iter().any(|arg| arg == "--json-errors");
+    let time = args.iter().any(|arg| arg == "--time");
+    let eval = args.iter().position(|arg| arg == "--eval").map(|i| {
+        args.get(i + 1).cloned().unwrap_or_else(|| {
+            eprintln!("error: --eval requires a source string");
+            process::exit(2);
+        })
+    });
 
-    // Initialize the lexer, parser, and virtual machine.
-    let lexer = Lexer::new(&source_code);
-    let mut vm = Vm::new();
-    let mut parser = Parser::new(lexer, &mut vm);
+    if args.iter().any(|arg| arg == "--repl") {
+        let stdin = io::stdin();
+        if let Err(e) = repl::Repl::new().run(stdin.lock(), io::stdout()) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // The first argument that isn't an option (or the value of `--eval`) names the source file.
+    let file = args.iter().enumerate()
+        .find(|(i, arg)| !arg.starts_with("--") && (*i == 0 || args[i - 1] != "--eval"))
+        .map(|(_, arg)| arg.as_str());
+
+    // Read the source code from the command line, the named file, stdin, or the example file.
+    let source_code = match (&eval, file) {
+        (Some(code), _) => code.clone(),
+        (None, Some(path)) => fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("error: Failed to read {}: {}", path, e);
+            process::exit(2);
+        }),
+        (None, None) if !io::stdin().is_terminal() => {
+            let mut code = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut code) {
+                eprintln!("error: Failed to read the program from stdin: {}", e);
+                process::exit(2);
+            }
+            code
+        }
+        (None, None) => fs::read_to_string("examples/compiler.c4").expect("Failed to read C4 source file"),
+    };
+
+    let mut stats = RunStats::default();
+    let result = run_source_with_stats(&source_code, &mut stats);
+    if time {
+        eprintln!("{}", stats);
+    }
+    match result {
+        Ok(None) => println!("Program finished. No statements to execute."),
+        Ok(Some(mut vm)) => {
+            if let Err(e) = vm.end_output_line() { // A `print` may have left the line open
+                eprintln!("error: Failed to write program output: {}", e);
+                process::exit(1);
+            }
+            // Print the final result of the program in its literal form (e.g. `"hi"` or `[1, 2]`).
+            // A program that doesn't return a value has a void result, which prints nothing.
+            match (vm.get_result_value(), eval.is_some()) {
+                (Some(value), true) => println!("{}", value),
+                (None, true) => {}
+                (Some(value), false) => println!("Program finished. Final result = {}", value),
+                (None, false) => println!("Program finished."),
+            }
+        }
+        Err(err) => {
+            if json_errors {
+                eprintln!("{}", diagnostic::to_json(&[err.diagnostic()]));
+            } else {
+                eprintln!("error: {}", err);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses and executes the given source code, returning the VM holding the final result,
+/// or `None` if the program contains no statements (e.g. only whitespace and comments).
+///
+/// Errors are returned as an `Error` whose variant tells whether lexing, parsing or
+/// running the program failed.
+pub fn run_source(source_code: &str) -> Result<Option<Vm>, Error> {
+    run_source_with_stats(source_code, &mut RunStats::default())
+}
 
-    // Parse the source code into a list of statements.
-    let statements = parser.parse();
+/// Statistics about a run of a program, reported by `--time`.
+#[derive(Debug, Default)]
+pub struct RunStats {
+    /// How long lexing, parsing and constant folding took.
+    pub parse: Duration,
+    /// How long executing the program took.
+    pub execute: Duration,
+    /// The number of statements executed (see `Vm::statements_executed`).
+    pub statements: u64,
+}
 
-    // Execute each statement using the virtual machine.
-    for stmt in statements {
-        vm.execute(stmt);
+impl std::fmt::Display for RunStats {
+    /// Formats the statistics as one line (e.g. `time: parse 0.120ms, execute 1.500ms, 42 statements`).
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(f, "time: parse {:.3}ms, execute {:.3}ms, {} statements", ms(self.parse), ms(self.execute), self.statements)
     }
+}
 
-    // Print the final result of the program.
-    if let Some(s) = vm.get_result_str() {
-        println!("Program finished. Final result = \"{}\"", s);
-    } else {
-        println!("Program finished. Final result = {}", vm.get_result());
+/// Like `run_source`, but also records in `stats` how long each phase took, including when the
+/// program fails part-way.
+pub fn run_source_with_stats(source_code: &str, stats: &mut RunStats) -> Result<Option<Vm>, Error> {
+    let mut vm = Vm::new();
+    let start = Instant::now();
+    let statements = vm.compile(source_code);
+    stats.parse = start.elapsed();
+    let statements = statements?;
+    if statements.is_empty() {
+        return Ok(None);
     }
-    
+    let start = Instant::now();
+    let result = vm.execute_program(statements);
+    stats.execute = start.elapsed();
+    stats.statements = vm.statements_executed;
+    result?;
+    Ok(Some(vm))
+}
+
+/// Runs the given source code and returns its result: the value of its top-level `return`,
+/// or `None` if it has no `return` or no statements at all.
+pub fn eval(source_code: &str) -> Result<Option<Value>, Error> {
+    Vm::new().run(source_code)
 }
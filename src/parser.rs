@@ -1,7 +1,88 @@
-use crate::ast::{Expr, Stmt, BinOp, UnOp, Type}; // Import AST types (expressions, statements, etc.)
-use crate::lexer::{Lexer, Token}; // Import Lexer and Token definitions
+use crate::ast::{Expr, Stmt, BinOp, UnOp, Type, Param}; // Import AST types (expressions, statements, etc.)
+use crate::fold::fold_expr; // Constant folding, used for array sizes
+use crate::lexer::{LexError, Lexer, Spanned, Token}; // Import Lexer and Token definitions
 use crate::vm::Vm; // Import the VM module for code execution
-use std::collections::HashMap; // Import HashMap for storing type mappings
+use std::collections::{HashMap, VecDeque}; // Import HashMap for storing type mappings
+use std::fmt;
+
+/// The default limit on expression nesting, well below what overflows the stack of a test thread.
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// A syntax error, with the position the parser was at when it found it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// What went wrong (e.g. "Expected ';' after let").
+    pub message: String,
+    /// The line the error was found at.
+    pub line: usize,
+    /// The column the error was found at.
+    pub col: usize,
+}
+
+impl ParseError {
+    /// Creates a parse error with the given message and position.
+    pub fn new(message: impl Into<String>, line: usize, col: usize) -> Self {
+        ParseError { message: message.into(), line, col }
+    }
+}
+
+impl fmt::Display for ParseError {
+    /// Formats the error as its message followed by its position.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}, column {}", self.message, self.line, self.col)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<LexError> for ParseError {
+    /// Converts a lexing error into a syntax error at the same position.
+    fn from(err: LexError) -> Self {
+        ParseError::new(err.message, err.line, err.col)
+    }
+}
+
+/// Returns whether `name` is a built-in type name that can start a declaration or cast.
+fn is_type_name(name: &str) -> bool {
+    matches!(name, "int" | "char" | "float" | "bool" | "str" | "void")
+}
+
+/// Builds `sizeof(typ)` for a `sizeof` at `line`/`col`, or an error if the size doesn't fit in an `int`.
+fn sizeof_type(typ: Type, line: usize, col: usize) -> Result<Expr, ParseError> {
+    match typ.size() {
+        Some(_) => Ok(Expr::SizeOf(typ)),
+        None => Err(ParseError::new("Type too large for sizeof", line, col)),
+    }
+}
+
+/// Returns the binary operator a token stands for and its precedence, from 1 (`||`, loosest)
+/// to 11 (`**`, tightest). As in C, `&`, `^` and `|` bind looser than comparisons,
+/// so `a & b == c` is `a & (b == c)`, and equality binds looser than `<` and friends.
+fn binary_operator(token: &Token) -> Option<(BinOp, u8)> {
+    let operator = match token {
+        Token::Or => (BinOp::Or, 1),
+        Token::And => (BinOp::And, 2),
+        Token::BitOr => (BinOp::BitOr, 3),
+        Token::BitXor => (BinOp::BitXor, 4),
+        Token::BitAnd => (BinOp::BitAnd, 5),
+        Token::Equal => (BinOp::Equal, 6),
+        Token::NotEqual => (BinOp::NotEqual, 6),
+        Token::LessThan => (BinOp::LessThan, 7),
+        Token::GreaterThan => (BinOp::GreaterThan, 7),
+        Token::LessEqual => (BinOp::LessEqual, 7),
+        Token::GreaterEqual => (BinOp::GreaterEqual, 7),
+        Token::Shl => (BinOp::Shl, 8),
+        Token::Shr => (BinOp::Shr, 8),
+        Token::Add => (BinOp::Add, 9),
+        Token::Sub => (BinOp::Sub, 9),
+        Token::Mul => (BinOp::Mul, 10),
+        Token::Div => (BinOp::Div, 10),
+        Token::Mod => (BinOp::Mod, 10),
+        Token::Pow => (BinOp::Pow, 11),
+        _ => return None,
+    };
+    Some(operator)
+}
 
 /// The `Parser` struct is responsible for parsing the input source code
 /// into an intermediate representation that can be processed by the VM.
@@ -12,11 +93,20 @@ use std::collections::HashMap; // Import HashMap for storing type mappings
 /// - `current_token`: The current token being processed by the parser.
 /// - `vm`: A mutable reference to the `Vm` instance, which executes the parsed code.
 /// - `type_map`: A `HashMap` that maps type names (as `String`) to their corresponding `Type` definitions.
+/// - `trailing_expression`: Whether a final expression without a semicolon becomes the program result.
+/// - `depth` / `max_depth`: The current and maximum nesting depth of expressions being parsed.
+/// - `lex_error`: The error the lexer stopped at, if any (see `lex_error`).
 pub struct Parser<'a> {
     lexer: Lexer, // Lexer instance to tokenize the input
     current_token: Token, // Current token to be processed
+    token_position: (usize, usize), // Line and column where the current token starts
+    lookahead: VecDeque<Spanned>, // Tokens after the current one, already read by `peek`
     vm: &'a mut Vm, // Reference to the virtual machine for execution
     type_map: HashMap<String, Type>, // A map for storing types (e.g., int, char, etc.)
+    trailing_expression: bool, // Accept a semicolon-less expression at the end of the input
+    depth: usize, // How deeply the expression being parsed is nested
+    max_depth: usize, // Nesting limit, reported as an error instead of overflowing the stack
+    lex_error: Option<LexError>, // Why the lexer stopped, if it failed
 }
 
 impl<'a> Parser<'a> {
@@ -26,614 +116,956 @@ impl<'a> Parser<'a> {
         let mut parser = Self {
             lexer,
             current_token: Token::Eof, // Start with EOF (End of File) token
+            token_position: (1, 1),
+            lookahead: VecDeque::new(),
             vm,
             type_map: HashMap::new(), // Initialize the type map
+            trailing_expression: false, // Off by default: every expression statement needs a ';'
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            lex_error: None,
         };
-        parser.next(); // Move to the first token
+        let _ = parser.next(); // Move to the first token; a lexing error is kept for `parse` to report
         parser
     }
 
-    /// Advances to the next token in the input.
-    pub fn next(&mut self) {
-        self.current_token = self.lexer.next_token(); // Get the next token from the lexer
+    /// Enables or disables Rust-style trailing expressions. When enabled, a last top-level
+    /// expression without a semicolon (e.g. `2 + 3`) is returned as the program result.
+    #[allow(dead_code)]
+    pub fn set_trailing_expression(&mut self, enabled: bool) {
+        self.trailing_expression = enabled;
+    }
+
+    /// Sets how deeply expressions may be nested (parentheses, prefix operators, chained
+    /// assignments) before parsing stops with "Expression nesting too deep".
+    #[allow(dead_code)]
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Advances to the next token in the input, or fails with the lexer's error at its position.
+    pub fn next(&mut self) -> Result<(), ParseError> {
+        let next = match self.lookahead.pop_front() {
+            Some(spanned) => Ok(spanned),
+            None => self.lexer.next_spanned(), // Get the next token from the lexer
+        };
+        match next {
+            Ok(spanned) => {
+                self.current_token = spanned.token;
+                self.token_position = (spanned.line, spanned.col);
+                Ok(())
+            }
+            Err(err) => {
+                self.lex_error = Some(err.clone());
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Returns the token `ahead` tokens after the current one (1 for the next), without advancing.
+    fn peek(&mut self, ahead: usize) -> Result<&Token, ParseError> {
+        while self.lookahead.len() < ahead {
+            match self.lexer.next_spanned() {
+                Ok(spanned) => self.lookahead.push_back(spanned),
+                Err(err) => {
+                    self.lex_error = Some(err.clone());
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(&self.lookahead[ahead - 1].token)
+    }
+
+    /// Returns the lexing error that stopped parsing, if the source itself was malformed
+    /// (e.g. an unterminated string literal) rather than the tokens out of order.
+    pub fn lex_error(&self) -> Option<&LexError> {
+        self.lex_error.as_ref()
     }
 
-    /// Parses the entire input and returns a vector of statements.
-    pub fn parse(&mut self) -> Vec<Stmt> {
+    /// Returns the line and column where the current token starts, for error messages.
+    fn position(&self) -> (usize, usize) {
+        self.token_position
+    }
+
+    /// Parses the entire input and returns a vector of statements,
+    /// or the first syntax error in it.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        if let Some(err) = &self.lex_error {
+            return Err(err.clone().into()); // The first token could not be read
+        }
         let mut statements = Vec::new(); // Initialize an empty vector to hold statements
         while self.current_token != Token::Eof { // Loop until EOF is encountered
-            statements.push(self.statement()); // Parse each statement
+            statements.push(self.located_statement()?); // Parse each statement
         }
-        statements
+        Ok(statements)
+    }
+
+    /// Parses a single statement and annotates it with the line it starts on.
+    fn located_statement(&mut self) -> Result<Stmt, ParseError> {
+        let (line, _) = self.position(); // Line of the statement's first token
+        let stmt = self.statement()?;
+        Ok(Stmt::Located { line, stmt: Box::new(stmt) })
     }
 
     /// Parses a single statement from the input.
     /// It handles various kinds of statements (e.g., variable declarations, function declarations, etc.)
-    fn statement(&mut self) -> Stmt {
-        let (line, col) = self.lexer.get_position(); // Get the current position (line, column)
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        let (line, col) = self.position(); // Get the current position (line, column)
 
         // Check for function or typed variable declaration
         if let Token::Identifier(ref type_name) = self.current_token {
-            if matches!(type_name.as_str(), "int" | "char" | "bool" | "str" | "void") {
-                let var_type = self.parse_type().unwrap(); // Parse the variable type
-                let (name_line, name_col) = self.lexer.get_position(); // Get position of the variable name
-                let name = self.expect_identifier("Expected name after type", name_line, name_col); // Expect a valid identifier for variable name
+            if is_type_name(type_name) {
+                let var_type = self.parse_type()?.unwrap(); // Parse the variable type
+                let name = self.expect_identifier("Expected name after type")?; // Expect a valid identifier for variable name
 
                 // If the next token is an opening parenthesis, it’s a function declaration
                 if self.current_token == Token::OpenParen {
-                    self.next();
+                    self.next()?;
                     let mut params = Vec::new(); // Initialize an empty vector for function parameters
+                    let mut variadic = false;
                     while self.current_token != Token::CloseParen { // Parse parameters inside the parentheses
-                        let param_name = self.expect_identifier("Expected parameter name", line, col);
-                        params.push(param_name); // Add parameter name to the list
+                        if self.current_token == Token::Ellipsis { // `...` collects any extra arguments
+                            self.next()?;
+                            variadic = true;
+                            if self.current_token != Token::CloseParen {
+                                return Err(ParseError::new("'...' must be the last parameter", line, col));
+                            }
+                            break;
+                        }
+                        let by_ref = self.current_token == Token::Ref; // `ref` marks a by-reference parameter
+                        if by_ref {
+                            self.next()?;
+                        }
+                        let param_name = self.expect_identifier("Expected parameter name")?;
+                        let default = if self.current_token == Token::Assign { // Default value (e.g., `times = 1`)
+                            self.next()?;
+                            Some(self.expression()?)
+                        } else {
+                            if params.iter().any(|p: &Param| p.default.is_some()) {
+                                return Err(ParseError::new(format!("Parameter '{}' without a default follows a parameter with a default", param_name), line, col));
+                            }
+                            None
+                        };
+                        params.push(Param { name: param_name, by_ref, default }); // Add parameter to the list
                         if self.current_token == Token::Comma {
-                            self.next(); // Move past the comma
+                            self.next()?; // Move past the comma
                         } else if self.current_token != Token::CloseParen {
-                            panic!("Expected ',' or ')' in parameter list at line {}, column {}", line, col);
+                            return Err(ParseError::new("Expected ',' or ')' in parameter list", line, col));
                         }
                     }
-                    self.expect_token(Token::CloseParen, "Expected ')' after parameters", line, col); // Expect closing parenthesis
-                    let body = Box::new(self.block()); // Parse the body of the function
-                    return Stmt::Function {
+                    self.expect_token(Token::CloseParen, "Expected ')' after parameters")?; // Expect closing parenthesis
+                    if variadic && params.iter().any(|p| p.name == "args") { // `args` holds the extra arguments
+                        return Err(ParseError::new("A variadic function can't have a parameter named 'args', which holds its extra arguments", line, col));
+                    }
+                    let body = Box::new(self.block()?); // Parse the body of the function
+                    return Ok(Stmt::Function {
                         name,
                         params,
+                        variadic,
                         body,
                         return_type: Some(var_type),
-                    };
+                    });
                 } else {
                     // Handle variable declaration
-                    self.expect_token(Token::Assign, "Expected '=' after variable name", line, col); // Expect assignment operator
-                    let value = self.expression(); // Parse the expression on the right-hand side
+                    let value = if self.current_token == Token::Semicolon {
+                        var_type.default_initializer() // `int x;` declares a zero
+                    } else {
+                        self.expect_token(Token::Assign, "Expected '=' after variable name")?; // Expect assignment operator
+                        self.expression()? // Parse the expression on the right-hand side
+                    };
                     self.type_map.insert(name.clone(), var_type.clone()); // Add variable type to the type map
-                    self.expect_token(Token::Semicolon, "Expected ';' after variable declaration", line, col); // Expect semicolon
-                    return Stmt::Let { name, value, var_type: Some(var_type) }; // Return a Let statement
+                    self.expect_token(Token::Semicolon, "Expected ';' after variable declaration")?; // Expect semicolon
+                    return Ok(Stmt::Let { name, value, var_type: Some(var_type) }); // Return a Let statement
                 }
             }
         }
 
-        match &self.current_token {
+        let stmt = match &self.current_token {
             // Handle different types of statements
             Token::Return => {
-                self.next();
+                self.next()?;
                 let expr = if matches!(self.current_token, Token::Semicolon | Token::CloseBrace) {
                     Expr::Number(0) // If the next token is a semicolon or closing brace, return 0
                 } else {
-                    self.expression() // Otherwise, parse an expression
+                    self.expression()? // Otherwise, parse an expression
                 };
                 if self.current_token == Token::Semicolon {
-                    self.next(); // Consume the semicolon
+                    self.next()?; // Consume the semicolon
                 }
                 Stmt::Return(expr) // Return the parsed return statement
             }
 
             Token::Let => {
-                self.next();
+                self.next()?;
+                if self.current_token == Token::OpenBracket { // Destructuring (e.g., `let [a, b] = f();`)
+                    self.next()?;
+                    let mut names = Vec::new();
+                    let mut rest = None;
+                    while self.current_token != Token::CloseBracket {
+                        if self.current_token == Token::Ellipsis { // `...tail` collects the remaining elements
+                            self.next()?;
+                            rest = Some(self.expect_identifier("Expected identifier after '...' in 'let [...]'")?);
+                            if self.current_token != Token::CloseBracket {
+                                return Err(ParseError::new("Expected ']' after the rest element in 'let [...]'", line, col));
+                            }
+                            break;
+                        }
+                        names.push(self.expect_identifier("Expected identifier in 'let [...]'")?);
+                        if self.current_token == Token::Comma {
+                            self.next()?; // Consume the comma if present
+                        } else if self.current_token != Token::CloseBracket {
+                            return Err(ParseError::new("Expected ',' or ']' in 'let [...]'", line, col));
+                        }
+                    }
+                    self.next()?; // Consume the closing bracket
+                    self.expect_token(Token::Assign, "Expected '=' after 'let [...]'")?; // Expect assignment
+                    let value = self.expression()?; // Parse the array being unpacked
+                    self.expect_token(Token::Semicolon, "Expected ';' after let")?; // Expect semicolon
+                    return Ok(Stmt::LetArray { names, rest, value });
+                }
                 let mut decls = Vec::new(); // Initialize an empty vector for declarations
                 loop {
-                    let name = self.expect_identifier("Expected identifier after 'let'", line, col); // Parse variable name
+                    let name = self.expect_identifier("Expected identifier after 'let'")?; // Parse variable name
                     let var_type = if self.current_token == Token::Colon {
-                        self.next();
-                        self.parse_type().unwrap_or(Type::Int) // Parse type after colon
+                        self.next()?;
+                        self.parse_type()?.unwrap_or(Type::Int) // Parse type after colon
                     } else {
                         Type::Int // Default to int if no type specified
                     };
-                    self.expect_token(Token::Assign, "Expected '=' after identifier", line, col); // Expect assignment
-                    let value = self.expression(); // Parse the expression
+                    let value = if matches!(self.current_token, Token::Semicolon | Token::Comma) {
+                        var_type.default_initializer() // `let a: int[4];` declares zeroes
+                    } else {
+                        self.expect_token(Token::Assign, "Expected '=' after identifier")?; // Expect assignment
+                        self.expression()? // Parse the expression
+                    };
                     self.type_map.insert(name.clone(), var_type.clone()); // Add variable to type map
                     decls.push(Stmt::Let { name, value, var_type: Some(var_type) }); // Add declaration to the list
                     if self.current_token == Token::Comma {
-                        self.next(); // Consume the comma if present
+                        self.next()?; // Consume the comma if present
                     } else {
                         break;
                     }
                 }
-                self.expect_token(Token::Semicolon, "Expected ';' after let", line, col); // Expect semicolon at the end
+                self.expect_token(Token::Semicolon, "Expected ';' after let")?; // Expect semicolon at the end
                 if decls.len() == 1 {
                     decls.pop().unwrap() // Return single declaration
                 } else {
-                    Stmt::Block(decls) // Return block if multiple declarations
+                    Stmt::LetGroup(decls) // Declared together in the current scope
                 }
             }
 
-            Token::Print => {
-                self.next();
-                self.expect_token(Token::OpenParen, "Expected '(' after 'print'", line, col); // Expect opening parenthesis
-                let expr = self.expression(); // Parse the expression to print
-                self.expect_token(Token::CloseParen, "Expected ')' after expression", line, col); // Expect closing parenthesis
-                self.expect_token(Token::Semicolon, "Expected ';' after print", line, col); // Expect semicolon
-                Stmt::Print(expr) // Return Print statement
+            Token::Print | Token::Println => {
+                let newline = self.current_token == Token::Println;
+                let keyword = if newline { "println" } else { "print" };
+                self.next()?;
+                self.expect_token(Token::OpenParen, &format!("Expected '(' after '{}'", keyword))?; // Expect opening parenthesis
+                let value = self.expression()?; // Parse the expression to print
+                self.expect_token(Token::CloseParen, "Expected ')' after expression")?; // Expect closing parenthesis
+                self.expect_token(Token::Semicolon, &format!("Expected ';' after {}", keyword))?; // Expect semicolon
+                Stmt::Print { value, newline } // Return Print statement
             }
 
             Token::If => {
-                self.next();
-                self.expect_token(Token::OpenParen, "Expected '(' after 'if'", line, col); // Expect opening parenthesis
-                let condition = self.expression(); // Parse the condition
-                self.expect_token(Token::CloseParen, "Expected ')' after condition", line, col); // Expect closing parenthesis
-                let then_branch = Box::new(self.statement()); // Parse the then branch
+                self.next()?;
+                self.expect_token(Token::OpenParen, "Expected '(' after 'if'")?; // Expect opening parenthesis
+                let condition = self.expression()?; // Parse the condition
+                self.expect_token(Token::CloseParen, "Expected ')' after condition")?; // Expect closing parenthesis
+                let then_branch = Box::new(self.statement()?); // Parse the then branch
                 let else_branch = if self.current_token == Token::Else {
-                    self.next();
-                    Some(Box::new(self.statement())) // Parse the else branch
+                    self.next()?;
+                    Some(Box::new(self.statement()?)) // Parse the else branch
                 } else {
                     None
                 };
                 Stmt::If { condition, then_branch, else_branch } // Return If statement
             }
 
+            Token::Switch => self.parse_switch()?,
+
             Token::While => {
-                self.next();
-                self.expect_token(Token::OpenParen, "Expected '(' after 'while'", line, col); // Expect opening parenthesis
-                let condition = self.expression(); // Parse the condition
-                self.expect_token(Token::CloseParen, "Expected ')' after condition", line, col); // Expect closing parenthesis
-                let body = Box::new(self.statement()); // Parse the body
+                self.next()?;
+                self.expect_token(Token::OpenParen, "Expected '(' after 'while'")?; // Expect opening parenthesis
+                let condition = self.expression()?; // Parse the condition
+                self.expect_token(Token::CloseParen, "Expected ')' after condition")?; // Expect closing parenthesis
+                let body = Box::new(self.statement()?); // Parse the body
                 Stmt::While { condition, body } // Return While statement
             }
 
-            Token::OpenBrace => self.block(), // Parse a block statement
+            Token::Do => {
+                self.next()?;
+                let body = Box::new(self.statement()?); // Parse the body, which runs at least once
+                self.expect_token(Token::While, "Expected 'while' after 'do' body")?;
+                self.expect_token(Token::OpenParen, "Expected '(' after 'while'")?;
+                let condition = self.expression()?;
+                self.expect_token(Token::CloseParen, "Expected ')' after condition")?;
+                self.expect_token(Token::Semicolon, "Expected ';' after do-while condition")?;
+                Stmt::DoWhile { body, condition }
+            }
+
+            Token::For => {
+                self.next()?;
+                self.expect_token(Token::OpenParen, "Expected '(' after 'for'")?; // Expect opening parenthesis
+                let init = if self.current_token == Token::Semicolon {
+                    self.next()?; // No initializer
+                    None
+                } else {
+                    Some(Box::new(self.statement()?)) // A declaration or expression statement, including its ';'
+                };
+                let condition = if self.current_token == Token::Semicolon {
+                    None // No condition: loop until `return`
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect_token(Token::Semicolon, "Expected ';' after 'for' condition")?;
+                let update = if self.current_token == Token::CloseParen {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.expect_token(Token::CloseParen, "Expected ')' after 'for' clauses")?; // Expect closing parenthesis
+                let body = Box::new(self.statement()?); // Parse the body
+                Stmt::For { init, condition, update, body } // Return For statement
+            }
+
+            Token::OpenBrace => self.block()?, // Parse a block statement
+
+            Token::Try => {
+                self.next()?;
+                let body = Box::new(self.statement()?);
+                self.expect_token(Token::Catch, "Expected 'catch' after 'try' body")?;
+                self.expect_token(Token::OpenParen, "Expected '(' after 'catch'")?;
+                let name = self.expect_identifier("Expected error variable name in 'catch'")?;
+                self.expect_token(Token::CloseParen, "Expected ')' after error variable")?;
+                let handler = Box::new(self.statement()?);
+                Stmt::Try { body, name, handler }
+            }
+
+            Token::Const => {
+                self.next()?;
+                let name = self.expect_identifier("Expected name after 'const'")?;
+                self.expect_token(Token::Assign, "Expected '=' after constant name")?;
+                let value = self.expression()?;
+                self.expect_token(Token::Semicolon, "Expected ';' after constant declaration")?;
+                Stmt::Const { name, value }
+            }
 
             Token::Enum => {
-                self.next();
-                self.expect_token(Token::OpenBrace, "Expected '{' after 'enum'", line, col); // Expect opening brace
+                self.next()?;
+                self.expect_token(Token::OpenBrace, "Expected '{' after 'enum'")?; // Expect opening brace
                 let mut value = 0;
                 while self.current_token != Token::CloseBrace {
-                    let name = self.expect_identifier("Expected identifier in enum", line, col); // Parse enum name
+                    let name = self.expect_identifier("Expected identifier in enum")?; // Parse enum name
                     if self.current_token == Token::Assign {
-                        self.next();
+                        self.next()?;
                         if let Token::Num(n) = self.current_token {
                             value = n;
-                            self.next();
+                            self.next()?;
                         } else {
-                            panic!("Expected number after '=' in enum");
+                            let (line, col) = self.position();
+                            return Err(ParseError::new("Expected number after '=' in enum", line, col));
                         }
                     }
                     self.vm.constants.insert(name.clone(), value); // Insert constant into VM constants
                     value += 1;
                     if self.current_token == Token::Comma {
-                        self.next(); // Consume the comma if present
+                        self.next()?; // Consume the comma if present
                     } else if self.current_token != Token::CloseBrace {
-                        panic!("Expected ',' or '}}' in enum declaration");
+                        return Err(ParseError::new("Expected ',' or '}' in enum declaration", line, col));
+                    }
+                }
+                self.expect_token(Token::CloseBrace, "Expected '}' after enum")?; // Expect closing brace
+                self.expect_token(Token::Semicolon, "Expected ';' after enum")?; // Expect semicolon
+                Stmt::Block(vec![]) // Return an empty block
+            }
+
+            Token::Struct => {
+                self.next()?;
+                let name = self.expect_identifier("Expected name after 'struct'")?; // Parse struct name
+                self.expect_token(Token::OpenBrace, "Expected '{' after struct name")?; // Expect opening brace
+                let mut fields: Vec<(String, Type)> = Vec::new();
+                while self.current_token != Token::CloseBrace {
+                    let (field_line, field_col) = self.position();
+                    let field_type = self.parse_type()?.ok_or_else(|| {
+                        ParseError::new(format!("Expected field type in struct '{}'", name), field_line, field_col)
+                    })?;
+                    let field = self.expect_identifier("Expected field name")?; // Parse field name
+                    if fields.iter().any(|(existing, _)| *existing == field) {
+                        return Err(ParseError::new(format!("Duplicate field '{}' in struct '{}'", field, name), field_line, field_col));
                     }
+                    fields.push((field, field_type));
+                    self.expect_token(Token::Semicolon, "Expected ';' after struct field")?;
                 }
-                self.expect_token(Token::CloseBrace, "Expected '}' after enum", line, col); // Expect closing brace
-                self.expect_token(Token::Semicolon, "Expected ';' after enum", line, col); // Expect semicolon
+                self.expect_token(Token::CloseBrace, "Expected '}' after struct fields")?; // Expect closing brace
+                self.expect_token(Token::Semicolon, "Expected ';' after struct")?; // Expect semicolon
+                self.vm.structs.insert(name.clone(), Type::Struct(name, fields)); // Register the layout for later types
                 Stmt::Block(vec![]) // Return an empty block
             }
 
             _ => {
-                let expr = self.expression(); // Parse expression statement
-                self.expect_token(Token::Semicolon, "Expected ';' after expression", line, col); // Expect semicolon
+                let expr = self.expression()?; // Parse expression statement
+                if self.trailing_expression && self.current_token == Token::Eof {
+                    return Ok(Stmt::Return(expr)); // A trailing expression is the program result
+                }
+                self.expect_token(Token::Semicolon, "Expected ';' after expression")?; // Expect semicolon
                 Stmt::ExprStmt(expr) // Return Expression statement
             }
+        };
+        Ok(stmt)
+    }
+
+    /// Parses a `switch` statement: `switch (e) { case 1: ... case 2: ... default: ... }`.
+    fn parse_switch(&mut self) -> Result<Stmt, ParseError> {
+        self.next()?;
+        self.expect_token(Token::OpenParen, "Expected '(' after 'switch'")?; // Expect opening parenthesis
+        let scrutinee = self.expression()?; // Parse the value to dispatch on
+        self.expect_token(Token::CloseParen, "Expected ')' after switch value")?; // Expect closing parenthesis
+        self.expect_token(Token::OpenBrace, "Expected '{' after 'switch (...)'")?; // Expect opening brace
+        let mut cases = Vec::new();
+        let mut default = None;
+        while self.current_token != Token::CloseBrace {
+            let (line, col) = self.position();
+            let value = match self.current_token {
+                Token::Case => {
+                    self.next()?;
+                    Some(self.expression()?) // Parse the case value
+                }
+                Token::Default if default.is_some() => {
+                    return Err(ParseError::new("Multiple 'default' labels in switch", line, col));
+                }
+                Token::Default => {
+                    self.next()?;
+                    None
+                }
+                _ => return Err(ParseError::new("Expected 'case' or 'default' in switch", line, col)),
+            };
+            self.expect_token(Token::Colon, "Expected ':' after case label")?; // Expect colon
+            let mut body = Vec::new();
+            while !matches!(self.current_token, Token::Case | Token::Default | Token::CloseBrace | Token::Eof) {
+                body.push(self.located_statement()?); // Parse the statements of the case
+            }
+            match value {
+                Some(value) => cases.push((value, body)),
+                None => default = Some(body),
+            }
         }
+        self.expect_token(Token::CloseBrace, "Expected '}' after switch cases")?; // Expect closing brace
+        Ok(Stmt::Switch { scrutinee, cases, default })
     }
 
     // Parse expressions and handle different precedence levels
-    fn expression(&mut self) -> Expr {
-        self.parse_ternary() // Start with ternary operator parsing
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        self.enter_nesting()?;
+        let expr = self.parse_ternary()?; // Start with ternary operator parsing
+        self.depth -= 1;
+        Ok(expr)
     }
 
-    fn parse_ternary(&mut self) -> Expr {
-        let condition = self.parse_assignment(); // Parse assignment expression
+    /// Records one more level of expression nesting, or returns an error if it goes past `max_depth`.
+    /// Every recursive path through the expression parser passes through a call to this.
+    fn enter_nesting(&mut self) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            let (line, col) = self.position();
+            return Err(ParseError::new(format!("Expression nesting too deep (limit {})", self.max_depth), line, col));
+        }
+        Ok(())
+    }
+
+    fn parse_ternary(&mut self) -> Result<Expr, ParseError> {
+        let condition = self.parse_assignment()?; // Parse assignment expression
         if self.current_token == Token::QuestionMark { // If ternary operator found
-            self.next();
-            let then_branch = self.expression(); // Parse then branch
-            self.expect_token(Token::Colon, "Expected ':' in ternary", 0, 0); // Expect colon
-            let else_branch = self.expression(); // Parse else branch
-            Expr::Ternary {
+            self.next()?;
+            let then_branch = self.expression()?; // Parse then branch
+            self.expect_token(Token::Colon, "Expected ':' in ternary")?; // Expect colon
+            let else_branch = self.expression()?; // Parse else branch
+            Ok(Expr::Ternary {
                 condition: Box::new(condition),
                 then_branch: Box::new(then_branch),
                 else_branch: Box::new(else_branch),
-            }
+            })
         } else {
-            condition
+            Ok(condition)
         }
     }
 
-    /// Parses assignment expressions (variable assignments or array assignments).
-    fn parse_assignment(&mut self) -> Expr {
-        let lhs = self.parse_logic_or(); // Parse the left-hand side of the assignment
+    /// Parses assignment expressions (variable, array element or struct field assignments).
+    fn parse_assignment(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.parse_binary(1)?; // Parse the left-hand side of the assignment
         if self.current_token == Token::Assign { // If the current token is an assignment operator
-            self.next(); // Consume the assignment token
-            let rhs = self.parse_assignment(); // Parse the right-hand side of the assignment
+            let (line, col) = self.position();
+            self.next()?; // Consume the assignment token
+            self.enter_nesting()?;
+            let rhs = self.parse_assignment()?; // Parse the right-hand side of the assignment
+            self.depth -= 1;
             match lhs {
-                Expr::Variable(name) => Expr::BinaryOp { // Handle variable assignment
+                Expr::Variable(name) => Ok(Expr::BinaryOp { // Handle variable assignment
                     op: BinOp::Assign, // Assignment operation
                     left: Box::new(Expr::Variable(name)),
                     right: Box::new(rhs),
-                },
-                Expr::ArrayIndex(array, index) => Expr::BinaryOp { // Handle array assignment
+                }),
+                Expr::ArrayIndex(..) | Expr::Member(..) | Expr::Deref(_) => Ok(Expr::BinaryOp { // Handle element, field and pointer assignment
                     op: BinOp::Assign,
-                    left: Box::new(Expr::ArrayIndex(array, index)),
+                    left: Box::new(lhs),
                     right: Box::new(rhs),
-                },
-                _ => panic!("Invalid assignment target"), // Error if the left-hand side is not a valid target
+                }),
+                _ => Err(ParseError::new("Invalid assignment target", line, col)), // Error if the left-hand side is not a valid target
             }
         } else {
-            lhs // If no assignment operator, return the left-hand side expression
-        }
-    }
-
-    /// Parses logical OR expressions (using `||`).
-    fn parse_logic_or(&mut self) -> Expr {
-        let mut lhs = self.parse_logic_and(); // Parse logical AND expression first
-        while self.current_token == Token::Or { // While we have a logical OR token
-            self.next(); // Consume the OR token
-            let rhs = self.parse_logic_and(); // Parse the right-hand side of the OR operation
-            lhs = Expr::BinaryOp { // Build a binary operation for OR
-                op: BinOp::Or,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the OR operation
-    }
-
-    /// Parses logical AND expressions (using `&&`).
-    fn parse_logic_and(&mut self) -> Expr {
-        let mut lhs = self.parse_bit_or(); // Parse bitwise OR first
-        while self.current_token == Token::And { // While we have a logical AND token
-            self.next(); // Consume the AND token
-            let rhs = self.parse_bit_or(); // Parse the right-hand side of the AND operation
-            lhs = Expr::BinaryOp { // Build a binary operation for AND
-                op: BinOp::And,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the AND operation
-    }
-
-    /// Parses bitwise OR expressions (using `|`).
-    fn parse_bit_or(&mut self) -> Expr {
-        let mut lhs = self.parse_bit_xor(); // Parse bitwise XOR first
-        while self.current_token == Token::BitOr { // While we have a bitwise OR token
-            self.next(); // Consume the OR token
-            let rhs = self.parse_bit_xor(); // Parse the right-hand side of the OR operation
-            lhs = Expr::BinaryOp { // Build a binary operation for OR
-                op: BinOp::BitOr,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the OR operation
-    }
-
-    /// Parses bitwise XOR expressions (using `^`).
-    fn parse_bit_xor(&mut self) -> Expr {
-        let mut lhs = self.parse_bit_and(); // Parse bitwise AND first
-        while self.current_token == Token::BitXor { // While we have a bitwise XOR token
-            self.next(); // Consume the XOR token
-            let rhs = self.parse_bit_and(); // Parse the right-hand side of the XOR operation
-            lhs = Expr::BinaryOp { // Build a binary operation for XOR
-                op: BinOp::BitXor,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the XOR operation
-    }
-
-    /// Parses bitwise AND expressions (using `&`).
-    fn parse_bit_and(&mut self) -> Expr {
-        let mut lhs = self.parse_cmp(); // Parse comparison expressions first
-        while self.current_token == Token::BitAnd { // While we have a bitwise AND token
-            self.next(); // Consume the AND token
-            let rhs = self.parse_cmp(); // Parse the right-hand side of the AND operation
-            lhs = Expr::BinaryOp { // Build a binary operation for AND
-                op: BinOp::BitAnd,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the AND operation
-    }
-
-    /// Parses comparison expressions (e.g., `==`, `!=`, `<`, `>`, `<=`, `>=`).
-    fn parse_cmp(&mut self) -> Expr {
-        let mut lhs = self.parse_shift(); // Parse shift operations first
-
-        while matches!(self.current_token, Token::Equal | Token::NotEqual | Token::LessThan | Token::GreaterThan | Token::LessEqual | Token::GreaterEqual) {
-            let op = match self.current_token {
-                Token::Equal => BinOp::Equal,
-                Token::NotEqual => BinOp::NotEqual,
-                Token::LessThan => BinOp::LessThan,
-                Token::GreaterThan => BinOp::GreaterThan,
-                Token::LessEqual => BinOp::LessEqual,
-                Token::GreaterEqual => BinOp::GreaterEqual,
-                _ => unreachable!(),
-            };
-            self.next(); // Consume the comparison operator
-            let rhs = self.parse_add_sub(); // Parse the right-hand side of the comparison
-            lhs = Expr::BinaryOp { // Build a binary operation for comparison
-                op,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
+            Ok(lhs) // If no assignment operator, return the left-hand side expression
         }
-        lhs // Return the result of the comparison
     }
 
-    /// Parses shift expressions (e.g., `<<`, `>>`).
-    fn parse_shift(&mut self) -> Expr {
-        let mut lhs = self.parse_add_sub(); // Parse addition and subtraction first
-        while matches!(self.current_token, Token::Shl | Token::Shr) { // While we have shift tokens
-            let op = match self.current_token {
-                Token::Shl => BinOp::Shl,
-                Token::Shr => BinOp::Shr,
-                _ => unreachable!(),
-            };
-            self.next(); // Consume the shift token
-            let rhs = self.parse_add_sub(); // Parse the right-hand side of the shift operation
-            lhs = Expr::BinaryOp { // Build a binary operation for shift
+    /// Parses the binary operators from `||` down to `**` by precedence climbing:
+    /// one call handles a chain of operators whose precedence is at least `min_precedence`
+    /// (see `binary_operator`), and operators of equal precedence group to the left,
+    /// except `**`, which groups to the right (`2 ** 3 ** 2` is `2 ** 9`).
+    /// A parenthesized operand thus costs one stack frame here rather than one per level.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_unary()?; // Parse unary operations first
+        while let Some((op, precedence)) = binary_operator(&self.current_token) {
+            if precedence < min_precedence {
+                break; // Leave looser operators to the caller
+            }
+            self.next()?; // Consume the operator
+            let right_precedence = if op == BinOp::Pow { precedence } else { precedence + 1 };
+            let rhs = self.parse_binary(right_precedence)?; // Parse the tighter-binding right-hand side
+            lhs = Expr::BinaryOp { // Build a binary operation
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
             };
         }
-        lhs // Return the result of the shift operation
+        Ok(lhs) // Return the combined expression
     }
 
-    /// Parses addition and subtraction expressions (e.g., `+`, `-`).
-    fn parse_add_sub(&mut self) -> Expr {
-        let mut lhs = self.parse_mul_div(); // Parse multiplication and division first
-        while matches!(self.current_token, Token::Add | Token::Sub) { // While we have addition or subtraction tokens
-            let op = match self.current_token {
-                Token::Add => BinOp::Add,
-                Token::Sub => BinOp::Sub,
-                _ => unreachable!(),
-            };
-            self.next(); // Consume the addition or subtraction token
-            let rhs = self.parse_mul_div(); // Parse the right-hand side of the operation
-            lhs = Expr::BinaryOp { // Build a binary operation for addition or subtraction
-                op,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the addition or subtraction
-    }
-
-    /// Parses multiplication, division, and modulus expressions (e.g., `*`, `/`, `%`).
-    fn parse_mul_div(&mut self) -> Expr {
-        let mut lhs = self.parse_unary(); // Parse unary operations first
-        while matches!(self.current_token, Token::Mul | Token::Div | Token::Mod) { // While we have multiplication, division, or modulus tokens
-            let op = match self.current_token {
-                Token::Mul => BinOp::Mul,
-                Token::Div => BinOp::Div,
-                Token::Mod => BinOp::Mod,
-                _ => unreachable!(),
-            };
-            self.next(); // Consume the multiplication, division, or modulus token
-            let rhs = self.parse_unary(); // Parse the right-hand side of the operation
-            lhs = Expr::BinaryOp { // Build a binary operation for multiplication, division, or modulus
-                op,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
-            };
-        }
-        lhs // Return the result of the multiplication, division, or modulus
+    /// Parses unary operations (e.g., negation, dereference, address-of).
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        self.enter_nesting()?;
+        let is_prefix = matches!(
+            self.current_token,
+            Token::Not | Token::Sub | Token::BitNot | Token::AddressOf | Token::Deref
+                | Token::PlusPlus | Token::MinusMinus | Token::BitAnd | Token::Mul | Token::Pow
+        );
+        let expr = if is_prefix {
+            self.parse_prefix()? // Parse a prefix operator and its operand
+        } else {
+            self.parse_primary()? // Parse primary expression if no unary operator
+        };
+        let expr = self.parse_postfix(expr)?; // Handle postfix operations like increment and decrement
+        self.depth -= 1;
+        Ok(expr)
     }
 
-    /// Parses unary operations (e.g., negation, dereference, address-of).
-    fn parse_unary(&mut self) -> Expr {
-        let expr = match self.current_token {
-            Token::Not => {
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the NOT operation
-                Expr::UnaryOp { op: UnOp::Not, expr: Box::new(expr) } // Return a NOT operation
-            }
-            Token::AddressOf => {
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the address-of operation
-                Expr::AddressOf(Box::new(expr)) // Return an AddressOf operation
-            }
-            Token::Deref => {
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the dereference operation
-                Expr::Deref(Box::new(expr)) // Return a Deref operation
+    /// Parses a prefix operator and the unary expression it applies to.
+    /// Kept apart from `parse_unary` so the frames of deeply nested operands stay small.
+    fn parse_prefix(&mut self) -> Result<Expr, ParseError> {
+        let (line, col) = self.position(); // Position of the operator
+        let op = self.current_token.clone();
+        self.next()?;
+        let expr = self.parse_unary()?; // Parse the operand, so `2 * -3` is `2 * (-3)`
+        let expr = match op {
+            Token::Not => Expr::UnaryOp { op: UnOp::Not, expr: Box::new(expr) }, // Return a NOT operation
+            Token::Sub if self.current_token == Token::Pow => { // `-2 ** 2` is `-(2 ** 2)`, as in maths
+                self.next()?;
+                let exponent = self.parse_binary(11)?; // The precedence of `**`, which groups to the right
+                let power = Expr::BinaryOp { op: BinOp::Pow, left: Box::new(expr), right: Box::new(exponent) };
+                Expr::UnaryOp { op: UnOp::Neg, expr: Box::new(power) }
             }
+            Token::Sub => Expr::UnaryOp { op: UnOp::Neg, expr: Box::new(expr) }, // Return a negation
+            Token::BitNot => Expr::UnaryOp { op: UnOp::BitNot, expr: Box::new(expr) }, // Return a bitwise NOT operation
+            Token::AddressOf | Token::BitAnd => Expr::AddressOf(Box::new(expr)), // Return an AddressOf operation
+            Token::Deref | Token::Mul => Expr::Deref(Box::new(expr)), // Return a Deref operation
+            Token::Pow => Expr::Deref(Box::new(Expr::Deref(Box::new(expr)))), // `**pp` lexes as one token
             Token::PlusPlus => {
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the pre-increment operation
+                self.check_assignable(&expr, "++", line, col)?;
                 Expr::PreInc(Box::new(expr)) // Return a pre-increment operation
             }
             Token::MinusMinus => {
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the pre-decrement operation
+                self.check_assignable(&expr, "--", line, col)?;
                 Expr::PreDec(Box::new(expr)) // Return a pre-decrement operation
             }
-            Token::BitAnd => {  // ✅ For bitwise AND
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the bitwise AND operation
-                Expr::AddressOf(Box::new(expr)) // Return an AddressOf operation
-            }
-            Token::Mul => {     // ✅ For dereference
-                self.next();
-                let expr = self.parse_unary(); // Parse the right-hand side of the dereference operation
-                Expr::Deref(Box::new(expr)) // Return a Deref operation
-            }
-            _ => self.parse_primary(), // Parse primary expression if no unary operator
+            _ => unreachable!("parse_prefix called on a non-prefix token"),
         };
-        self.parse_postfix(expr) // Handle postfix operations like increment and decrement
+        Ok(expr)
     }
 
-    /// Handles postfix operations (e.g., `++`, `--`).
-    fn parse_postfix(&mut self, mut expr: Expr) -> Expr {
+    /// Handles postfix operations (`.field`, `[index]`, `(args)`, `++` and `--`) in a loop,
+    /// so they chain in any order (e.g., `points[i].x` or `shape.vertices[0]`).
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr, ParseError> {
         loop {
             match self.current_token {
+                Token::Dot => {
+                    self.next()?;
+                    let field = self.expect_identifier("Expected field name after '.'")?;
+                    expr = Expr::Member(Box::new(expr), field); // Struct field access
+                }
+                Token::OpenBracket => {
+                    self.next()?;
+                    let index_expr = self.expression()?; // Parse the index expression
+                    if self.current_token == Token::DotDot {
+                        self.next()?;
+                        let end = self.expression()?;
+                        self.expect_token(Token::CloseBracket, "Expected ']' after slice")?;
+                        expr = Expr::Slice { target: Box::new(expr), start: Box::new(index_expr), end: Box::new(end) };
+                        continue;
+                    }
+                    self.expect_token(Token::CloseBracket, "Expected ']' after array index")?; // Expect closing bracket
+                    expr = Expr::ArrayIndex(Box::new(expr), Box::new(index_expr)); // Array index
+                }
+                Token::OpenParen => {
+                    let args = self.parse_arguments()?;
+                    expr = match expr {
+                        Expr::Variable(name) => Expr::FunctionCall { name, args }, // Call by name
+                        callee => Expr::Call { callee: Box::new(callee), args }, // e.g. `handlers[0](3)`
+                    };
+                }
                 Token::PlusPlus => {
-                    self.next();
+                    let (line, col) = self.position(); // Position of the operator
+                    self.check_assignable(&expr, "++", line, col)?;
+                    self.next()?;
                     expr = Expr::PostInc(Box::new(expr)); // Post-increment operation
                 }
                 Token::MinusMinus => {
-                    self.next();
+                    let (line, col) = self.position(); // Position of the operator
+                    self.check_assignable(&expr, "--", line, col)?;
+                    self.next()?;
                     expr = Expr::PostDec(Box::new(expr)); // Post-decrement operation
                 }
                 _ => break, // Exit loop if no more postfix operators
             }
         }
-        expr // Return the final expression with postfix operations applied
+        Ok(expr) // Return the final expression with postfix operations applied
     }
 
     /// Parses primary expressions (e.g., numbers, strings, identifiers, etc.)
-    fn parse_primary(&mut self) -> Expr {
-        let (line, col) = self.lexer.get_position(); // Get the position of the current token
-        match &self.current_token {
-            Token::Num(n) => { let val = *n; self.next(); Expr::Number(val) } // Parse number literal
-            Token::True => { self.next(); Expr::Boolean(true) } // Parse boolean true
-            Token::False => { self.next(); Expr::Boolean(false) } // Parse boolean false
-            Token::Char(c) => { let ch = *c; self.next(); Expr::Char(ch) } // Parse character literal
-            Token::StringLiteral(s) => { let val = s.clone(); self.next(); Expr::StringLiteral(val) } // Parse string literal
-    
-            Token::Sizeof => {
-                self.next();
-                self.expect_token(Token::OpenParen, "Expected '(' after sizeof", line, col); // Expect '('
-                let typ = self.parse_type().unwrap_or(Type::Int); // Parse the type after sizeof
-                self.expect_token(Token::CloseParen, "Expected ')' after type", line, col); // Expect ')'
-                Expr::SizeOf(typ) // Return SizeOf expression
+    /// Anything beyond a literal is parsed by a helper, which keeps this frame small
+    /// since it is on the stack once per level of parentheses.
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let (line, col) = self.position(); // Get the position of the current token
+        let expr = match &self.current_token {
+            Token::Num(n) => { let val = *n; self.next()?; Expr::Number(val) } // Parse number literal
+            Token::Float(x) => { let val = *x; self.next()?; Expr::Float(val) } // Parse float literal
+            Token::True => { self.next()?; Expr::Boolean(true) } // Parse boolean true
+            Token::False => { self.next()?; Expr::Boolean(false) } // Parse boolean false
+            Token::Char(c) => { let ch = *c; self.next()?; Expr::Char(ch) } // Parse character literal
+            Token::StringLiteral(s) => { let val = s.clone(); self.next()?; Expr::StringLiteral(val) } // Parse string literal
+            Token::Sizeof => self.parse_sizeof()?,
+            Token::OpenBrace => self.parse_brace_expression(line, col)?, // `{1, 2}` or `{ let a = 2; a * 3 }`
+            Token::OpenBracket => self.parse_array_literal()?, // ✅ Support array literals like [1, 2, 3]
+            Token::Identifier(_) => self.parse_identifier_expression()?,
+            Token::OpenParen => self.parse_parenthesized()?, // `(expr)` or a cast like `(int)x`
+            token => return Err(ParseError::new(format!("Unexpected token: {:?}", token), line, col)), // Handle unexpected tokens
+        };
+        Ok(expr)
+    }
+
+    /// Parses `sizeof(type)` or `sizeof(variable)`.
+    /// A variable declared with a type other than `int` has the size of that type, as in C;
+    /// any other variable (e.g., `let a = [1, 2, 3];`) is measured by its value at runtime.
+    fn parse_sizeof(&mut self) -> Result<Expr, ParseError> {
+        let (line, col) = self.position();
+        self.next()?;
+        self.expect_token(Token::OpenParen, "Expected '(' after sizeof")?; // Expect '('
+        let is_variable = match &self.current_token {
+            Token::Identifier(name) => !is_type_name(name) && !self.vm.structs.contains_key(name),
+            _ => false,
+        };
+        if is_variable {
+            let expr = self.expression()?;
+            self.expect_token(Token::CloseParen, "Expected ')' after expression")?; // Expect ')'
+            return match expr {
+                Expr::Variable(name) => match self.type_map.get(&name) {
+                    Some(typ) if *typ != Type::Int => sizeof_type(typ.clone(), line, col), // Declared type (e.g., `int[4]`)
+                    _ => Ok(Expr::SizeOfValue(Box::new(Expr::Variable(name)))),
+                },
+                expr => Ok(Expr::SizeOfValue(Box::new(expr))),
+            };
+        }
+        let typ = self.parse_type()?.unwrap_or(Type::Int); // Parse the type after sizeof
+        self.expect_token(Token::CloseParen, "Expected ')' after type")?; // Expect ')'
+        sizeof_type(typ, line, col) // Return SizeOf expression
+    }
+
+    /// Parses an expression starting with `{`: an array literal such as `{1, 2, 3}`,
+    /// or a block expression if the braces hold a statement.
+    fn parse_brace_expression(&mut self, line: usize, col: usize) -> Result<Expr, ParseError> {
+        self.next()?;
+        if self.starts_statement() {
+            return self.block_expression(Vec::new(), line, col); // e.g. `{ let a = 2; a * 3 }`
+        }
+        let mut elements = Vec::new(); // Initialize a vector for array elements
+        while self.current_token != Token::CloseBrace { // Parse array elements until we encounter a closing brace
+            let (element_line, _) = self.position();
+            let element = self.expression()?;
+            if elements.is_empty() && self.current_token == Token::Semicolon { // `{ f(); x }` is a block expression
+                self.next()?;
+                let first = Stmt::Located { line: element_line, stmt: Box::new(Stmt::ExprStmt(element)) };
+                return self.block_expression(vec![first], line, col);
             }
-    
-            Token::OpenBrace => {
-                self.next();
-                let mut elements = Vec::new(); // Initialize a vector for array elements
-                while self.current_token != Token::CloseBrace { // Parse array elements until we encounter a closing brace
-                    elements.push(self.expression()); // Parse each element in the array
-                    if self.current_token == Token::Comma {
-                        self.next(); // Consume the comma if present
-                    } else {
-                        break; // Exit loop if no more elements
-                    }
-                }
-                self.expect_token(Token::CloseBrace, "Expected '}' after array literal", line, col); // Expect closing brace
-                Expr::ArrayLiteral(elements) // Return an ArrayLiteral expression
+            elements.push(element); // Add each element in the array
+            if self.current_token == Token::Comma {
+                self.next()?; // Consume the comma if present
+            } else {
+                break; // Exit loop if no more elements
             }
-    
-            // ✅ Support array literals like [1, 2, 3]
-            Token::OpenBracket => {
-                self.next();
-                let mut elements = Vec::new(); // Initialize a vector for array elements
-                while self.current_token != Token::CloseBracket { // Parse array elements until we encounter a closing bracket
-                    elements.push(self.expression()); // Parse each element in the array
-                    if self.current_token == Token::Comma {
-                        self.next(); // Consume the comma if present
-                    } else {
-                        break; // Exit loop if no more elements
-                    }
-                }
-                self.expect_token(Token::CloseBracket, "Expected ']' after array literal", line, col); // Expect closing bracket
-                Expr::ArrayLiteral(elements) // Return an ArrayLiteral expression
+        }
+        self.expect_token(Token::CloseBrace, "Expected '}' after array literal")?; // Expect closing brace
+        Ok(Expr::ArrayLiteral(elements)) // Return an ArrayLiteral expression
+    }
+
+    /// Parses an array literal in brackets (e.g., `[1, 2, 3]`).
+    fn parse_array_literal(&mut self) -> Result<Expr, ParseError> {
+        self.next()?;
+        let mut elements = Vec::new(); // Initialize a vector for array elements
+        while self.current_token != Token::CloseBracket { // Parse array elements until we encounter a closing bracket
+            elements.push(self.expression()?); // Parse each element in the array
+            if self.current_token == Token::Comma {
+                self.next()?; // Consume the comma if present
+            } else {
+                break; // Exit loop if no more elements
             }
-    
-            Token::Identifier(name) => {
-                let id = name.clone(); // Parse the identifier
-                self.next();
-    
-                if self.current_token == Token::OpenParen { // If the next token is '(', it’s a function call
-                    self.next();
-                    let mut args = Vec::new(); // Initialize a vector for function arguments
-                    while self.current_token != Token::CloseParen { // Parse function arguments
-                        args.push(self.expression()); // Parse each argument
-                        if self.current_token == Token::Comma {
-                            self.next(); // Consume the comma if present
-                        }
-                    }
-                    self.expect_token(Token::CloseParen, "Expected ')' after arguments", line, col); // Expect closing parenthesis
-                    Expr::FunctionCall { name: id, args } // Return a FunctionCall expression
-                }
-                else if self.current_token == Token::OpenBracket { // If the next token is '[', it’s an array index
-                    self.next();
-                    let index_expr = self.expression(); // Parse the index expression
-                    self.expect_token(Token::CloseBracket, "Expected ']' after array index", line, col); // Expect closing bracket
-                    Expr::ArrayIndex(Box::new(Expr::Variable(id)), Box::new(index_expr)) // Return an ArrayIndex expression
-                }
-                else {
-                    Expr::Variable(id) // Return a Variable expression
-                }
+        }
+        self.expect_token(Token::CloseBracket, "Expected ']' after array literal")?; // Expect closing bracket
+        Ok(Expr::ArrayLiteral(elements)) // Return an ArrayLiteral expression
+    }
+
+    /// Parses an identifier as a variable. Calls, indexing and field access on it are
+    /// handled by `parse_postfix`.
+    fn parse_identifier_expression(&mut self) -> Result<Expr, ParseError> {
+        let id = match &self.current_token {
+            Token::Identifier(name) => name.clone(), // Parse the identifier
+            _ => unreachable!("parse_identifier_expression called on a non-identifier"),
+        };
+        self.next()?;
+        if self.current_token == Token::OpenBrace && self.vm.structs.contains_key(&id) {
+            return self.parse_struct_literal(id); // e.g. `Point { x: 1, y: 2 }`
+        }
+        Ok(Expr::Variable(id)) // Return a Variable expression
+    }
+
+    /// Parses the braces of a struct literal (e.g., `{ x: 1, y: 2 }` after `Point`).
+    /// Each field may be given at most once, and must be a field of the struct.
+    fn parse_struct_literal(&mut self, name: String) -> Result<Expr, ParseError> {
+        let declared = match self.vm.structs.get(&name) {
+            Some(Type::Struct(_, fields)) => fields.iter().map(|(field, _)| field.clone()).collect::<Vec<_>>(),
+            _ => unreachable!("parse_struct_literal called for a non-struct name"),
+        };
+        self.next()?;
+        let mut fields: Vec<(String, Expr)> = Vec::new();
+        while self.current_token != Token::CloseBrace {
+            let (line, col) = self.position();
+            let field = self.expect_identifier("Expected field name in struct literal")?;
+            if !declared.contains(&field) {
+                return Err(ParseError::new(format!("Struct '{}' has no field '{}'", name, field), line, col));
             }
-    
-            Token::OpenParen => {
-                self.next();
-                let is_type = match &self.current_token {
-                    Token::Identifier(tn) => matches!(tn.as_str(), "int" | "char" | "bool" | "str" | "void"), // Check if it’s a type
-                    Token::Mul => true, // Handle pointer types
-                    _ => false,
-                };
-                if is_type {
-                    let typ = self.parse_type().unwrap(); // Parse type inside parentheses
-                    self.expect_token(Token::CloseParen, "Expected ')' after type", line, col); // Expect closing parenthesis
-                    let expr = self.parse_unary(); // Parse the unary expression
-                    Expr::Cast(typ, Box::new(expr)) // Return a Cast expression
-                } else {
-                    let expr = self.expression(); // Parse the regular expression
-                    self.expect_token(Token::CloseParen, "Expected ')' after expression", line, col); // Expect closing parenthesis
-                    expr // Return the parsed expression
-                }
+            if fields.iter().any(|(f, _)| *f == field) {
+                return Err(ParseError::new(format!("Field '{}' given more than once in struct literal", field), line, col));
+            }
+            self.expect_token(Token::Colon, "Expected ':' after field name")?;
+            fields.push((field, self.expression()?));
+            if self.current_token != Token::Comma {
+                break;
+            }
+            self.next()?;
+        }
+        self.expect_token(Token::CloseBrace, "Expected '}' after struct literal")?;
+        Ok(Expr::StructLiteral { name, fields })
+    }
+
+    /// Parses the parenthesized argument list of a call (e.g., `(1, x + 2)`).
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        self.next()?;
+        let mut args = Vec::new(); // Initialize a vector for function arguments
+        while self.current_token != Token::CloseParen { // Parse function arguments
+            args.push(self.expression()?); // Parse each argument
+            if self.current_token == Token::Comma {
+                self.next()?; // Consume the comma if present
             }
-    
-            _ => panic!("Unexpected token at line {}, column {}: {:?}", line, col, self.current_token), // Handle unexpected tokens
+        }
+        self.expect_token(Token::CloseParen, "Expected ')' after arguments")?; // Expect closing parenthesis
+        Ok(args)
+    }
+
+    /// Parses an expression starting with `(`: a parenthesized expression or a cast.
+    fn parse_parenthesized(&mut self) -> Result<Expr, ParseError> {
+        self.next()?;
+        let is_type = match &self.current_token {
+            Token::Identifier(tn) => is_type_name(tn), // Check if it’s a type
+            Token::Mul | Token::Pow => self.pointer_type_follows()?, // `(*int)p`, but not `(*p)`
+            _ => false,
+        };
+        if is_type {
+            let typ = self.parse_type()?.unwrap(); // Parse type inside parentheses
+            self.expect_token(Token::CloseParen, "Expected ')' after type")?; // Expect closing parenthesis
+            let expr = self.parse_unary()?; // Parse the unary expression
+            Ok(Expr::Cast(typ, Box::new(expr))) // Return a Cast expression
+        } else {
+            let expr = self.expression()?; // Parse the regular expression
+            self.expect_token(Token::CloseParen, "Expected ')' after expression")?; // Expect closing parenthesis
+            Ok(expr) // Return the parsed expression
         }
     }
     
+    /// Returns whether the `*` or `**` at the current token begins a pointer type, as in the cast
+    /// `(*int)p`, rather than a dereference such as `(*p) + 1`: the stars must be followed by a type.
+    fn pointer_type_follows(&mut self) -> Result<bool, ParseError> {
+        let mut ahead = 1;
+        loop {
+            match self.peek(ahead)?.clone() {
+                Token::Mul | Token::Pow => ahead += 1,
+                Token::Identifier(name) => return Ok(is_type_name(&name) || self.vm.structs.contains_key(&name)),
+                token => return Ok(token == Token::Struct),
+            }
+        }
+    }
+
+    /// Parses the rest of a block expression, after its `{` and any statements already read.
+    /// Statements run in order and the final expression, written without a semicolon, is the value.
+    fn block_expression(&mut self, mut stmts: Vec<Stmt>, line: usize, col: usize) -> Result<Expr, ParseError> {
+        loop {
+            if self.starts_statement() {
+                stmts.push(self.located_statement()?);
+                continue;
+            }
+            if self.current_token == Token::CloseBrace {
+                return Err(ParseError::new("Expected an expression at the end of block expression", line, col));
+            }
+            let (expr_line, _) = self.position();
+            let expr = self.expression()?;
+            if self.current_token == Token::Semicolon {
+                self.next()?;
+                stmts.push(Stmt::Located { line: expr_line, stmt: Box::new(Stmt::ExprStmt(expr)) });
+            } else {
+                self.expect_token(Token::CloseBrace, "Expected ';' or '}' in block expression")?;
+                return Ok(Expr::Block { stmts, value: Box::new(expr) });
+            }
+        }
+    }
+
+    /// Returns whether the current token can only begin a statement, not an expression.
+    fn starts_statement(&self) -> bool {
+        match &self.current_token {
+            Token::Let | Token::Return | Token::Print | Token::Println | Token::If | Token::While | Token::Do | Token::For | Token::Const | Token::Try | Token::Enum | Token::Struct
+                | Token::Switch => true,
+            Token::Identifier(name) => is_type_name(name), // Typed declarations
+            _ => false,
+        }
+    }
+
     /// Parses a type (e.g., `int`, `char`, `void`).
-    fn parse_type(&mut self) -> Option<Type> {
+    fn parse_type(&mut self) -> Result<Option<Type>, ParseError> {
+        let (line, col) = self.position();
         let mut base = match self.current_token {
             Token::Identifier(ref name) => match name.as_str() {
-                "int" => { self.next(); Type::Int } // Parse int type
-                "char" => { self.next(); Type::Char } // Parse char type
-                "bool" => { self.next(); Type::Char } // Parse bool type (treated as char for now)
-                "str" => { self.next(); Type::Pointer(Box::new(Type::Char)) } // Parse string type (pointer to char)
-                "void" => { self.next(); Type::Void } // Parse void type
-                _ => panic!("Unknown type '{}'", name), // Handle unknown types
+                "int" => { self.next()?; Type::Int } // Parse int type
+                "char" => { self.next()?; Type::Char } // Parse char type
+                "float" => { self.next()?; Type::Float } // Parse float type
+                "bool" => { self.next()?; Type::Char } // Parse bool type (treated as char for now)
+                "str" => { self.next()?; Type::Pointer(Box::new(Type::Char)) } // Parse string type (pointer to char)
+                "void" => { self.next()?; Type::Void } // Parse void type
+                _ => match self.vm.structs.get(name) {
+                    Some(t) => { let t = t.clone(); self.next()?; t } // Parse a declared struct type by its name
+                    None => return Err(ParseError::new(format!("Unknown type '{}'", name), line, col)), // Handle unknown types
+                },
             },
+            Token::Struct => {
+                self.next()?;
+                let name = self.expect_identifier("Expected name after 'struct'")?;
+                match self.vm.structs.get(&name) {
+                    Some(t) => t.clone(), // Parse `struct Name`
+                    None => return Err(ParseError::new(format!("Unknown struct '{}'", name), line, col)),
+                }
+            }
             Token::Mul => {
-                self.next();
-                return self.parse_type().map(|t| Type::Pointer(Box::new(t))); // Handle pointer type
+                self.next()?;
+                return Ok(self.parse_type()?.map(|t| Type::Pointer(Box::new(t)))); // Handle pointer type
+            }
+            Token::Pow => {
+                self.next()?;
+                return Ok(self.parse_type()?.map(|t| Type::Pointer(Box::new(Type::Pointer(Box::new(t)))))); // e.g. `**int`
             }
-            _ => return None, // If no type is found, return None
+            _ => return Ok(None), // If no type is found, return None
         };
     
+        let mut sizes = Vec::new();
         while self.current_token == Token::OpenBracket { // Handle array types (e.g., `int[]`)
-            self.next();
-            if let Token::Num(n) = self.current_token {
-                self.next();
-                self.expect_token(Token::CloseBracket, "Expected ']' after array size", 0, 0); // Expect closing bracket
-                base = Type::Array(Box::new(base), n as usize); // Build array type
-            } else {
-                panic!("Expected array size inside brackets"); // Error if no array size is specified
-            }
+            self.next()?;
+            sizes.push(self.parse_array_size()?); // Parse the array size (number or enum constant)
+            self.expect_token(Token::CloseBracket, "Expected ']' after array size")?; // Expect closing bracket
+        }
+        for n in sizes.into_iter().rev() { // `int[2][3]` is 2 arrays of `int[3]`, so the last size is innermost
+            base = Type::Array(Box::new(base), n); // Build array type
         }
     
-        Some(base) // Return the parsed type
+        Ok(Some(base)) // Return the parsed type
+    }
+
+    /// Parses the size inside an array type's brackets.
+    /// The size may be any constant expression: a literal, an enum constant declared earlier
+    /// in the program, or arithmetic on literals and `sizeof` (e.g., `char[sizeof(int) * 2]`).
+    fn parse_array_size(&mut self) -> Result<usize, ParseError> {
+        let (line, col) = self.position();
+        if self.current_token == Token::CloseBracket {
+            return Err(ParseError::new("Expected array size inside brackets", line, col)); // Error if no array size is specified
+        }
+        let size = match fold_expr(self.expression()?, &self.vm.constants) {
+            Expr::Number(n) => n, // Literal or folded size (e.g., `int[3]`, `int[SIZE]` or `char[sizeof(int)]`)
+            Expr::Variable(name) => return Err(ParseError::new(format!("Unknown constant '{}' in array size", name), line, col)),
+            _ => return Err(ParseError::new("Array size must be a constant expression", line, col)),
+        };
+        if size < 0 {
+            return Err(ParseError::new("Array size must not be negative", line, col));
+        }
+        Ok(size as usize)
     }
     
 
      /// Parses a block of statements (enclosed in `{}`).
-     fn block(&mut self) -> Stmt {
-        self.expect_token(Token::OpenBrace, "Expected '{' to start block", 0, 0); // Expect opening brace
+     fn block(&mut self) -> Result<Stmt, ParseError> {
+        self.expect_token(Token::OpenBrace, "Expected '{' to start block")?; // Expect opening brace
         let mut stmts = Vec::new(); // Initialize an empty vector for statements
         while self.current_token != Token::CloseBrace { // Parse statements until we encounter closing brace
-            let stmt = self.statement(); // Parse each statement
+            let stmt = self.located_statement()?; // Parse each statement
             stmts.push(stmt); // Add the statement to the list
         }
-        self.next(); // Consume closing brace
-        Stmt::Block(stmts) // Return the block of statements
+        self.next()?; // Consume closing brace
+        Ok(Stmt::Block(stmts)) // Return the block of statements
     }
 
-    /// Expects a specific token and advances the parser, or panics with an error message if the token doesn't match.
-    fn expect_token(&mut self, expected: Token, msg: &str, line: usize, col: usize) {
+    /// Expects a specific token and advances the parser, or returns an error with the message if the token doesn't match.
+    fn expect_token(&mut self, expected: Token, msg: &str) -> Result<(), ParseError> {
         if self.current_token != expected {
-            panic!("{} at line {}, column {}", msg, line, col); // If the token doesn't match, panic
+            let (line, col) = self.position();
+            return Err(ParseError::new(msg, line, col)); // The token doesn't match, reported where it starts
         }
-        self.next(); // Consume the expected token
+        self.next()?; // Consume the expected token
+        Ok(())
+    }
+
+    /// Checks that the operand of `++` or `--` is something that can be assigned to, i.e. a variable,
+    /// a dereference (`(*p)++`), an array element (`a[i]++`) or a struct field (`p.x++`).
+    /// `line` and `col` are the position of the operator.
+    fn check_assignable(&self, expr: &Expr, op: &str, line: usize, col: usize) -> Result<(), ParseError> {
+        let kind = match expr {
+            Expr::Variable(_) | Expr::Deref(_) | Expr::ArrayIndex(..) | Expr::Member(..) => return Ok(()),
+            Expr::Number(_) | Expr::Char(_) | Expr::Boolean(_) | Expr::StringLiteral(_) => "literal",
+            Expr::FunctionCall { .. } => "function call",
+            _ => "expression",
+        };
+        Err(ParseError::new(format!("Operand of '{}' must be assignable (got {})", op, kind), line, col))
     }
 
-    /// Expects an identifier and advances the parser, or panics with an error message if the token isn't an identifier.
-    fn expect_identifier(&mut self, msg: &str, line: usize, col: usize) -> String {
+    /// Expects an identifier and advances the parser, or returns an error with the message if the token isn't an identifier.
+    fn expect_identifier(&mut self, msg: &str) -> Result<String, ParseError> {
+        let (line, col) = self.position();
         if let Token::Identifier(n) = &self.current_token {
             let name = n.clone();
-            self.next();
-            name // Return the identifier
+            self.next()?;
+            Ok(name) // Return the identifier
+        } else if let Some(keyword) = self.current_token.keyword() {
+            Err(ParseError::new(format!("{}: '{}' is a reserved keyword", msg, keyword), line, col)) // Keywords can't name things
         } else {
-            panic!("{} at line {}, column {}", msg, line, col); // Error if the token is not an identifier
+            Err(ParseError::new(msg, line, col)) // Error if the token is not an identifier
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,181 @@
+/// An interactive read-eval-print loop (REPL).
+/// Each line of input is parsed and executed in the same VM, so variables, functions and
+/// enums defined on one line can be used on the next. The value of every top-level
+/// expression statement on a line is printed.
+///
+/// An error on a line is reported and the session continues. Statements that ran before
+/// the error (including earlier statements on the same line) keep their effects, so a
+/// line that fails part-way may still have changed some variables.
+///
+/// Lines starting with `.` are commands to the REPL itself rather than C4 code
+/// (e.g., `.vars`); `.help` lists them.
+use crate::diagnostic::Diagnostic;
+use crate::fold;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::resolver;
+use crate::vm::{Value, Vm, VmConfig};
+use std::io::{self, BufRead, Write};
+
+/// A REPL session, holding the VM state shared by all lines.
+pub struct Repl {
+    vm: Vm,
+}
+
+impl Repl {
+    /// Creates a new session with an empty VM.
+    pub fn new() -> Self {
+        let config = VmConfig { collect_results: true, ..VmConfig::default() }; // Print the value of each expression statement
+        Self { vm: Vm::with_config(config) }
+    }
+
+    /// Parses and executes one line of input.
+    ///
+    /// # Parameters
+    /// - `line`: The source code to run.
+    ///
+    /// # Returns
+    /// The values of the line's top-level expression statements, or the error that stopped it.
+    pub fn eval_line(&mut self, line: &str) -> Result<Vec<Value>, Diagnostic> {
+        let outcome = self.run_line(line);
+        let values = self.vm.take_results();
+        self.vm.should_return = false; // A top-level `return` only ends the current line
+        match outcome {
+            Ok(()) => Ok(values),
+            Err(diag) => {
+                self.vm.truncate_scopes(1); // Drop the scopes of any calls the error interrupted
+                Err(diag)
+            }
+        }
+    }
+
+    /// Parses and executes the statements of `line` in the session's VM, stopping at the first error.
+    fn run_line(&mut self, line: &str) -> Result<(), Diagnostic> {
+        let statements = Parser::new(Lexer::new(line), &mut self.vm).parse()?;
+        let statements = resolver::resolve_program(fold::fold_program(statements, &self.vm.foldable_constants()));
+        for stmt in statements {
+            self.vm.execute(&stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a meta-command (a line starting with `.`, such as `.vars`).
+    ///
+    /// # Parameters
+    /// - `command`: The command, including its leading `.`.
+    ///
+    /// # Returns
+    /// The text to show for the command, one line per entry.
+    pub fn meta_command(&mut self, command: &str) -> Vec<String> {
+        match command {
+            ".help" => vec![
+                ".help   Show this message".to_string(),
+                ".vars   List the variables and their values".to_string(),
+                ".funcs  List the defined functions".to_string(),
+                ".reset  Forget all variables, functions, constants and structs".to_string(),
+            ],
+            ".vars" => {
+                let mut vars = self.vm.variables[0].iter()
+                    .filter_map(|(name, slot)| Some(format!("{} = {}", name, self.vm.slot_value(slot)?)))
+                    .collect::<Vec<_>>();
+                vars.sort();
+                vars
+            }
+            ".funcs" => {
+                let mut functions = self.vm.functions.iter().collect::<Vec<_>>();
+                functions.sort_by_key(|(key, _)| *key); // By name, then parameter count
+                functions.into_iter().map(|(_, function)| function.to_string()).collect()
+            }
+            ".reset" => {
+                self.vm.reset();
+                vec![]
+            }
+            _ => vec![format!("error: Unknown command '{}' (try .help)", command)],
+        }
+    }
+
+    /// Runs the session, reading lines from `input` until it ends and writing results to `output`.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim_start().starts_with('.') { // Commands are handled before parsing
+                for text in self.meta_command(line.trim()) {
+                    writeln!(output, "{}", text)?;
+                }
+                write!(output, "> ")?;
+                output.flush()?;
+                continue;
+            }
+            let outcome = self.eval_line(&line);
+            self.vm.end_output_line()?; // Start the results and the prompt on a line of their own
+            match outcome {
+                Ok(values) => {
+                    for value in values {
+                        writeln!(output, "{}", value)?;
+                    }
+                }
+                Err(diag) => writeln!(output, "error: {}", diag)?,
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that definitions persist from one line to the next.
+    #[test]
+    fn test_state_persists_between_lines() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval_line("let x = 20; int twice(n) { return n * 2; }"), Ok(vec![]));
+        assert_eq!(repl.eval_line("twice(x) + 2;"), Ok(vec![Value::Int(42)]));
+    }
+
+    /// Tests that an error on one line doesn't end the session.
+    #[test]
+    fn test_continues_after_runtime_error() {
+        let mut repl = Repl::new();
+        repl.eval_line("let x = 1; int boom(n) { return n / 0; }").unwrap();
+        let err = repl.eval_line("x = 5; boom(x);").unwrap_err();
+        assert_eq!(err.message, "Division by zero");
+        assert_eq!(repl.eval_line("x + 1;"), Ok(vec![Value::Int(6)])); // `x = 5` ran before the error
+    }
+
+    /// Tests driving a whole session through `run`.
+    #[test]
+    fn test_run_session() {
+        let mut output = Vec::new();
+        Repl::new().run("let y = 2;\ny * 3;\nnope;\ny;\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "> > 6\n> error: Variable 'nope' not found\n> 2\n> \n"
+        );
+    }
+
+    /// Tests the `.vars`, `.funcs` and `.reset` commands.
+    #[test]
+    fn test_meta_commands() {
+        let mut repl = Repl::new();
+        repl.eval_line("let y = [1, 2]; let x = 20; int twice(n) { return n * 2; } int total(base, ...) { return base; }").unwrap();
+        assert_eq!(repl.meta_command(".vars"), vec!["x = 20", "y = [1, 2]"]);
+        assert_eq!(repl.meta_command(".funcs"), vec!["int total(base, ...)", "int twice(n)"]);
+        assert_eq!(repl.meta_command(".reset"), Vec::<String>::new());
+        assert_eq!(repl.meta_command(".vars"), Vec::<String>::new());
+        assert_eq!(repl.eval_line("twice(2);").unwrap_err().message, "Function 'twice' not found");
+        assert_eq!(repl.meta_command(".nope"), vec!["error: Unknown command '.nope' (try .help)"]);
+    }
+
+    /// Tests that commands are intercepted by `run` before the line is parsed.
+    #[test]
+    fn test_run_vars_command() {
+        let mut output = Vec::new();
+        Repl::new().run("let x = 5;\n.vars\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "> > x = 5\n> \n");
+    }
+}
@@ -0,0 +1,410 @@
+/// Variable resolution for the abstract syntax tree.
+/// This pass runs after constant folding and replaces each use of a local variable whose place
+/// is known at compile time with an `Expr::Local`, so the VM can find it by index instead of
+/// looking its name up in every scope.
+///
+/// The VM pushes a scope for every block, `for` loop, `catch` handler and function call, and
+/// keeps a scope's variables in the order they were declared. A use is only located when that
+/// order is certain. Globals, a caller's variables used by a function, and variables that may or
+/// may not have been declared by then (e.g., after `if (c) let x = 1;`) are still looked up by name.
+use crate::ast::{BinOp, Expr, Param, Stmt};
+use std::collections::HashSet;
+
+/// What the resolver knows about the variables of one scope.
+#[derive(Default)]
+struct Scope {
+    /// The variables certainly declared so far, by slot.
+    slots: Vec<String>,
+    /// Variables that may have been declared, at slots that aren't known.
+    unknown: HashSet<String>,
+    /// Whether the slots of variables declared from now on are known. The global scope's never
+    /// are, since earlier programs (or REPL lines) may have declared globals in it.
+    numbered: bool,
+}
+
+impl Scope {
+    /// A new scope, whose variables will all be located.
+    fn numbered() -> Self {
+        Scope { numbered: true, ..Scope::default() }
+    }
+
+    /// Records a declaration of `name` that certainly runs.
+    fn declare(&mut self, name: &str) {
+        if self.slots.iter().any(|n| n == name) {
+            return; // Declaring a variable again in the same scope replaces it in its slot
+        }
+        if self.numbered && !self.unknown.contains(name) {
+            self.slots.push(name.to_string());
+        } else {
+            self.may_declare(name);
+        }
+    }
+
+    /// Records a declaration of `name` that may or may not run, after which the scope's later
+    /// variables can't be located either.
+    fn may_declare(&mut self, name: &str) {
+        if !self.slots.iter().any(|n| n == name) {
+            self.unknown.insert(name.to_string());
+            self.numbered = false;
+        }
+    }
+}
+
+/// Locates the local variables in every statement of a program.
+///
+/// # Parameters
+/// - `stmts`: The program, after constant folding.
+///
+/// # Returns
+/// The program with the variable uses that can be located replaced by `Expr::Local`.
+pub fn resolve_program(stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut resolver = Resolver { scopes: vec![Scope::default()] }; // The global scope
+    resolver.resolve_stmts(stmts)
+}
+
+/// The scopes enclosing the code being resolved, innermost last. In a function body, the
+/// outermost one is the function's own scope, since the caller's scopes aren't known.
+struct Resolver {
+    scopes: Vec<Scope>,
+}
+
+impl Resolver {
+    /// Finds the variable a use of `name` refers to, as scopes out from the innermost one and slot.
+    fn lookup(&self, name: &str) -> Option<(usize, usize)> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(slot) = scope.slots.iter().position(|n| n == name) {
+                return Some((depth, slot));
+            }
+            if scope.unknown.contains(name) {
+                return None; // It may or may not be this scope's variable
+            }
+        }
+        None
+    }
+
+    // Returns a use of the variable `name`, located if possible.
+    fn locate(&self, name: String) -> Expr {
+        match self.lookup(&name) {
+            Some((depth, slot)) => Expr::Local { name, depth, slot },
+            None => Expr::Variable(name),
+        }
+    }
+
+    fn current(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    // Resolves code that runs in a new scope.
+    fn in_scope<T>(&mut self, scope: Scope, resolve: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(scope);
+        let resolved = resolve(self);
+        self.scopes.pop();
+        resolved
+    }
+
+    fn resolve_stmts(&mut self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        stmts.into_iter().map(|stmt| self.resolve_stmt(stmt)).collect()
+    }
+
+    // Resolves a statement that may not run, or may stop part-way, in the current scope. The
+    // variables it declares there are only possibly declared after it.
+    fn resolve_branch(&mut self, stmt: Stmt) -> Stmt {
+        let declared = self.current().slots.len();
+        let stmt = self.resolve_stmt(stmt);
+        let scope = self.current();
+        for name in scope.slots.split_off(declared) {
+            scope.may_declare(&name);
+        }
+        stmt
+    }
+
+    // Prepares to resolve a loop whose body runs in the current scope. A variable the body
+    // declares there may shadow an outer one from the second iteration on, even in code that
+    // comes before the declaration, so none of them is located.
+    fn enter_loop(&mut self, body: &Stmt) {
+        let mut names = Vec::new();
+        collect_declared(body, &mut names);
+        for name in names {
+            self.current().may_declare(&name);
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: Stmt) -> Stmt {
+        match stmt {
+            Stmt::Return(expr) => Stmt::Return(self.resolve_expr(expr)),
+            Stmt::Print { value, newline } => Stmt::Print { value: self.resolve_expr(value), newline },
+            Stmt::ExprStmt(expr) => Stmt::ExprStmt(self.resolve_expr(expr)),
+            Stmt::Block(stmts) => Stmt::Block(self.in_scope(Scope::numbered(), |r| r.resolve_stmts(stmts))),
+            Stmt::LetGroup(stmts) => Stmt::LetGroup(self.resolve_stmts(stmts)),
+            Stmt::Let { name, value, var_type } => {
+                let value = self.resolve_expr(value); // Before the variable exists, as in the VM
+                self.current().declare(&name);
+                Stmt::Let { name, value, var_type }
+            }
+            Stmt::Const { name, value } => Stmt::Const { name, value: self.resolve_expr(value) }, // Always a global
+            Stmt::LetArray { names, rest, value } => {
+                let value = self.resolve_expr(value);
+                for name in names.iter().chain(&rest) {
+                    self.current().declare(name);
+                }
+                Stmt::LetArray { names, rest, value }
+            }
+            Stmt::Assign { name, value } => {
+                let value = self.resolve_expr(value);
+                self.assign(&name);
+                Stmt::Assign { name, value }
+            }
+            Stmt::If { condition, then_branch, else_branch } => Stmt::If {
+                condition: self.resolve_expr(condition),
+                then_branch: Box::new(self.resolve_branch(*then_branch)),
+                else_branch: else_branch.map(|s| Box::new(self.resolve_branch(*s))),
+            },
+            Stmt::While { condition, body } => {
+                self.enter_loop(&body);
+                Stmt::While {
+                    condition: self.resolve_expr(condition),
+                    body: Box::new(self.resolve_branch(*body)),
+                }
+            }
+            Stmt::DoWhile { body, condition } => {
+                self.enter_loop(&body);
+                Stmt::DoWhile {
+                    body: Box::new(self.resolve_branch(*body)),
+                    condition: self.resolve_expr(condition),
+                }
+            }
+            Stmt::For { init, condition, update, body } => self.in_scope(Scope::numbered(), |r| {
+                let init = init.map(|s| Box::new(r.resolve_stmt(*s)));
+                r.enter_loop(&body);
+                Stmt::For {
+                    init,
+                    condition: condition.map(|e| r.resolve_expr(e)),
+                    body: Box::new(r.resolve_branch(*body)),
+                    update: update.map(|e| r.resolve_expr(e)),
+                }
+            }),
+            Stmt::Switch { scrutinee, cases, default } => Stmt::Switch {
+                scrutinee: self.resolve_expr(scrutinee),
+                cases: cases.into_iter().map(|(value, body)| {
+                    let value = self.resolve_expr(value);
+                    (value, self.in_scope(Scope::numbered(), |r| r.resolve_stmts(body)))
+                }).collect(),
+                default: default.map(|body| self.in_scope(Scope::numbered(), |r| r.resolve_stmts(body))),
+            },
+            Stmt::Try { body, name, handler } => {
+                let body = Box::new(self.resolve_branch(*body));
+                let mut scope = Scope::numbered();
+                scope.declare(&name); // The handler's scope holds the error message
+                let handler = Box::new(self.in_scope(scope, |r| r.resolve_stmt(*handler)));
+                Stmt::Try { body, name, handler }
+            }
+            Stmt::Located { line, stmt } => Stmt::Located { line, stmt: Box::new(self.resolve_stmt(*stmt)) },
+            Stmt::Function { name, params, variadic, body, return_type } => {
+                // The body runs in a scope of its own on top of the caller's, whatever encloses the definition
+                let outer = std::mem::replace(&mut self.scopes, vec![Scope::numbered()]);
+                let params = params.into_iter().map(|param| self.resolve_param(param)).collect();
+                if variadic {
+                    self.current().declare("args");
+                }
+                let body = Box::new(self.resolve_stmt(*body));
+                self.scopes = outer;
+                Stmt::Function { name, params, variadic, body, return_type }
+            }
+        }
+    }
+
+    // Declares a parameter in the function's scope. Its default is evaluated just before,
+    // once the parameters before it are declared.
+    fn resolve_param(&mut self, param: Param) -> Param {
+        let default = param.default.map(|e| self.resolve_expr(e));
+        self.current().declare(&param.name);
+        Param { default, ..param }
+    }
+
+    // Records an assignment to `name`, which declares it in the current scope if no scope has it.
+    fn assign(&mut self, name: &str) {
+        if self.lookup(name).is_none() {
+            self.current().may_declare(name); // It may still be a global or a caller's variable
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Variable(name) => self.locate(name),
+            Expr::BinaryOp { op: BinOp::Assign, left, right } => {
+                let (left, right) = match *left {
+                    Expr::Variable(name) => {
+                        let right = self.resolve_expr(*right); // The value is evaluated before a variable is assigned
+                        self.assign(&name);
+                        (self.locate(name), right)
+                    }
+                    left => (self.resolve_expr(left), self.resolve_expr(*right)),
+                };
+                Expr::BinaryOp { op: BinOp::Assign, left: Box::new(left), right: Box::new(right) }
+            }
+            Expr::BinaryOp { op, left, right } => {
+                // Walk a chain like `a + b - c` down its left operands, so long chains don't recurse per operator
+                let mut rights = vec![(op, *right)];
+                let mut left = *left;
+                loop {
+                    match left {
+                        Expr::BinaryOp { op, left: inner, right } if op != BinOp::Assign => {
+                            rights.push((op, *right));
+                            left = *inner;
+                        }
+                        other => {
+                            left = other;
+                            break;
+                        }
+                    }
+                }
+                let mut resolved = self.resolve_expr(left);
+                for (op, right) in rights.into_iter().rev() {
+                    let right = self.resolve_expr(right);
+                    resolved = Expr::BinaryOp { op, left: Box::new(resolved), right: Box::new(right) };
+                }
+                resolved
+            }
+            Expr::UnaryOp { op, expr } => Expr::UnaryOp { op, expr: Box::new(self.resolve_expr(*expr)) },
+            Expr::Ternary { condition, then_branch, else_branch } => Expr::Ternary {
+                condition: Box::new(self.resolve_expr(*condition)),
+                then_branch: Box::new(self.resolve_expr(*then_branch)),
+                else_branch: Box::new(self.resolve_expr(*else_branch)),
+            },
+            Expr::ArrayLiteral(elements) => Expr::ArrayLiteral(self.resolve_exprs(elements)),
+            Expr::ArrayIndex(array, index) => {
+                let array = self.resolve_expr(*array);
+                Expr::ArrayIndex(Box::new(array), Box::new(self.resolve_expr(*index)))
+            }
+            Expr::StructLiteral { name, fields } => Expr::StructLiteral {
+                name,
+                fields: fields.into_iter().map(|(f, e)| (f, self.resolve_expr(e))).collect(),
+            },
+            Expr::Slice { target, start, end } => Expr::Slice {
+                target: Box::new(self.resolve_expr(*target)),
+                start: Box::new(self.resolve_expr(*start)),
+                end: Box::new(self.resolve_expr(*end)),
+            },
+            Expr::Member(object, field) => Expr::Member(Box::new(self.resolve_expr(*object)), field),
+            Expr::FunctionCall { name, args } => Expr::FunctionCall { name, args: self.resolve_exprs(args) },
+            Expr::Call { callee, args } => {
+                let callee = Box::new(self.resolve_expr(*callee));
+                Expr::Call { callee, args: self.resolve_exprs(args) }
+            }
+            Expr::PreInc(expr) => Expr::PreInc(Box::new(self.resolve_expr(*expr))),
+            Expr::PreDec(expr) => Expr::PreDec(Box::new(self.resolve_expr(*expr))),
+            Expr::PostInc(expr) => Expr::PostInc(Box::new(self.resolve_expr(*expr))),
+            Expr::PostDec(expr) => Expr::PostDec(Box::new(self.resolve_expr(*expr))),
+            Expr::SizeOfValue(expr) => Expr::SizeOfValue(Box::new(self.resolve_expr(*expr))),
+            Expr::Cast(t, expr) => Expr::Cast(t, Box::new(self.resolve_expr(*expr))),
+            Expr::AddressOf(expr) => Expr::AddressOf(Box::new(self.resolve_expr(*expr))),
+            Expr::Deref(expr) => Expr::Deref(Box::new(self.resolve_expr(*expr))),
+            Expr::Block { stmts, value } => self.in_scope(Scope::numbered(), |r| Expr::Block {
+                stmts: r.resolve_stmts(stmts),
+                value: Box::new(r.resolve_expr(*value)),
+            }),
+            other => other, // Literals and uses that are already located
+        }
+    }
+
+    fn resolve_exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|e| self.resolve_expr(e)).collect()
+    }
+}
+
+// Adds the names of the variables a statement declares in the scope it runs in, but not in
+// the blocks it contains, to `names`.
+fn collect_declared(stmt: &Stmt, names: &mut Vec<String>) {
+    match stmt {
+        Stmt::Let { name, .. } => names.push(name.clone()),
+        Stmt::LetArray { names: bound, rest, .. } => names.extend(bound.iter().chain(rest).cloned()),
+        Stmt::LetGroup(stmts) => stmts.iter().for_each(|s| collect_declared(s, names)),
+        Stmt::If { then_branch, else_branch, .. } => {
+            collect_declared(then_branch, names);
+            if let Some(else_branch) = else_branch {
+                collect_declared(else_branch, names);
+            }
+        }
+        Stmt::While { body, .. } | Stmt::DoWhile { body, .. } | Stmt::Try { body, .. } => collect_declared(body, names),
+        Stmt::Located { stmt, .. } => collect_declared(stmt, names),
+        _ => {} // Blocks, `for` loops and `switch` cases have scopes of their own, and constants are globals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::fold_program;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    /// Parses, folds and resolves a program.
+    fn resolve(code: &str) -> Vec<Stmt> {
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(Lexer::new(code), &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        resolve_program(fold_program(stmts, &vm.constants))
+    }
+
+    /// Returns the statements of a function body, or of a block.
+    fn body(stmt: &Stmt) -> &[Stmt] {
+        match stmt.unlocated() {
+            Stmt::Function { body, .. } => self::body(body),
+            Stmt::Block(stmts) => stmts,
+            other => panic!("expected a function or block, got {:?}", other),
+        }
+    }
+
+    /// Tests that parameters and variables declared in blocks are located by scope and slot.
+    #[test]
+    fn test_resolve_locals() {
+        let stmts = resolve("int f(a, b) { let c = a; { let d = 1; return b + c + d; } }");
+        let block = body(&body(&stmts[0])[1]);
+        let expected = [("b", 2, 1), ("c", 1, 0), ("d", 0, 0)];
+        let mut expr = match block[1].unlocated() {
+            Stmt::Return(expr) => expr,
+            other => panic!("expected a return, got {:?}", other),
+        };
+        for (name, depth, slot) in expected.iter().rev() {
+            let (left, right) = match expr {
+                Expr::BinaryOp { left, right, .. } => (&**left, &**right),
+                right => (right, right),
+            };
+            match right {
+                Expr::Local { name: n, depth: d, slot: s } => assert_eq!((n.as_str(), *d, *s), (*name, *depth, *slot)),
+                other => panic!("expected {} to be located, got {:?}", name, other),
+            }
+            expr = left;
+        }
+    }
+
+    /// Tests that globals and variables whose declaration may not have run are left to be looked up by name.
+    #[test]
+    fn test_resolve_leaves_unknown_variables() {
+        let stmts = resolve("let g = 1; int f(a) { if (a) let x = 1; return g + x; }");
+        match body(&stmts[1])[1].unlocated() {
+            Stmt::Return(Expr::BinaryOp { left, right, .. }) => {
+                assert!(matches!(&**left, Expr::Variable(name) if name == "g"));
+                assert!(matches!(&**right, Expr::Variable(name) if name == "x"));
+            }
+            other => panic!("expected a return, got {:?}", other),
+        }
+    }
+
+    /// Tests that a loop body's declarations keep uses in the loop from being located, since
+    /// they may shadow an outer variable from the second iteration on.
+    #[test]
+    fn test_resolve_loop_declarations() {
+        let stmts = resolve("int f(x) { for (let i = 0; x < 3; i++) let x = 5; return x; }");
+        match body(&stmts[0])[0].unlocated() {
+            Stmt::For { condition: Some(Expr::BinaryOp { left, .. }), update: Some(Expr::PostInc(i)), .. } => {
+                assert!(matches!(&**left, Expr::Variable(name) if name == "x"));
+                assert!(matches!(&**i, Expr::Local { depth: 0, slot: 0, .. }));
+            }
+            other => panic!("expected a for loop, got {:?}", other),
+        }
+        // After the loop, `x` is the parameter again, since the loop's scope is gone
+        assert!(matches!(body(&stmts[0])[1].unlocated(), Stmt::Return(Expr::Local { depth: 1, slot: 0, .. })));
+    }
+}
@@ -1,5 +1,29 @@
-use crate::ast::{BinOp, Expr, Stmt, UnOp, Type};
-use std::collections::HashMap;
+use crate::ast::{BinOp, Expr, Stmt, UnOp, Type, Param};
+use crate::builtins::is_mutating_builtin;
+use crate::error::Error;
+use crate::fold;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::resolver;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::{panic, thread};
+
+/// The default `VmConfig::max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// How many nested calls run on one native stack before the next call moves to a new thread.
+/// A call takes about 10 KB of stack in a debug build, and about 25 KB when it is made from
+/// inside nested loops, so this many fit in what is left of an ordinary 2 MB thread stack.
+const CALLS_PER_STACK: usize = 64;
+
+/// The stack size of the threads that deeper calls run on, with room for `CALLS_PER_STACK` calls
+/// and deeply nested expressions inside them.
+const CALL_STACK_SIZE: usize = 8 * 1024 * 1024;
 
 /// Represents a function in the language, including its name, parameters, body, and return type.
 #[derive(Clone)]
@@ -7,37 +31,338 @@ use std::collections::HashMap;
 pub struct Function {
     /// The name of the function.
     pub name: String,
-    /// A list of parameter names for the function.
-    pub params: Vec<String>,
-    /// The body of the function, represented as a statement.
-    pub body: Stmt,
+    /// A list of parameters for the function.
+    pub params: Vec<Param>,
+    /// Whether the function takes extra arguments after its parameters (`...`), collected into `args`.
+    pub variadic: bool,
+    /// The body of the function, represented as a statement. It is shared, so calls don't copy it.
+    pub body: Arc<Stmt>,
     /// The return type of the function, which may be `None` for void functions.
     pub return_type: Option<Type>, // Optional: None for void
 }
 
+impl Function {
+    /// The number of parameters without a default value, which every call must supply.
+    fn required_params(&self) -> usize {
+        self.params.iter().take_while(|p| p.default.is_none()).count()
+    }
+
+    /// Checks whether a call with `count` arguments matches this function's parameters.
+    fn accepts(&self, count: usize) -> bool {
+        count >= self.required_params() && (self.variadic || count <= self.params.len())
+    }
+
+    /// Describes how many arguments the function takes, for error messages (e.g. `1 to 2`).
+    fn arity(&self) -> String {
+        let required = self.required_params();
+        if self.variadic {
+            format!("at least {}", required)
+        } else if required < self.params.len() {
+            format!("{} to {}", required, self.params.len())
+        } else {
+            required.to_string()
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    /// Formats the function's signature (e.g. `int greet(name, times = ...)`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(return_type) = &self.return_type {
+            write!(f, "{} ", return_type)?;
+        }
+        let mut params = self.params.iter().map(|p| {
+            let by_ref = if p.by_ref { "ref " } else { "" };
+            let default = if p.default.is_some() { " = ..." } else { "" };
+            format!("{}{}{}", by_ref, p.name, default)
+        }).collect::<Vec<_>>();
+        if self.variadic {
+            params.push("...".to_string());
+        }
+        write!(f, "{}({})", self.name, params.join(", "))
+    }
+}
+
 /// Represents the different values that can be used at runtime, such as integers, strings, and arrays.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     /// Integer value (e.g., 42)
     Int(i32),
+    /// Boolean value, from `true`, `false`, comparisons and logical operators (e.g., `1 < 2`)
+    Bool(bool),
     /// String value (e.g., "Hello")
     Str(String),
     /// Array value, which contains a vector of `Value`s.
     Array(Vec<Value>),
+    /// Floating-point value (e.g., the result of `fdiv(7, 2)`)
+    Float(f64),
+    /// Reference to a user-defined function by name (e.g., `let f = square;`)
+    Func(String),
+    /// Struct value: the struct's name and its fields in declaration order (e.g., `Point { x: 1, y: 2 }`)
+    Struct(String, Vec<(String, Value)>),
+    /// Pointer to a memory cell, made by `&x` (see `Slot::Cell`)
+    Ptr(usize),
+}
+
+/// Where a variable's value is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Slot {
+    /// In the variable's scope, as it is until its address is taken.
+    Value(Value),
+    /// In the VM's memory: `addr` is the address `&x` gives and `index` the position of the cell
+    /// in `Vm::memory`. `&x` moves `x` into memory, so that reads and writes through pointers
+    /// to it (`*p = 5;`) and through its name see the same value.
+    Cell { addr: usize, index: usize },
+}
+
+/// A block of memory cells in use: one allocated by `malloc`, or the cell of a variable whose
+/// address was taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Block {
+    /// The position of the block's first cell in `Vm::memory`.
+    index: usize,
+    /// The number of cells.
+    size: usize,
+    /// Whether `malloc` allocated the block, so that `free` may release it.
+    heap: bool,
+}
+
+/// The variables of one scope. Their slots are kept in the order the variables were declared,
+/// so that code the resolver has located (`Expr::Local`) can reach them by index; the names
+/// lead to the slots for everything else, such as globals.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    names: HashMap<String, usize>,
+    slots: Vec<Slot>,
+}
+
+impl Scope {
+    /// Returns the slot of the variable `name`, if this scope has one.
+    pub fn get(&self, name: &str) -> Option<&Slot> {
+        self.names.get(name).map(|&i| &self.slots[i])
+    }
+
+    /// Returns the slot of the variable `name` for updating it, if this scope has one.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Slot> {
+        let i = *self.names.get(name)?;
+        Some(&mut self.slots[i])
+    }
+
+    /// Checks whether this scope has a variable named `name`.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.names.contains_key(name)
+    }
+
+    /// Declares a variable, giving it the next slot, or replacing the slot of the
+    /// variable of that name if this scope already has one.
+    pub fn insert(&mut self, name: String, slot: Slot) {
+        match self.names.entry(name) {
+            Entry::Occupied(entry) => self.slots[*entry.get()] = slot,
+            Entry::Vacant(entry) => {
+                entry.insert(self.slots.len());
+                self.slots.push(slot);
+            }
+        }
+    }
+
+    /// Iterates over the variables as names and slots, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Slot)> {
+        self.names.iter().map(move |(name, &i)| (name, &self.slots[i]))
+    }
+}
+
+impl Value {
+    /// Checks whether the value counts as true in a condition or under `!`.
+    /// Zero and empty strings or arrays are false; everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(i) => *i != 0,
+            Value::Bool(b) => *b,
+            Value::Float(x) => *x != 0.0,
+            Value::Str(s) => !s.is_empty(),
+            Value::Array(elements) => !elements.is_empty(),
+            Value::Func(_) | Value::Struct(..) => true,
+            Value::Ptr(addr) => *addr != 0, // Address 0 is the null pointer
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Formats the value the way it would be written in C4 source (e.g., `65`, `"hi"`, `[1, 2]`).
+    /// This is the user-facing form used in error messages; `{:?}` is kept for internal debugging.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Float(x) if x.is_finite() && x.fract() == 0.0 => write!(f, "{:.1}", x), // Keep the `.0` on whole floats
+            Value::Float(x) => write!(f, "{}", x), // NaN and infinities print as `NaN`, `inf` and `-inf`
+            Value::Str(s) => write!(f, "\"{}\"", s),
+            Value::Func(name) => write!(f, "{}", name), // Written as the function's name
+            Value::Array(arr) => {
+                let elements = arr.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Value::Ptr(addr) => write!(f, "0x{:x}", addr), // Written as its address
+            Value::Struct(name, fields) => {
+                let fields = fields.iter().map(|(field, v)| format!("{}: {}", field, v)).collect::<Vec<_>>();
+                write!(f, "{} {{ {} }}", name, fields.join(", "))
+            }
+        }
+    }
+}
+
+/// An error that stops a running program, returned by `Vm::execute` instead of panicking.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// Integer division (or `floor_div`/`ceil_div`) by zero.
+    DivisionByZero,
+    /// Integer `%` by zero.
+    ModuloByZero,
+    /// A variable that is not defined in any enclosing scope (nor a constant or function).
+    UndefinedVariable(String),
+    /// A call to a function that is neither user-defined nor a builtin.
+    UndefinedFunction(String),
+    /// A call with a number of arguments the function doesn't accept.
+    ArgumentCount {
+        /// The name of the function.
+        function: String,
+        /// The accepted number of arguments (e.g. `2` or `1 to 2`).
+        expected: String,
+        /// The number of arguments in the call.
+        got: usize,
+    },
+    /// An array index past the end of the array, or a negative one.
+    IndexOutOfBounds { len: usize, idx: i32 },
+    /// An operation applied to values of the wrong type (e.g. `1 + "a"`), with the details.
+    TypeMismatch(String),
+    /// More nested function calls than `Vm::max_call_depth`, such as from runaway recursion.
+    RecursionLimitExceeded { limit: usize },
+    /// A string or array larger than `Vm::max_value_size`.
+    ValueTooLarge { kind: &'static str, size: usize, limit: usize },
+    /// Any other error, such as an integer overflow or an empty array passed to `min`.
+    Other(String),
+}
+
+impl fmt::Display for RuntimeError {
+    /// Formats the error as the message shown to the user (e.g. `Variable 'x' not found`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeError::ModuloByZero => write!(f, "Modulo by zero"),
+            RuntimeError::UndefinedVariable(name) => write!(f, "Variable '{}' not found", name),
+            RuntimeError::UndefinedFunction(name) => write!(f, "Function '{}' not found", name),
+            RuntimeError::ArgumentCount { function, expected, got } => {
+                let noun = if expected == "1" || expected == "at least 1" { "argument" } else { "arguments" };
+                write!(f, "Function '{}' expected {} {}, got {}", function, expected, noun, got)
+            }
+            RuntimeError::IndexOutOfBounds { len, idx } => write!(f, "Array index out of bounds: {} (length {})", idx, len),
+            RuntimeError::RecursionLimitExceeded { limit } => {
+                write!(f, "Recursion limit exceeded: more than {} nested function calls", limit)
+            }
+            RuntimeError::ValueTooLarge { kind, size, limit } => {
+                write!(f, "Value too large: {} of size {} exceeds the limit of {}", kind, size, limit)
+            }
+            RuntimeError::TypeMismatch(msg) | RuntimeError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The options a host sets up front when creating a VM with `Vm::with_config`.
+/// `VmConfig::default()` gives the same VM as `Vm::new()`.
+pub struct VmConfig {
+    /// Whether the value of every top-level expression statement is recorded (see `Vm::results`).
+    pub collect_results: bool,
+    /// The most array elements `print` shows before eliding the middle (`None` prints them all).
+    pub array_print_limit: Option<usize>,
+    /// The largest string (in bytes) or array (in elements) a program may create (`None` for no limit).
+    pub max_value_size: Option<usize>,
+    /// Whether non-void functions return the value of a final expression statement (see `Vm::implicit_return`).
+    pub implicit_return: bool,
+    /// Where `print` and `printf` write.
+    pub output: Box<dyn Write + Send>,
+    /// Where `eprint` writes.
+    pub error_output: Box<dyn Write + Send>,
+    /// The most function calls that may be in progress at once (see `Vm::max_call_depth`).
+    pub max_call_depth: usize,
+}
+
+impl Default for VmConfig {
+    /// No limits other than `DEFAULT_MAX_CALL_DEPTH`, with output going to standard output and standard error.
+    fn default() -> Self {
+        Self {
+            collect_results: false,
+            array_print_limit: None,
+            max_value_size: None,
+            implicit_return: false,
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
 }
 
 /// The virtual machine (VM) that runs the program, holding state like variables, functions, and constants.
 pub struct Vm {
     /// The last result returned by an expression evaluation.
     pub last_result: Value,
-    /// The list of variable scopes, with each scope being a map of variable names to values.
-    pub variables: Vec<HashMap<String, Value>>,
-    /// A map of function names to their corresponding function definitions.
-    pub functions: HashMap<String, Function>,
+    /// The list of variable scopes, innermost last, each holding where its variables' values are kept.
+    pub variables: Vec<Scope>,
+    /// How many scopes declare each variable name, so that looking up a name no scope declares
+    /// (such as a function's, at each call) doesn't search every scope of every call in progress.
+    /// Names stay in it with a count of 0, so a loop declaring a variable doesn't allocate for it.
+    declared_names: HashMap<String, usize>,
+    /// The cells of the blocks in use (see `blocks`). Position 0 is never used.
+    memory: Vec<Value>,
+    /// The blocks in use, by the address they start at: those from `malloc` until they are freed,
+    /// and the cells of variables whose address was taken until the variables go out of scope.
+    /// No two blocks ever get the same addresses, so a pointer into a released block is caught
+    /// even after its cells are reused. Address 0 is the null pointer.
+    blocks: BTreeMap<usize, Block>,
+    /// The address the next block starts at.
+    next_address: usize,
+    /// The ranges of unused cells in `memory`, as position and length, in order and with
+    /// adjacent ranges merged, which new blocks reuse.
+    free_cells: Vec<(usize, usize)>,
+    /// A map of function names and parameter counts to their function definitions,
+    /// so functions with the same name but different arities can coexist.
+    pub functions: HashMap<(String, usize), Function>,
     /// A map of constant names to their corresponding constant values.
     pub constants: HashMap<String, i32>,
+    /// The names of the globals declared with `const`, which can't be assigned.
+    pub consts: HashSet<String>,
+    /// A map of struct names to their declared types (including the field layout).
+    pub structs: HashMap<String, Type>,
     /// A flag that indicates whether the VM should return after the next statement.
     pub should_return: bool,
+    /// When set, the value of every top-level expression statement is recorded in `results`.
+    pub collect_results: bool,
+    /// The values collected from top-level expression statements (see `collect_results`).
+    results: Vec<Value>,
+    /// The source line of the statement currently being executed, if known.
+    current_line: Option<usize>,
+    /// The most array elements `print` shows before eliding the middle (`None` prints them all).
+    pub array_print_limit: Option<usize>,
+    /// The largest string (in bytes) or array (in elements) a program may create (`None` for no limit).
+    pub max_value_size: Option<usize>,
+    /// When set, a function that isn't `void` and whose body ends with an expression statement
+    /// returns that expression's value if it doesn't `return` earlier, as in Rust
+    /// (e.g. `int square(n) { n * n; }`). It applies to functions defined while it is set.
+    pub implicit_return: bool,
+    /// Where `print` and `printf` write (standard output by default).
+    output: Box<dyn Write + Send>,
+    /// Whether the output written so far ends in the middle of a line (see `end_output_line`).
+    output_mid_line: bool,
+    /// Where `eprint` writes (standard error by default).
+    error_output: Box<dyn Write + Send>,
+    /// The number of statements executed so far, including those in function calls (for `--time`).
+    pub statements_executed: u64,
+    /// The most function calls that may be in progress at once; a call beyond it fails with
+    /// `RuntimeError::RecursionLimitExceeded` rather than overflowing the native stack.
+    pub max_call_depth: usize,
+    /// The number of function calls in progress.
+    call_depth: usize,
 }
 
 impl Vm {
@@ -46,15 +371,66 @@ impl Vm {
     /// # Returns
     /// A new `Vm` instance with empty variables, functions, and constants.
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    /// Creates a new virtual machine with the given options.
+    ///
+    /// # Parameters
+    /// - `config`: The options, such as size limits and output sinks.
+    ///
+    /// # Returns
+    /// A new `Vm` instance with empty variables, functions, and constants.
+    pub fn with_config(config: VmConfig) -> Self {
         Self {
             last_result: Value::Int(0),
-            variables: vec![HashMap::new()],
+            variables: vec![Scope::default()],
+            declared_names: HashMap::new(),
+            memory: vec![Value::Int(0)],
+            blocks: BTreeMap::new(),
+            next_address: 1,
+            free_cells: Vec::new(),
             functions: HashMap::new(),
             constants: HashMap::new(),
+            consts: HashSet::new(),
+            structs: HashMap::new(),
             should_return: false,
+            collect_results: config.collect_results,
+            results: Vec::new(),
+            current_line: None,
+            array_print_limit: config.array_print_limit,
+            max_value_size: config.max_value_size,
+            implicit_return: config.implicit_return,
+            output: config.output,
+            output_mid_line: false,
+            error_output: config.error_output,
+            statements_executed: 0,
+            max_call_depth: config.max_call_depth,
+            call_depth: 0,
         }
     }
 
+    /// Forgets every variable, function, constant and struct, as if the VM were new.
+    /// The options it was created with (see `VmConfig`) are kept.
+    pub fn reset(&mut self) {
+        self.last_result = Value::Int(0);
+        self.variables = vec![Scope::default()];
+        self.declared_names.clear();
+        self.memory = vec![Value::Int(0)];
+        self.blocks.clear();
+        self.next_address = 1;
+        self.free_cells.clear();
+        self.functions.clear();
+        self.constants.clear();
+        self.consts.clear();
+        self.structs.clear();
+        self.should_return = false;
+        self.results.clear();
+        self.current_line = None;
+        self.statements_executed = 0;
+        self.call_depth = 0;
+    }
+
     /// Sets the last result to a given value and sets the return flag to true.
     ///
     /// # Parameters
@@ -67,12 +443,30 @@ impl Vm {
     /// Retrieves the last result as an integer.
     ///
     /// # Returns
-    /// The last result as an integer, or 0 if the result is not an integer.
+    /// The last result as an integer (floats are truncated), or 0 if the result is not a number.
+    #[allow(dead_code)]
     pub fn get_result(&self) -> i32 {
         match &self.last_result {
             Value::Int(i) => *i,
+            Value::Bool(b) => *b as i32,
+            Value::Float(x) => *x as i32,
             Value::Str(_) => 0,
             Value::Array(_) => 0, // Default to 0 for arrays
+            Value::Func(_) | Value::Struct(..) => 0,
+            Value::Ptr(addr) => *addr as i32,
+        }
+    }
+
+    /// Retrieves the value returned by the program.
+    ///
+    /// # Returns
+    /// The value of the top-level `return` that ended the program, or `None` if the
+    /// program ran to the end without returning a value (a void result).
+    pub fn get_result_value(&self) -> Option<&Value> {
+        if self.should_return { // Only a top-level `return` leaves the flag set after execution
+            Some(&self.last_result)
+        } else {
+            None
         }
     }
 
@@ -80,6 +474,7 @@ impl Vm {
     ///
     /// # Returns
     /// An `Option` containing the string, or `None` if the result is not a string.
+    #[allow(dead_code)]
     pub fn get_result_str(&self) -> Option<&str> {
         match &self.last_result {
             Value::Str(s) => Some(s),
@@ -87,1008 +482,4099 @@ impl Vm {
         }
     }
 
-    /// Executes a statement, performing the appropriate actions based on the statement type.
+    /// Retrieves the values of the top-level expression statements executed so far.
     ///
-    /// # Parameters
-    /// - `stmt`: The statement to execute.
-    pub fn execute(&mut self, stmt: Stmt) {
-        if self.should_return {
-            return;
+    /// # Returns
+    /// The collected values, in execution order. This is always empty unless
+    /// `collect_results` is enabled.
+    #[allow(dead_code)]
+    pub fn results(&self) -> &[Value] {
+        &self.results
+    }
+
+    /// Removes and returns the values collected so far, so the next call only sees new ones.
+    ///
+    /// # Returns
+    /// The collected values, in execution order (see `results`).
+    pub fn take_results(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.results)
+    }
+
+    /// Redirects the output of `print` and `printf`, e.g. into a buffer in tests.
+    #[allow(dead_code)]
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    /// Redirects the output of `eprint`, which goes to standard error by default.
+    #[allow(dead_code)]
+    pub fn set_error_output(&mut self, error_output: Box<dyn Write + Send>) {
+        self.error_output = error_output;
+    }
+
+    /// Looks up a variable in the innermost scope that defines it.
+    fn get_variable(&self, name: &str) -> Option<&Value> {
+        if self.declared_names.get(name).is_none_or(|&count| count == 0) {
+            return None;
         }
+        let slot = self.variables.iter().rev().find_map(|scope| scope.get(name))?;
+        self.slot_value(slot)
+    }
 
-        match stmt {
-            Stmt::Return(expr) => {
-                let value = self.eval_expr(expr);
-                self.set_result(value);
-            }
-            Stmt::Let { name, value, .. } => {
-                let val = self.eval_expr(value);
-                self.variables.last_mut().unwrap().insert(name, val);
-            }
-            Stmt::Assign { name, value } => {
-                let val = self.eval_expr(value);
-                for scope in self.variables.iter_mut().rev() {
-                    if scope.contains_key(&name) {
-                        scope.insert(name, val);
-                        return;
-                    }
-                }
-                self.variables.last_mut().unwrap().insert(name, val);
-            }
-            Stmt::If { condition, then_branch, else_branch } => {
-                if self.eval_as_bool(condition) {
-                    self.execute(*then_branch);
-                } else if let Some(else_stmt) = else_branch {
-                    self.execute(*else_stmt);
-                }
-            }
-            Stmt::While { condition, body } => {
-                while self.eval_as_bool(condition.clone()) {
-                    self.execute(*body.clone());
-                    if self.should_return {
-                        break;
-                    }
-                }
+    /// Looks up a variable in the innermost scope that defines it, for updating it in place.
+    fn variable_mut(&mut self, name: &str) -> Option<&mut Value> {
+        match self.variables.iter_mut().rev().find_map(|scope| scope.get_mut(name))? {
+            Slot::Value(val) => Some(val),
+            Slot::Cell { index, .. } => self.memory.get_mut(*index),
+        }
+    }
+
+    /// Returns a variable the resolver located (see `Expr::Local`), for reading or updating it in place.
+    fn local_mut(&mut self, depth: usize, slot: usize) -> Option<&mut Value> {
+        let scope = self.variables.len().checked_sub(depth + 1)?;
+        match self.variables[scope].slots.get_mut(slot)? {
+            Slot::Value(val) => Some(val),
+            Slot::Cell { index, .. } => self.memory.get_mut(*index),
+        }
+    }
+
+    /// Returns the value kept in a variable's slot, following it into memory if needed.
+    pub fn slot_value<'a>(&'a self, slot: &'a Slot) -> Option<&'a Value> {
+        match slot {
+            Slot::Value(val) => Some(val),
+            Slot::Cell { index, .. } => self.memory.get(*index),
+        }
+    }
+
+    /// Returns an error if `name` refers to a constant rather than a variable shadowing it,
+    /// for anything that would change its value.
+    fn check_not_constant(&self, name: &str) -> Result<(), RuntimeError> {
+        let innermost = self.variables.iter().rposition(|scope| scope.contains_key(name));
+        if innermost == Some(0) && self.consts.contains(name) {
+            return Err(RuntimeError::Other(format!("Cannot assign to constant '{}'", name)));
+        }
+        Ok(())
+    }
+
+    /// Declares a variable in the innermost scope, replacing any variable of that name in it.
+    fn declare(&mut self, name: String, val: Value) {
+        let (replaced_cell, is_new) = match self.variables.last().unwrap().get(&name) {
+            Some(Slot::Cell { addr, .. }) => (Some(*addr), false),
+            Some(Slot::Value(_)) => (None, false),
+            None => (None, true),
+        };
+        if is_new {
+            self.count_declaration(&name);
+        }
+        self.variables.last_mut().unwrap().insert(name, Slot::Value(val));
+        if let Some(addr) = replaced_cell {
+            self.release_block(addr); // The replaced variable's cell is no longer reachable by name
+        }
+    }
+
+    /// Returns the address of a variable for `&name`, moving it into memory the first time.
+    /// The cell is released when the variable's scope ends (see `pop_scope`).
+    fn address_of(&mut self, name: &str) -> Result<usize, RuntimeError> {
+        self.check_not_constant(name)?; // A pointer could be used to change it
+        match self.variables.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(Slot::Cell { addr, .. }) => return Ok(*addr), // Already in memory
+            Some(Slot::Value(_)) => {}
+            None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
+        }
+        let (addr, index) = self.take_block(1, false);
+        if let Some(slot) = self.variables.iter_mut().rev().find_map(|scope| scope.get_mut(name)) {
+            if let Slot::Value(val) = std::mem::replace(slot, Slot::Cell { addr, index }) {
+                self.memory[index] = val;
             }
-            Stmt::Block(stmts) => {
-                let is_single_scope = stmts.iter().all(|s| matches!(s, Stmt::Let { .. }));
-                if !is_single_scope {
-                    self.variables.push(HashMap::new());
-                }
-                for stmt in stmts {
-                    self.execute(stmt);
-                    if self.should_return {
-                        break;
-                    }
-                }
-                if !is_single_scope {
-                    self.variables.pop();
-                }
+        }
+        Ok(addr)
+    }
+
+    /// Leaves the innermost scope, releasing the memory cells of its variables whose address was taken.
+    fn pop_scope(&mut self) {
+        if let Some(scope) = self.variables.pop() {
+            self.release_cells(&scope);
+            self.forget_names(&scope);
+        }
+    }
+
+    /// Counts a new declaration of `name` in `declared_names`.
+    fn count_declaration(&mut self, name: &str) {
+        match self.declared_names.get_mut(name) {
+            Some(count) => *count += 1,
+            None => { self.declared_names.insert(name.to_string(), 1); }
+        }
+    }
+
+    /// Updates `declared_names` for a scope that was left.
+    fn forget_names(&mut self, scope: &Scope) {
+        for name in scope.names.keys() {
+            if let Some(count) = self.declared_names.get_mut(name) {
+                *count -= 1;
             }
-            #[allow(unused_variables)]
-            Stmt::Function { name, params, body, return_type } => {
-                self.functions.insert(name.clone(), Function {
-                    name,
-                    params,
-                    body: *body,
-                    return_type: None, // or Some(Type::Int) if you want to default to int
-                });
-            }
-            Stmt::Print(expr) => {
-                let val = self.eval_expr(expr);
-                match val {
-                    Value::Int(i) => println!("{}", i),
-                    Value::Str(s) => println!("{}", s),
-                    Value::Array(arr) => {
-                        let display = arr.iter()
-                                         .map(|v| match v {
-                                             Value::Int(i) => i.to_string(),
-                                             Value::Str(s) => format!("\"{}\"", s),
-                                             _ => String::from("?"),
-                                         })
-                                         .collect::<Vec<_>>()
-                                         .join(", ");
-                        println!("[{}]", display);
-                    }
-                }
+        }
+    }
+
+    /// Leaves scopes until `depth` are left, as `pop_scope` does, e.g. after an error interrupted them.
+    pub(crate) fn truncate_scopes(&mut self, depth: usize) {
+        while self.variables.len() > depth {
+            self.pop_scope();
+        }
+    }
+
+    /// Releases the memory cells of a scope's variables whose address was taken, for later reuse.
+    /// A pointer to one of them that is still around then refers to freed memory.
+    fn release_cells(&mut self, scope: &Scope) {
+        for slot in &scope.slots {
+            if let Slot::Cell { addr, .. } = slot {
+                self.release_block(*addr);
             }
-            Stmt::ExprStmt(expr) => {
-                self.eval_expr(expr);
+        }
+    }
+
+    /// Returns the memory cell a pointer refers to, for `*p`.
+    fn cell_mut(&mut self, pointer: Value) -> Result<&mut Value, RuntimeError> {
+        match pointer {
+            Value::Ptr(addr) => {
+                let index = self.element_index(addr, 0)?;
+                Ok(&mut self.memory[index])
             }
+            _ => Err(RuntimeError::TypeMismatch("Invalid pointer dereference".to_string())),
         }
     }
 
-    /// Evaluates an expression and returns its result as a `Value`.
+    /// Returns the block in use that contains `addr`, with the address the block starts at.
+    fn block_at(&self, addr: usize) -> Option<(usize, Block)> {
+        let (&start, &block) = self.blocks.range(..=addr).next_back()?;
+        if addr - start < block.size {
+            Some((start, block))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the position in `memory` of element `idx` of the block a pointer refers to,
+    /// for `p[idx]` (and `*p`, element 0). The pointer must be in a block in use: not null and
+    /// not in a block that was released, even if its cells have been reused since. The element
+    /// must be in the same block, so `p[idx]` past the end of the block is out of bounds.
+    fn element_index(&self, addr: usize, idx: i32) -> Result<usize, RuntimeError> {
+        if addr == 0 {
+            return Err(RuntimeError::Other("Null pointer dereference".to_string()));
+        }
+        let (start, block) = match self.block_at(addr) {
+            Some(found) => found,
+            // Addresses are handed out in order and never reused, so one below the next is in a released block
+            None if addr < self.next_address => {
+                return Err(RuntimeError::Other(format!("Use after free: address {} was freed", addr)));
+            }
+            None => return Err(RuntimeError::Other(format!("Invalid pointer: no memory at address {}", addr))),
+        };
+        let offset = addr - start;
+        Ok(block.index + offset + checked_index(idx, block.size - offset)?)
+    }
+
+    /// Allocates `size` zeroed memory cells for `malloc`, reusing freed cells if there are enough in a row.
     ///
-    /// # Parameters
-    /// - `expr`: The expression to evaluate.
+    /// # Returns
+    /// The address of the first cell.
+    pub(crate) fn allocate(&mut self, size: usize) -> Result<usize, RuntimeError> {
+        self.check_size("allocation", size)?;
+        Ok(self.take_block(size, true).0)
+    }
+
+    /// Sets aside a block of `size` zeroed cells, for `malloc` (`heap`) or for a variable whose
+    /// address is taken. The block gets addresses no earlier block had, whichever cells it reuses.
     ///
     /// # Returns
-    /// The evaluated result as a `Value`.
-    fn eval_expr(&mut self, expr: Expr) -> Value {
-        match expr {
-            Expr::Number(n) => Value::Int(n),
-            Expr::Boolean(b) => Value::Int(if b { 1 } else { 0 }),
-            Expr::Char(c) => Value::Int(c as i32),
-            Expr::StringLiteral(s) => Value::Str(s),
-            Expr::Ternary { condition, then_branch, else_branch } => {
-                if self.eval_as_bool(*condition) {
-                    self.eval_expr(*then_branch)
-                } else {
-                    self.eval_expr(*else_branch)
-                }
-            }
-            Expr::AddressOf(expr) => {
-                let val = self.eval_expr(*expr);
-                match val {
-                    Value::Int(i) => Value::Int(i * 1000),
-                    _ => panic!("Cannot take address of non-int"),
-                }
-            }
-            Expr::Deref(expr) => {
-                let addr = self.eval_expr(*expr);
-                match addr {
-                    Value::Int(fake_ptr) => Value::Int(fake_ptr / 1000),
-                    _ => panic!("Invalid pointer dereference"),
-                }
-            }
-            Expr::ArrayLiteral(elements) => {
-                let evaluated = elements.into_iter()
-                    .map(|e| self.eval_expr(e))
-                    .collect::<Vec<_>>();
-                Value::Array(evaluated)
-            }
-            Expr::ArrayIndex(array_expr, index_expr) => {
-                let array_val = self.eval_expr(*array_expr);
-                let index_val = self.eval_expr(*index_expr);
-                let idx = match index_val {
-                    Value::Int(i) => i as usize,
-                    _ => panic!("Array index must be an integer"),
-                };
-                match array_val {
-                    Value::Array(vec) => vec.get(idx).cloned().unwrap_or_else(|| {
-                        panic!("Array index out of bounds: {}", idx)
-                    }),
-                    _ => panic!("Attempted to index non-array value"),
-                }
-            }
-            Expr::PreInc(expr) => {
-                if let Expr::Variable(name) = *expr {
-                    for scope in self.variables.iter_mut().rev() {
-                        if let Some(Value::Int(ref mut val)) = scope.get_mut(&name) {
-                            *val += 1;
-                            return Value::Int(*val);
-                        }
-                    }
-                    panic!("Variable '{}' not found", name);
-                } else {
-                    panic!("++ requires a variable");
-                }
-            }
-            Expr::PreDec(expr) => {
-                if let Expr::Variable(name) = *expr {
-                    for scope in self.variables.iter_mut().rev() {
-                        if let Some(Value::Int(ref mut val)) = scope.get_mut(&name) {
-                            *val -= 1;
-                            return Value::Int(*val);
-                        }
-                    }
-                    panic!("Variable '{}' not found", name);
-                } else {
-                    panic!("-- requires a variable");
-                }
-            }
-            Expr::PostInc(expr) => {
-                if let Expr::Variable(name) = *expr {
-                    for scope in self.variables.iter_mut().rev() {
-                        if let Some(Value::Int(ref mut val)) = scope.get_mut(&name) {
-                            let original = *val;
-                            *val += 1;
-                            return Value::Int(original);
-                        }
-                    }
-                    panic!("Variable '{}' not found", name);
+    /// The address of the block and the position of its first cell in `memory`.
+    fn take_block(&mut self, size: usize, heap: bool) -> (usize, usize) {
+        let index = match self.free_cells.iter().position(|&(_, len)| len >= size) {
+            Some(i) => {
+                let (index, len) = self.free_cells[i];
+                if len > size {
+                    self.free_cells[i] = (index + size, len - size); // Keep the rest of the range free
                 } else {
-                    panic!("++ requires a variable");
-                }
-            }
-            Expr::PostDec(expr) => {
-                if let Expr::Variable(name) = *expr {
-                    for scope in self.variables.iter_mut().rev() {
-                        if let Some(Value::Int(ref mut val)) = scope.get_mut(&name) {
-                            let original = *val;
-                            *val -= 1;
-                            return Value::Int(original);
-                        }
-                    }
-                    panic!("Variable '{}' not found", name);
-                } else {
-                    panic!("-- requires a variable");
-                }
-            }
-            Expr::SizeOf(t) => {
-                let size: i32 = match t {
-                    Type::Int => 4,
-                    Type::Char => 1,
-                    Type::Pointer(_) => 8,
-                    Type::Void => 0,
-                    Type::Array(elem_type, len) => {
-                        let elem_size = match *elem_type {
-                            Type::Int => 4,
-                            Type::Char => 1,
-                            Type::Pointer(_) => 8,
-                            Type::Void => 0,
-                            Type::Array(_, _) => panic!("Nested arrays not supported in sizeof"),
-                        };
-                        elem_size * (len as i32)
-                    }
-                };
-                
-                Value::Int(size)
-            }
-            Expr::Cast(to_type, expr) => {
-                let val = self.eval_expr(*expr);
-                match (&to_type, val) {
-                    (Type::Int, Value::Int(i)) => Value::Int(i),
-                    (Type::Char, Value::Int(i)) => Value::Int(i & 0xFF),
-                    (Type::Int, Value::Str(_)) => Value::Int(0),
-                    (Type::Char, Value::Str(_)) => Value::Int(0),
-                    (Type::Pointer(_), Value::Int(i)) => Value::Int(i),
-                    (_, v) => panic!("Unsupported cast: {:?} to {:?}", v, to_type),
-                }
-            }
-            Expr::Variable(name) => {
-                for scope in self.variables.iter().rev() {
-                    if let Some(val) = scope.get(&name) {
-                        return val.clone();
-                    }
-                }
-                if let Some(i) = self.constants.get(&name) {
-                    return Value::Int(*i);
+                    self.free_cells.remove(i);
                 }
-                panic!("Variable '{}' not found", name);
+                index
             }
-            Expr::EnumValue(enum_name, variant_name) => {
-                let key = format!("{}::{}", enum_name, variant_name);
-                Value::Int(*self.constants.get(&key).unwrap_or_else(|| {
-                    panic!("Enum variant '{}' not found", key)
-                }))
-            }
-            Expr::BinaryOp { op, left, right } => {
-                if op == BinOp::Assign {
-                    return self.handle_assign(*left, *right);
-                }
-                let l = self.eval_expr(*left);
-                let r = self.eval_expr(*right);
-                match (l, r) {
-                    (Value::Int(li), Value::Int(ri)) => match op {
-                        BinOp::Add => Value::Int(li + ri),
-                        BinOp::Sub => Value::Int(li - ri),
-                        BinOp::Mul => Value::Int(li * ri),
-                        BinOp::Div => {
-                            if ri == 0 {
-                                panic!("Division by zero");
-                            }
-                            Value::Int(li / ri)
-                        }
-                        BinOp::Mod => {
-                            if ri == 0 {
-                                panic!("Modulo by zero");
-                            }
-                            Value::Int(li % ri)
-                        }
-                        BinOp::Equal => Value::Int((li == ri) as i32),
-                        BinOp::NotEqual => Value::Int((li != ri) as i32),
-                        BinOp::LessThan => Value::Int((li < ri) as i32),
-                        BinOp::GreaterThan => Value::Int((li > ri) as i32),
-                        BinOp::LessEqual => Value::Int((li <= ri) as i32),
-                        BinOp::GreaterEqual => Value::Int((li >= ri) as i32),
-                        BinOp::And => Value::Int((li != 0 && ri != 0) as i32),
-                        BinOp::Or => Value::Int((li != 0 || ri != 0) as i32),
-                        BinOp::BitAnd => Value::Int(li & ri),
-                        BinOp::BitOr => Value::Int(li | ri),
-                        BinOp::BitXor => Value::Int(li ^ ri),
-                        BinOp::Shl => Value::Int(li << ri),
-                        BinOp::Shr => Value::Int(li >> ri),
-                        _ => unreachable!(),
-                    },
-                    (Value::Str(ls), Value::Str(rs)) => match op {
-                        BinOp::Add => Value::Str(ls + &rs),
-                        BinOp::Equal => Value::Int((ls == rs) as i32),
-                        BinOp::NotEqual => Value::Int((ls != rs) as i32),
-                        _ => panic!("Unsupported string operation: {:?}", op),
-                    },
-                    _ => panic!("Mismatched types for operation"),
-                }
-            }
-            Expr::UnaryOp { op, expr } => {
-                let val = self.eval_expr(*expr);
-                match op {
-                    UnOp::Not => match val {
-                        Value::Int(i) => Value::Int(if i == 0 { 1 } else { 0 }),
-                        Value::Str(_) => Value::Int(0),
-                        Value::Array(_) => panic!("Cannot apply 'Not' operator to an array"),
-                    },
-                }
-            }
-            Expr::FunctionCall { name, args } => {
-                let function = self.functions.get(&name).unwrap_or_else(|| {
-                    panic!("Function '{}' not found", name)
-                }).clone();
-    
-                let arg_values: Vec<Value> = args.into_iter().map(|arg| self.eval_expr(arg)).collect();
-    
-                if arg_values.len() != function.params.len() {
-                    panic!(
-                        "Function '{}' expected {} arguments, got {}",
-                        name,
-                        function.params.len(),
-                        arg_values.len()
-                    );
-                }
-    
-                self.variables.push(HashMap::new());
-                for (param, val) in function.params.iter().zip(arg_values) {
-                    self.variables.last_mut().unwrap().insert(param.clone(), val);
-                }
-    
-                let prev_result = self.last_result.clone();
-                let prev_should_return = self.should_return;
-                self.last_result = Value::Int(0);
-                self.should_return = false;
-    
-                self.execute(function.body.clone());
-    
-                let result = self.last_result.clone();
-                self.variables.pop();
-                self.last_result = prev_result;
-                self.should_return = prev_should_return;
-                result
+            None => {
+                self.memory.resize(self.memory.len() + size, Value::Int(0));
+                self.memory.len() - size
             }
+        };
+        self.memory[index..index + size].fill(Value::Int(0));
+        let addr = self.next_address;
+        self.next_address += size;
+        self.blocks.insert(addr, Block { index, size, heap });
+        (addr, index)
+    }
+
+    /// Releases the block starting at `addr`, giving its cells back for reuse.
+    fn release_block(&mut self, addr: usize) {
+        let block = match self.blocks.remove(&addr) {
+            Some(block) => block,
+            None => return,
+        };
+        // Merge the cells with the free ranges on either side, so that larger blocks fit later
+        let (mut index, mut len) = (block.index, block.size);
+        let mut i = self.free_cells.partition_point(|&(start, _)| start < index);
+        if i < self.free_cells.len() && index + len == self.free_cells[i].0 {
+            len += self.free_cells.remove(i).1;
+        }
+        if i > 0 && self.free_cells[i - 1].0 + self.free_cells[i - 1].1 == index {
+            i -= 1;
+            let (start, before) = self.free_cells.remove(i);
+            index = start;
+            len += before;
+        }
+        if index + len == self.memory.len() {
+            self.memory.truncate(index); // Free cells at the end aren't kept at all
+        } else {
+            self.free_cells.insert(i, (index, len));
         }
     }
 
-    /// Handles assignment operations for variables and array indices.
-    ///
-    /// # Parameters
-    /// - `left`: The left-hand side expression (either a variable or an array index).
-    /// - `right`: The value to assign.
-    ///
-    /// # Returns
-    /// The value that was assigned to the left-hand side.
-    fn handle_assign(&mut self, left: Expr, right: Expr) -> Value {
-        match left {
-            Expr::Variable(name) => {
-                let val = self.eval_expr(right);
-                for scope in self.variables.iter_mut().rev() {
-                    if scope.contains_key(&name) {
-                        scope.insert(name.clone(), val.clone());
-                        return val;
-                    }
-                }
-                self.variables.last_mut().unwrap().insert(name, val.clone());
-                val
+    /// Releases a block allocated by `malloc`, for `free`. Freeing the null pointer does nothing.
+    pub(crate) fn release(&mut self, addr: usize) -> Result<(), RuntimeError> {
+        if addr == 0 {
+            return Ok(());
+        }
+        match self.blocks.get(&addr) {
+            Some(block) if block.heap => {
+                self.release_block(addr);
+                Ok(())
             }
-            Expr::ArrayIndex(array_expr, index_expr) => {
-                let array_name = match *array_expr {
-                    Expr::Variable(name) => name,
-                    _ => panic!("Left-hand side must be a variable array reference"),
-                };
-                let index = match self.eval_expr(*index_expr) {
-                    Value::Int(i) => i as usize,
-                    _ => panic!("Array index must be an integer"),
-                };
-                let val = self.eval_expr(right);
-                for scope in self.variables.iter_mut().rev() {
-                    if let Some(Value::Array(ref mut vec)) = scope.get_mut(&array_name) {
-                        if index >= vec.len() {
-                            panic!("Array index {} out of bounds", index);
-                        }
-                        vec[index] = val.clone();
-                        return val;
-                    }
-                }
-                panic!("Array '{}' not found", array_name);
+            None if addr < self.next_address && self.block_at(addr).is_none() => {
+                Err(RuntimeError::Other(format!("Double free of address {}", addr)))
             }
-            _ => panic!("Left-hand side of assignment must be a variable or array element"),
+            _ => Err(RuntimeError::Other(format!("free() of address {} that wasn't returned by malloc", addr))),
         }
     }
 
-    /// Evaluates an expression and returns its result as a boolean value.
+    /// Checks a string or array size against `max_value_size`, before or after creating the value.
     ///
     /// # Parameters
-    /// - `expr`: The expression to evaluate.
+    /// - `kind`: What is being created, for the error message (e.g., "string").
+    /// - `size`: Its size in bytes (strings) or elements (arrays).
+    pub(crate) fn check_size(&self, kind: &'static str, size: usize) -> Result<(), RuntimeError> {
+        match self.max_value_size {
+            Some(limit) if size > limit => Err(RuntimeError::ValueTooLarge { kind, size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the size of a value that has just been created (see `check_size`).
+    pub(crate) fn check_value_size(&self, value: &Value) -> Result<(), RuntimeError> {
+        match value {
+            Value::Str(s) => self.check_size("string", s.len()),
+            Value::Array(elements) => self.check_size("array", elements.len()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes program output to the output sink (standard output by default).
+    pub(crate) fn write_output(&mut self, text: &str) -> Result<(), RuntimeError> {
+        if !text.is_empty() {
+            self.output_mid_line = !text.ends_with('\n');
+        }
+        self.output.write_all(text.as_bytes())
+            .map_err(|e| RuntimeError::Other(format!("Failed to write program output: {}", e)))
+    }
+
+    /// Ends the line of program output if `print` left it open, so that what is written next
+    /// (e.g. the CLI's result line or the REPL's prompt) starts on a line of its own.
+    pub fn end_output_line(&mut self) -> io::Result<()> {
+        if self.output_mid_line {
+            self.output_mid_line = false;
+            self.output.write_all(b"\n")?;
+        }
+        self.output.flush()
+    }
+
+    /// Writes diagnostic output to the error sink (standard error by default).
+    pub(crate) fn write_error_output(&mut self, text: &str) -> Result<(), RuntimeError> {
+        self.error_output.write_all(text.as_bytes())
+            .map_err(|e| RuntimeError::Other(format!("Failed to write error output: {}", e)))
+    }
+
+    /// Lists the names of all user-defined functions, e.g. for completion in an editor.
     ///
     /// # Returns
-    /// A boolean value (`true` or `false`).
-    fn eval_as_bool(&mut self, expr: Expr) -> bool {
-        match self.eval_expr(expr) {
-            Value::Int(i) => i != 0,  // Non-zero integers are treated as true, zero as false
-            Value::Str(_) => true,     // Any non-empty string is considered "truthy"
-            Value::Array(_) => true,   // Arrays are considered "truthy"
-        }
+    /// The function names, sorted alphabetically.
+    #[allow(dead_code)]
+    pub fn function_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.functions.keys().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        names.dedup(); // Overloads share a name
+        names
     }
-}
 
+    /// Lists the names of all constants (such as enum variants).
+    ///
+    /// # Returns
+    /// The constant names, sorted alphabetically.
+    #[allow(dead_code)]
+    pub fn constant_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.constants.keys().cloned().collect();
+        names.sort();
+        names
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
+    /// Retrieves the source line of the statement currently being executed.
+    ///
+    /// # Returns
+    /// The line number, or `None` if no statement with a known line has run yet.
+    /// After execution finishes, this is the line of the last statement executed.
+    #[allow(dead_code)]
+    pub fn current_line(&self) -> Option<usize> {
+        self.current_line
+    }
 
-    /// Helper function to run a piece of C4 code and return the result.
+    /// Lexes, parses and runs a program in this VM. Functions, variables and constants the
+    /// program defines are kept, so they can be used by later calls to `run` or `call_function`.
+    /// For example, `Vm::new().run("return 2+2;")` returns `Ok(Some(Value::Int(4)))`. The crate
+    /// is a binary without doctests, so `test_vm_run` checks this example.
     ///
     /// # Parameters
-    /// - `code`: A string containing the C4 code to execute.
+    /// - `source`: The C4 source code to run.
     ///
     /// # Returns
-    /// The integer result of executing the C4 code.
-    fn run(code: &str) -> i32 {
-        let lexer = Lexer::new(code);
-        let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
-        vm.get_result()
+    /// The value of the program's top-level `return`, `None` if it didn't return a value,
+    /// or an `Error` telling which phase failed.
+    pub fn run(&mut self, source: &str) -> Result<Option<Value>, Error> {
+        self.should_return = false; // A `return` from an earlier program doesn't end this one
+        let statements = self.compile(source)?;
+        self.execute_program(statements)?;
+        Ok(self.get_result_value().cloned())
     }
 
-    /// Tests basic arithmetic operations such as addition and multiplication.
-    #[test]
-    fn test_arithmetic() {
-        let code = "return 2 + 3 * 4;";
-        assert_eq!(run(code), 14);
+    /// Lexes and parses a program, folding its compile-time constants (see `run`).
+    pub(crate) fn compile(&mut self, source: &str) -> Result<Vec<Stmt>, Error> {
+        let mut parser = Parser::new(Lexer::new(source), self);
+        // An error from the lexer is reported as such, rather than as the syntax error it stopped the parser with
+        let statements = parser.parse().map_err(|err| match parser.lex_error() {
+            Some(lex_error) => Error::Lex(lex_error.clone()),
+            None => Error::Parse(err),
+        })?;
+        Ok(resolver::resolve_program(fold::fold_program(statements, &self.foldable_constants())))
     }
 
-    /// Tests variable assignment and using those variables in calculations.
-    #[test]
-    fn test_variable_assignment() {
-        let code = "let x = 10; let y = x + 5; return y;";
-        assert_eq!(run(code), 15);
+    /// Returns the constants that code compiled now may fold (see `fold::fold_program`):
+    /// all of them except those shadowed by a variable that already exists, such as a global
+    /// defined by an earlier `run`.
+    pub(crate) fn foldable_constants(&self) -> HashMap<String, i32> {
+        self.constants.iter()
+            .filter(|(name, _)| self.get_variable(name).is_none())
+            .map(|(name, value)| (name.clone(), *value))
+            .collect()
     }
 
-    /// Tests function call with a single parameter and return value.
-    #[test]
-    fn test_function_call() {
-        let code = "
-            int square(n) {
-                return n * n;
+    /// Executes a parsed program's statements in order, stopping at the first error (see `run`).
+    pub(crate) fn execute_program(&mut self, statements: Vec<Stmt>) -> Result<(), Error> {
+        // Every runtime error, integer overflow included, is returned by `execute` rather than panicking.
+        // Runtime errors carry no position of their own, so the error is placed at the statement that failed.
+        statements.iter().try_for_each(|stmt| self.execute(stmt))
+            .map_err(|error| Error::Runtime { error, line: self.current_line })
+    }
+
+    /// Executes a statement, performing the appropriate actions based on the statement type.
+    /// The statement is only borrowed, so loop and function bodies run without being copied.
+    ///
+    /// # Parameters
+    /// - `stmt`: The statement to execute.
+    ///
+    /// # Returns
+    /// `Ok` once the statement has run, or the error that stopped it (e.g. a division by zero).
+    pub fn execute(&mut self, stmt: &Stmt) -> Result<(), RuntimeError> {
+        if self.should_return {
+            return Ok(());
+        }
+        let mut stmt = stmt;
+        while let Stmt::Located { line, stmt: located } = stmt { // Unwrapped here rather than by recursing
+            self.current_line = Some(*line);
+            stmt = located;
+        }
+        self.statements_executed += 1; // Count the statement, not its line annotation
+
+        // As in `eval_expr`, the arms only dispatch, so that the frames nested in each call stay small
+        match stmt {
+            Stmt::Return(expr) => self.eval_expr(expr).map(|value| self.set_result(value)),
+            Stmt::Const { name, value } => self.execute_const(name, value),
+            Stmt::Let { name, value, var_type } => self.execute_let(name, value, var_type),
+            Stmt::LetArray { names, rest, value } => self.execute_let_array(names, rest, value),
+            Stmt::Assign { name, value } => self.eval_expr(value).map(|val| self.assign_variable(name, val)),
+            Stmt::If { condition, then_branch, else_branch } => self.execute_if(condition, then_branch, else_branch),
+            Stmt::While { condition, body } => self.execute_while(condition, body),
+            Stmt::Try { body, name, handler } => self.execute_try(body, name, handler),
+            Stmt::DoWhile { body, condition } => self.execute_do_while(body, condition),
+            Stmt::Switch { scrutinee, cases, default } => self.execute_switch(scrutinee, cases, default),
+            Stmt::For { init, condition, update, body } => self.execute_for(init, condition, update, body),
+            Stmt::Located { .. } => unreachable!("line annotations are unwrapped above"),
+            Stmt::Block(stmts) => self.execute_block(stmts),
+            Stmt::LetGroup(decls) => decls.iter().try_for_each(|decl| self.execute(decl)),
+            Stmt::Function { name, params, variadic, body, return_type } => {
+                self.define_function(name, params, *variadic, body, return_type);
+                Ok(())
+            }
+            Stmt::Print { value, newline } => self.execute_print(value, *newline),
+            Stmt::ExprStmt(expr) => self.execute_expr_stmt(expr),
+        }
+    }
+
+    /// Runs the branch of an `if` statement its condition picks, for `execute`.
+    fn execute_if(&mut self, condition: &Expr, then_branch: &Stmt, else_branch: &Option<Box<Stmt>>) -> Result<(), RuntimeError> {
+        if self.eval_as_bool(condition)? {
+            self.execute(then_branch)?;
+        } else if let Some(else_stmt) = else_branch {
+            self.execute(else_stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a `while` loop until its condition is false or its body returns, for `execute`.
+    fn execute_while(&mut self, condition: &Expr, body: &Stmt) -> Result<(), RuntimeError> {
+        while self.eval_as_bool(condition)? {
+            self.execute(body)?;
+            if self.should_return {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a `do`-`while` loop, whose body runs before the condition is checked, for `execute`.
+    fn execute_do_while(&mut self, body: &Stmt, condition: &Expr) -> Result<(), RuntimeError> {
+        loop {
+            self.execute(body)?;
+            if self.should_return || !self.eval_as_bool(condition)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Evaluates an expression statement, keeping its value if `collect_results` is set, for `execute`.
+    fn execute_expr_stmt(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+        let val = self.eval_expr(expr)?;
+        if self.collect_results && self.variables.len() == 1 { // Only statements in the global scope
+            self.results.push(val);
+        }
+        Ok(())
+    }
+
+    /// Declares the constant `name` in the global scope, for `execute`.
+    fn execute_const(&mut self, name: &str, value: &Expr) -> Result<(), RuntimeError> {
+        if self.variables[0].contains_key(name) {
+            return Err(RuntimeError::Other(format!("Cannot redeclare '{}' as a constant", name)));
+        }
+        let val = self.eval_expr(value)?; // Evaluated once, at the declaration
+        self.variables[0].insert(name.to_string(), Slot::Value(val));
+        self.count_declaration(name);
+        self.consts.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Declares the variable `name`, converting its value to the declared type, for `execute`.
+    fn execute_let(&mut self, name: &str, value: &Expr, var_type: &Option<Type>) -> Result<(), RuntimeError> {
+        if self.variables.len() == 1 && self.consts.contains(name) {
+            return Err(RuntimeError::Other(format!("Cannot redeclare constant '{}'", name)));
+        }
+        let is_literal = matches!(value, Expr::ArrayLiteral(_));
+        let mut val = self.eval_expr(value)?;
+        // An array literal for a sized array is zero-padded to the declared size, as in C
+        if let (true, Some(array_type @ Type::Array(..))) = (is_literal, var_type) {
+            val = pad_array(name, array_type, val)?;
+        }
+        if let (true, Some(Type::Struct(struct_name, fields)), Value::Array(elements)) = (is_literal, var_type, &val) {
+            val = init_struct(struct_name, fields, elements)?; // `let p: Point = {1, 2};`
+        }
+        if let (Some(Type::Float), Value::Int(i)) = (var_type, &val) {
+            val = Value::Float(f64::from(*i)); // `float x = 1;` stores 1.0
+        }
+        self.declare(name.to_string(), val);
+        Ok(())
+    }
+
+    /// Declares the variables of `let [a, b, ...rest] = array;`, for `execute`.
+    fn execute_let_array(&mut self, names: &[String], rest: &Option<String>, value: &Expr) -> Result<(), RuntimeError> {
+        let mut elements = match self.eval_expr(value)? {
+            Value::Array(elements) => elements,
+            other => return Err(RuntimeError::TypeMismatch(format!("Cannot destructure non-array value {}", other))),
+        };
+        if rest.is_some() && elements.len() < names.len() {
+            return Err(RuntimeError::Other(format!(
+                "Cannot destructure an array of {} elements into at least {} variables", elements.len(), names.len()
+            )));
+        }
+        if rest.is_none() && elements.len() != names.len() {
+            return Err(RuntimeError::Other(format!(
+                "Cannot destructure an array of {} elements into {} variables", elements.len(), names.len()
+            )));
+        }
+        let tail = elements.split_off(names.len());
+        for (name, val) in names.iter().zip(elements) {
+            self.declare(name.clone(), val);
+        }
+        if let Some(rest) = rest {
+            self.declare(rest.clone(), Value::Array(tail));
+        }
+        Ok(())
+    }
+
+    /// Runs `try { body } catch (name) { handler }`, for `execute`.
+    fn execute_try(&mut self, body: &Stmt, name: &str, handler: &Stmt) -> Result<(), RuntimeError> {
+        let (depth, prev_result, prev_line) = (self.variables.len(), self.last_result.clone(), self.current_line);
+        if let Err(err) = self.execute(body) {
+            // Leave the scopes of any blocks and calls the error interrupted, as if they had returned
+            self.truncate_scopes(depth);
+            self.last_result = prev_result;
+            self.current_line = prev_line;
+            self.should_return = false;
+            self.variables.push(Scope::default());
+            self.declare(name.to_string(), Value::Str(err.to_string()));
+            let result = self.execute(handler);
+            self.truncate_scopes(depth);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Runs the case of a `switch` whose value matches the scrutinee, or its default, for `execute`.
+    fn execute_switch(&mut self, scrutinee: &Expr, cases: &[(Expr, Vec<Stmt>)], default: &Option<Vec<Stmt>>) -> Result<(), RuntimeError> {
+        let value = self.eval_expr(scrutinee)?;
+        let mut matched = None;
+        for (i, (case_value, _)) in cases.iter().enumerate() {
+            let case_value = self.eval_expr(case_value)?;
+            let is_match = match apply_binop(BinOp::Equal, value.clone(), case_value) {
+                Ok(equal) => equal.is_truthy(),
+                Err(RuntimeError::TypeMismatch(_)) => false, // A case of another type (e.g., a string) never matches
+                Err(err) => return Err(err),
+            };
+            if is_match {
+                matched = Some(i);
+                break;
+            }
+        }
+        // An empty case shares the body of the next case that has one, so `case 1: case 2:` both match it
+        let body = match matched {
+            Some(i) => cases.iter().skip(i).map(|(_, body)| body).find(|body| !body.is_empty()),
+            None => default.as_ref(),
+        };
+        if let Some(body) = body {
+            self.execute_block(body)?; // Only the matching case runs; there is no fallthrough
+        }
+        Ok(())
+    }
+
+    /// Runs a `for` loop, whose initializer's variables belong to the loop, for `execute`.
+    fn execute_for(
+        &mut self,
+        init: &Option<Box<Stmt>>,
+        condition: &Option<Expr>,
+        update: &Option<Expr>,
+        body: &Stmt,
+    ) -> Result<(), RuntimeError> {
+        self.variables.push(Scope::default()); // Variables declared in the initializer belong to the loop
+        if let Some(init) = init {
+            self.execute(init)?;
+        }
+        while !self.should_return {
+            if let Some(condition) = condition {
+                if !self.eval_as_bool(condition)? {
+                    break;
+                }
+            }
+            self.execute(body)?;
+            if self.should_return {
+                break; // Skip the update after a `return` in the body
+            }
+            if let Some(update) = update {
+                self.eval_expr(update)?;
+            }
+        }
+        self.pop_scope();
+        Ok(())
+    }
+
+    /// Defines a function for `execute`. A definition with the same name and arity replaces
+    /// the earlier one, and variadic functions are keyed by their number of fixed parameters.
+    fn define_function(&mut self, name: &str, params: &[Param], variadic: bool, body: &Stmt, return_type: &Option<Type>) {
+        let body = if self.implicit_return && *return_type != Some(Type::Void) {
+            with_implicit_return(body.clone())
+        } else {
+            body.clone()
+        };
+        self.functions.insert((name.to_string(), params.len()), Function {
+            name: name.to_string(),
+            params: params.to_vec(),
+            variadic,
+            body: Arc::new(body), // Shared by every call, rather than copied into each
+            return_type: return_type.clone(),
+        });
+    }
+
+    /// Prints a value for `print` or, with `newline`, `println`, for `execute`.
+    fn execute_print(&mut self, value: &Expr, newline: bool) -> Result<(), RuntimeError> {
+        let val = self.eval_expr(value)?;
+        let mut text = self.format_for_print(&val);
+        if newline {
+            text.push('\n'); // `println`; `print` leaves the line open for more output
+        }
+        self.write_output(&text)?;
+        Ok(())
+    }
+
+    /// Executes the statements of a block in a new scope, stopping early at a `return`.
+    fn execute_block(&mut self, stmts: &[Stmt]) -> Result<(), RuntimeError> {
+        self.variables.push(Scope::default());
+        for stmt in stmts {
+            self.execute(stmt)?;
+            if self.should_return {
+                break;
+            }
+        }
+        self.pop_scope();
+        Ok(())
+    }
+
+    /// Formats a value the way `print` shows it. Top-level strings are printed without quotes,
+    /// and arrays longer than `array_print_limit` keep their first elements and the last one.
+    ///
+    /// # Parameters
+    /// - `val`: The value to format.
+    ///
+    /// # Returns
+    /// The text `print` writes for the value, e.g. `[1, 2, 3, 4, ..., 1000]` under a limit of 5.
+    pub(crate) fn format_for_print(&self, val: &Value) -> String {
+        match (val, self.array_print_limit) {
+            (Value::Str(s), _) => s.clone(),
+            (Value::Array(arr), Some(limit)) if arr.len() > limit && limit > 0 => {
+                let mut elements: Vec<String> = arr[..limit - 1].iter().map(|v| v.to_string()).collect();
+                elements.push("...".to_string());
+                elements.push(arr[arr.len() - 1].to_string());
+                format!("[{}]", elements.join(", "))
+            }
+            (other, _) => other.to_string(),
+        }
+    }
+
+    /// Evaluates an expression and returns its result as a `Value`.
+    ///
+    /// # Parameters
+    /// - `expr`: The expression to evaluate.
+    ///
+    /// # Returns
+    /// The evaluated result as a `Value`, or the error that stopped the evaluation.
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        // Every call nests a few frames of this function, so it only dispatches: the work,
+        // and the temporaries of each `?`, go in functions that take stack space only while they run
+        match expr {
+            Expr::Number(n) => Ok(Value::Int(*n)),
+            Expr::Float(x) => Ok(Value::Float(*x)),
+            Expr::Boolean(b) => Ok(Value::Bool(*b)),
+            Expr::Char(c) => Ok(Value::Int(*c as i32)),
+            Expr::StringLiteral(s) => Ok(Value::Str(s.clone())),
+            Expr::Ternary { condition, then_branch, else_branch } => self.eval_ternary(condition, then_branch, else_branch),
+            Expr::Block { stmts, value } => self.eval_block(stmts, value),
+            Expr::AddressOf(expr) => self.eval_address_of(expr),
+            Expr::Deref(expr) => self.eval_deref(expr),
+            Expr::ArrayLiteral(elements) => self.eval_array_literal(elements),
+            Expr::ArrayIndex(array_expr, index_expr) => self.eval_index(array_expr, index_expr),
+            Expr::StructLiteral { name, fields } => self.eval_struct_literal(name, fields),
+            Expr::Slice { target, start, end } => self.eval_slice(target, start, end),
+            Expr::Member(object, field) => self.eval_member(object, field),
+            Expr::Call { callee, args } => self.eval_call_value(callee, args),
+            Expr::PreInc(expr) => self.step_variable(expr, 1, false),
+            Expr::PreDec(expr) => self.step_variable(expr, -1, false),
+            Expr::PostInc(expr) => self.step_variable(expr, 1, true),
+            Expr::PostDec(expr) => self.step_variable(expr, -1, true),
+            Expr::SizeOf(t) => t.size().map(Value::Int).ok_or_else(|| RuntimeError::Other("Type too large for sizeof".to_string())),
+            Expr::SizeOfValue(expr) => self.eval_expr(expr).map(|val| Value::Int(value_size(&val))),
+            Expr::Cast(to_type, expr) => self.eval_expr(expr).and_then(|val| cast(to_type, val)),
+            Expr::Variable(name) => self.lookup(name),
+            Expr::Local { name, depth, slot } => match self.local_mut(*depth, *slot) {
+                Some(val) => Ok(val.clone()),
+                None => self.lookup(name),
+            },
+            Expr::EnumValue(enum_name, variant_name) => self.enum_value(enum_name, variant_name),
+            Expr::BinaryOp { op: BinOp::Assign, left, right } => self.handle_assign(left, right),
+            Expr::BinaryOp { op, left, right } => self.eval_binary(op, left, right),
+            Expr::UnaryOp { op, expr } => self.eval_expr(expr).and_then(|val| apply_unop(op, val)),
+            Expr::FunctionCall { name, args } => self.eval_call(name, args),
+        }
+    }
+
+    /// Evaluates `condition ? then_branch : else_branch`, evaluating only the branch it picks.
+    fn eval_ternary(&mut self, condition: &Expr, then_branch: &Expr, else_branch: &Expr) -> Result<Value, RuntimeError> {
+        if self.eval_as_bool(condition)? {
+            self.eval_expr(then_branch)
+        } else {
+            self.eval_expr(else_branch)
+        }
+    }
+
+    /// Evaluates `*expr`, the value in the cell a pointer refers to.
+    fn eval_deref(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        let pointer = self.eval_expr(expr)?;
+        Ok(self.cell_mut(pointer)?.clone())
+    }
+
+    /// Evaluates a call through an expression, like `f(1)` where `f` holds a function or `(g)(1)`.
+    fn eval_call_value(&mut self, callee: &Expr, args: &[Expr]) -> Result<Value, RuntimeError> {
+        match self.eval_expr(callee)? {
+            Value::Func(name) => self.eval_call(&name, args),
+            other => Err(RuntimeError::TypeMismatch(format!("Cannot call non-function value {}", other))),
+        }
+    }
+
+    /// Evaluates a block expression in a new scope: its statements, then its value.
+    fn eval_block(&mut self, stmts: &[Stmt], value: &Expr) -> Result<Value, RuntimeError> {
+        self.variables.push(Scope::default()); // The block's variables go out of scope with it
+        for stmt in stmts {
+            self.execute(stmt)?;
+        }
+        let result = if self.should_return {
+            self.last_result.clone() // A `return` inside the block also ends the enclosing function
+        } else {
+            self.eval_expr(value)?
+        };
+        self.pop_scope();
+        Ok(result)
+    }
+
+    /// Evaluates `&expr`, the address of a variable.
+    fn eval_address_of(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        match expr {
+            Expr::Variable(name) | Expr::Local { name, .. } => Ok(Value::Ptr(self.address_of(name)?)),
+            _ => Err(RuntimeError::Other("Can only take the address of a variable".to_string())),
+        }
+    }
+
+    /// Evaluates an array literal's elements into an array.
+    fn eval_array_literal(&mut self, elements: &[Expr]) -> Result<Value, RuntimeError> {
+        // Elements are evaluated in a loop, so only nesting (not width) adds recursion depth
+        let evaluated = elements.iter()
+            .map(|e| self.eval_expr(e))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.check_size("array", evaluated.len())?;
+        Ok(Value::Array(evaluated))
+    }
+
+    /// Evaluates `array[index]` on an array, a pointer or a string.
+    fn eval_index(&mut self, array_expr: &Expr, index_expr: &Expr) -> Result<Value, RuntimeError> {
+        let array_val = self.eval_expr(array_expr)?;
+        let idx = match self.eval_expr(index_expr)? {
+            Value::Int(i) => i,
+            other => return Err(RuntimeError::TypeMismatch(format!("Array index must be an integer, got {}", other))),
+        };
+        match array_val {
+            Value::Array(vec) => Ok(vec[checked_index(idx, vec.len())?].clone()),
+            Value::Ptr(addr) => {
+                let element = self.element_index(addr, idx)?; // `p[i]` is `*(p + i)`, within the block
+                Ok(self.memory[element].clone())
+            }
+            Value::Str(s) => match usize::try_from(idx).ok().and_then(|i| s.chars().nth(i)) {
+                Some(c) => Ok(Value::Int(c as i32)), // A character is indexed as its code, like a char literal
+                None => Err(RuntimeError::IndexOutOfBounds { len: s.chars().count(), idx }),
+            },
+            other => Err(RuntimeError::TypeMismatch(format!("Attempted to index non-array value {}", other))),
+        }
+    }
+
+    /// Evaluates a struct literal, filling fields it leaves out with zero values.
+    fn eval_struct_literal(&mut self, name: &str, fields: &[(String, Expr)]) -> Result<Value, RuntimeError> {
+        let layout = match self.structs.get(name) {
+            Some(Type::Struct(_, layout)) => layout.clone(),
+            _ => return Err(RuntimeError::Other(format!("Unknown struct '{}'", name))),
+        };
+        let mut values = Vec::new();
+        for (field, t) in layout { // In declaration order, whatever order the literal used
+            let value = match fields.iter().find(|(f, _)| *f == field) {
+                Some((_, expr)) => self.eval_expr(expr)?,
+                None => zero_value(&t),
+            };
+            values.push((field, value));
+        }
+        Ok(Value::Struct(name.to_string(), values))
+    }
+
+    /// Evaluates `target[start..end]` on an array or a string.
+    fn eval_slice(&mut self, target: &Expr, start: &Expr, end: &Expr) -> Result<Value, RuntimeError> {
+        let target = self.eval_expr(target)?;
+        let bounds = (self.eval_expr(start)?, self.eval_expr(end)?);
+        let (start, end) = match bounds {
+            (Value::Int(start), Value::Int(end)) => (start, end),
+            (start, end) => return Err(RuntimeError::TypeMismatch(format!("Slice bounds must be integers, got {}..{}", start, end))),
+        };
+        let len = match &target {
+            Value::Array(vec) => vec.len(),
+            Value::Str(s) => s.chars().count(),
+            other => return Err(RuntimeError::TypeMismatch(format!("Attempted to slice non-array value {}", other))),
+        };
+        if start > end {
+            return Err(RuntimeError::Other(format!("Slice start {} is greater than its end {}", start, end)));
+        }
+        if start < 0 || end as usize > len {
+            return Err(RuntimeError::Other(format!("Slice {}..{} out of range for length {}", start, end, len)));
+        }
+        let (start, end) = (start as usize, end as usize);
+        Ok(match target {
+            Value::Array(vec) => Value::Array(vec[start..end].to_vec()),
+            Value::Str(s) => Value::Str(s.chars().skip(start).take(end - start).collect()), // By characters, like indexing
+            _ => unreachable!(),
+        })
+    }
+
+    /// Evaluates `object.field` on a struct.
+    fn eval_member(&mut self, object: &Expr, field: &str) -> Result<Value, RuntimeError> {
+        match self.eval_expr(object)? {
+            Value::Struct(name, fields) => match fields.into_iter().find(|(f, _)| f == field) {
+                Some((_, val)) => Ok(val),
+                None => Err(RuntimeError::Other(format!("Struct '{}' has no field '{}'", name, field))),
+            },
+            other => Err(RuntimeError::TypeMismatch(format!("Cannot access field '{}' of non-struct value {}", field, other))),
+        }
+    }
+
+    /// Looks up the value of the enum variant `Enum::Variant`.
+    fn enum_value(&self, enum_name: &str, variant_name: &str) -> Result<Value, RuntimeError> {
+        let key = format!("{}::{}", enum_name, variant_name);
+        match self.constants.get(&key) {
+            Some(i) => Ok(Value::Int(*i)),
+            None => Err(RuntimeError::Other(format!("Enum variant '{}' not found", key))),
+        }
+    }
+
+    /// Evaluates a binary operator other than assignment, such as `i < n`.
+    fn eval_binary(&mut self, op: &BinOp, left: &Expr, right: &Expr) -> Result<Value, RuntimeError> {
+        if matches!(left, Expr::BinaryOp { .. }) {
+            return self.eval_chain(op, left, right);
+        }
+        let l = self.eval_expr(left)?;
+        let r = self.eval_expr(right)?;
+        self.apply_checked_binop(op, l, r)
+    }
+
+    /// Evaluates a chain of binary operators like `a + b - c * d` by walking down its
+    /// left operands, so that long chains are evaluated in a loop instead of recursing once per operator.
+    fn eval_chain(&mut self, op: &BinOp, left: &Expr, right: &Expr) -> Result<Value, RuntimeError> {
+        let mut rights = vec![(op, right)];
+        let mut left = left;
+        while let Expr::BinaryOp { op, left: inner, right } = left {
+            if *op == BinOp::Assign {
+                break;
+            }
+            rights.push((op, &**right));
+            left = &**inner;
+        }
+        let mut acc = self.eval_expr(left)?;
+        for (op, right) in rights.into_iter().rev() {
+            let r = self.eval_expr(right)?;
+            acc = self.apply_checked_binop(op, acc, r)?;
+        }
+        Ok(acc)
+    }
+
+    /// Applies a binary operator for `eval_expr`, checking the size of a string concatenation before building it.
+    fn apply_checked_binop(&mut self, op: &BinOp, l: Value, r: Value) -> Result<Value, RuntimeError> {
+        if let (BinOp::Pow, Value::Ptr(_)) = (op, &r) {
+            // `x**p` lexes as `x ** p`, but a pointer can't be an exponent, so it means `x * *p` as in C
+            let value = self.cell_mut(r)?.clone();
+            return apply_binop(BinOp::Mul, l, value);
+        }
+        if let (Value::Str(l), Value::Str(r)) = (&l, &r) {
+            self.check_size("string", l.len() + r.len())?;
+        }
+        apply_binop(op.clone(), l, r)
+    }
+
+    /// Looks up a name used as a value: a variable, a constant, or a function.
+    fn lookup(&self, name: &str) -> Result<Value, RuntimeError> {
+        if let Some(val) = self.get_variable(name) {
+            return Ok(val.clone());
+        }
+        if let Some(i) = self.constants.get(name) {
+            return Ok(Value::Int(*i));
+        }
+        if self.functions.keys().any(|(n, _)| n == name) {
+            return Ok(Value::Func(name.to_string())); // A function name used as a value
+        }
+        Err(RuntimeError::UndefinedVariable(name.to_string()))
+    }
+
+    /// Evaluates a call to the function `name`, whether user-defined or builtin, with the given arguments.
+    fn eval_call(&mut self, name: &str, args: &[Expr]) -> Result<Value, RuntimeError> {
+        // A variable holding a function value calls that function (e.g., `let f = square; f(3);`)
+        let name = match self.get_variable(name) {
+            Some(Value::Func(target)) => target.clone(),
+            _ => name.to_string(),
+        };
+
+        // Remember which arguments are plain variables, for by-reference parameters
+        let arg_names: Vec<Option<&String>> = args.iter().map(|arg| match arg {
+            Expr::Variable(var) | Expr::Local { name: var, .. } => Some(var),
+            _ => None,
+        }).collect();
+
+        let arg_values = args.iter().map(|arg| self.eval_expr(arg)).collect::<Result<Vec<_>, _>>()?;
+
+        // Fall back to builtins when no user-defined function matches the call
+        match self.find_function(&name, arg_values.len()) {
+            Ok(function) => self.call_with_refs(&function, &arg_names, arg_values),
+            Err(err) => self.call_builtin_fallback(&name, &arg_names, arg_values, err),
+        }
+    }
+
+    /// Calls a builtin for `eval_call` when no user-defined function matches, failing with
+    /// `err` (the reason no function matched) if there is no such builtin either.
+    fn call_builtin_fallback(
+        &mut self,
+        name: &str,
+        arg_names: &[Option<&String>],
+        arg_values: Vec<Value>,
+        err: RuntimeError,
+    ) -> Result<Value, RuntimeError> {
+        if !is_mutating_builtin(name) {
+            return self.call_builtin(name, arg_values)?.ok_or(err);
+        }
+        // `pop(arr)` and `push(arr, v)` change the array variable in place
+        let var = match arg_names.first() {
+            Some(Some(var)) => *var,
+            _ => return Err(RuntimeError::Other(format!(
+                "The array passed to '{}' must be a variable ({})", name, err
+            ))),
+        };
+        self.check_not_constant(var)?;
+        let (result, array) = self.call_mutating_builtin(name, arg_values)?;
+        self.assign_variable(var, array);
+        Ok(result)
+    }
+
+    /// Calls a user-defined function for `eval_call`, copying its `ref` parameters back into
+    /// the caller's variables named in `arg_names` when it returns.
+    fn call_with_refs(&mut self, function: &Function, arg_names: &[Option<&String>], arg_values: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.check_ref_arguments(function, arg_names)?;
+        let (result, callee_scope) = self.invoke(function, arg_values)?;
+        self.copy_back_refs(function, arg_names, &callee_scope);
+        self.release_cells(&callee_scope);
+        Ok(result)
+    }
+
+    /// Checks that the argument for each `ref` parameter is a variable that isn't a constant.
+    fn check_ref_arguments(&self, function: &Function, arg_names: &[Option<&String>]) -> Result<(), RuntimeError> {
+        for (param, arg_name) in function.params.iter().zip(arg_names) {
+            if param.by_ref && arg_name.is_none() {
+                return Err(RuntimeError::Other(format!(
+                    "Argument for 'ref' parameter '{}' of '{}' must be a variable", param.name, function.name
+                )));
+            }
+            if let (true, Some(var)) = (param.by_ref, arg_name) {
+                self.check_not_constant(var)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the final values of `ref` parameters from the callee's scope back into the caller's variables.
+    fn copy_back_refs(&mut self, function: &Function, arg_names: &[Option<&String>], callee_scope: &Scope) {
+        for (param, arg_name) in function.params.iter().zip(arg_names) {
+            if let (true, Some(var)) = (param.by_ref, arg_name) {
+                if let Some(val) = callee_scope.get(&param.name).and_then(|slot| self.slot_value(slot)) {
+                    self.assign_variable(var, val.clone());
+                }
+            }
+        }
+    }
+
+    /// Calls a user-defined function from the host program, e.g. after parsing a script:
+    /// `vm.call_function("add", vec![Value::Int(2), Value::Int(3)])`.
+    ///
+    /// # Parameters
+    /// - `name`: The name of the function to call.
+    /// - `args`: The argument values. Arguments for `ref` parameters are passed by value,
+    ///   since there is no caller variable to copy them back into.
+    ///
+    /// # Returns
+    /// The function's return value, or an error if there is no such function, the number
+    /// of arguments doesn't match its parameters, or the function fails while running.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let function = self.find_function(name, args.len())?;
+        let (result, callee_scope) = self.invoke(&function, args)?;
+        self.release_cells(&callee_scope);
+        Ok(result)
+    }
+
+    /// Looks up the user-defined function a call refers to, by name and argument count.
+    ///
+    /// # Parameters
+    /// - `name`: The name of the function.
+    /// - `count`: The number of arguments in the call.
+    ///
+    /// # Returns
+    /// The matching function, or an error if no function has that name or none
+    /// of the functions with that name take `count` arguments.
+    fn find_function(&self, name: &str, count: usize) -> Result<Function, RuntimeError> {
+        if let Some(function) = self.functions.get(&(name.to_string(), count)) {
+            return Ok(function.clone());
+        }
+        let mut overloads: Vec<&Function> = self.functions.values().filter(|f| f.name == name).collect();
+        overloads.sort_by_key(|f| f.params.len());
+        // Otherwise, the overload with the most parameters that accepts this many arguments,
+        // leaving out parameters with defaults or passing extras to a variadic function
+        if let Some(function) = overloads.iter().rev().find(|f| f.accepts(count)) {
+            return Ok((*function).clone());
+        }
+        let arities: Vec<String> = overloads.iter().map(|f| f.arity()).collect();
+        let expected = match arities.split_last() {
+            None => return Err(RuntimeError::UndefinedFunction(name.to_string())),
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        };
+        Err(RuntimeError::ArgumentCount { function: name.to_string(), expected, got: count })
+    }
+
+    /// Runs a user-defined function's body in a new scope holding its arguments.
+    /// Fails with `RuntimeError::RecursionLimitExceeded` if `max_call_depth` calls are already in progress.
+    ///
+    /// # Parameters
+    /// - `function`: The function to run.
+    /// - `arg_values`: The argument values, one per parameter.
+    ///
+    /// # Returns
+    /// The function's return value, and its scope as it was when the function returned.
+    fn invoke(&mut self, function: &Function, arg_values: Vec<Value>) -> Result<(Value, Scope), RuntimeError> {
+        if self.call_depth >= self.max_call_depth {
+            return Err(RuntimeError::RecursionLimitExceeded { limit: self.max_call_depth });
+        }
+        self.call_depth += 1;
+        let result = if self.call_depth.is_multiple_of(CALLS_PER_STACK) {
+            self.invoke_on_new_stack(function, arg_values)
+        } else {
+            self.invoke_frame(function, arg_values)
+        };
+        self.call_depth -= 1; // Also when the call fails, since `try` may catch the error
+        result
+    }
+
+    /// Runs a call for `invoke` on a new thread, so that deep recursion continues on a fresh
+    /// native stack instead of overflowing the current one. A panic in the call is passed on.
+    fn invoke_on_new_stack(&mut self, function: &Function, arg_values: Vec<Value>) -> Result<(Value, Scope), RuntimeError> {
+        thread::scope(|scope| {
+            let call = thread::Builder::new()
+                .stack_size(CALL_STACK_SIZE)
+                .spawn_scoped(scope, || self.invoke_frame(function, arg_values))
+                .map_err(|e| RuntimeError::Other(format!("Failed to start a thread for a nested call: {}", e)))?;
+            call.join().unwrap_or_else(|payload| panic::resume_unwind(payload))
+        })
+    }
+
+    /// Runs a call for `invoke`, once the call depth has been checked.
+    fn invoke_frame(&mut self, function: &Function, arg_values: Vec<Value>) -> Result<(Value, Scope), RuntimeError> {
+        self.variables.push(Scope::default());
+        self.bind_arguments(function, arg_values)?;
+
+        let prev_result = self.last_result.clone();
+        let prev_should_return = self.should_return;
+        let prev_line = self.current_line;
+        self.last_result = Value::Int(0);
+        self.should_return = false;
+
+        self.execute(&function.body)?;
+
+        let result = std::mem::replace(&mut self.last_result, prev_result);
+        let callee_scope = self.variables.pop().unwrap();
+        self.forget_names(&callee_scope);
+        self.should_return = prev_should_return;
+        self.current_line = prev_line; // Back to the caller's statement
+        Ok((result, callee_scope))
+    }
+
+    /// Declares a function's parameters in the callee's scope for `invoke_frame`: the arguments,
+    /// the defaults of omitted trailing parameters, and `args` for a variadic function.
+    fn bind_arguments(&mut self, function: &Function, mut arg_values: Vec<Value>) -> Result<(), RuntimeError> {
+        let fixed = arg_values.len().min(function.params.len());
+        let extra = arg_values.split_off(fixed); // Arguments beyond the fixed parameters
+        let given = arg_values.len();
+        for (param, val) in function.params.iter().zip(arg_values) {
+            self.declare(param.name.clone(), val);
+        }
+        // Fill in omitted trailing arguments from their defaults, in the callee's scope
+        for param in &function.params[given..] {
+            let default = match &param.default {
+                Some(default) => default,
+                None => return Err(RuntimeError::Other(format!(
+                    "Missing argument for parameter '{}' of '{}'", param.name, function.name
+                ))),
+            };
+            let val = self.eval_expr(default)?;
+            self.declare(param.name.clone(), val);
+        }
+        if function.variadic {
+            self.declare("args".to_string(), Value::Array(extra));
+        }
+        Ok(())
+    }
+
+    /// Handles assignment operations for variables and array indices.
+    ///
+    /// # Parameters
+    /// - `left`: The left-hand side expression (either a variable or an array index).
+    /// - `right`: The value to assign.
+    ///
+    /// # Returns
+    /// The value that was assigned to the left-hand side.
+    fn handle_assign(&mut self, left: &Expr, right: &Expr) -> Result<Value, RuntimeError> {
+        // Each kind of target is assigned in its own function, keeping this frame small (see `eval_expr`)
+        match left {
+            Expr::Variable(name) => self.assign_to_variable(name, right),
+            Expr::Local { name, depth, slot } => self.assign_to_local(name, *depth, *slot, right),
+            Expr::ArrayIndex(..) | Expr::Member(..) => self.assign_to_place(left, right),
+            Expr::Deref(pointer_expr) => self.assign_through_pointer(pointer_expr, right),
+            _ => Err(RuntimeError::Other("Left-hand side of assignment must be a variable, array element or struct field".to_string())),
+        }
+    }
+
+    /// Assigns the value of `right` to the variable `name`, for `handle_assign`.
+    fn assign_to_variable(&mut self, name: &str, right: &Expr) -> Result<Value, RuntimeError> {
+        self.check_not_constant(name)?;
+        let val = self.eval_expr(right)?;
+        self.assign_variable(name, val.clone());
+        Ok(val)
+    }
+
+    /// Assigns the value of `right` to a variable the resolver located, for `handle_assign`.
+    fn assign_to_local(&mut self, name: &str, depth: usize, slot: usize, right: &Expr) -> Result<Value, RuntimeError> {
+        let val = self.eval_expr(right)?; // A located variable is never a global, so never a constant
+        match self.local_mut(depth, slot) {
+            Some(local) => *local = val.clone(),
+            None => self.assign_variable(name, val.clone()),
+        }
+        Ok(val)
+    }
+
+    /// Assigns the value of `right` to an array element or struct field, for `handle_assign`.
+    fn assign_to_place(&mut self, left: &Expr, right: &Expr) -> Result<Value, RuntimeError> {
+        let (name, path) = self.resolve_place(left)?; // Indices are evaluated before the value
+        self.check_not_constant(&name)?;
+        let val = self.eval_expr(right)?;
+        *self.place_mut(&name, &path)? = val.clone();
+        Ok(val)
+    }
+
+    /// Assigns the value of `right` to the cell a pointer refers to, as in `*p = 5;`, for `handle_assign`.
+    fn assign_through_pointer(&mut self, pointer_expr: &Expr, right: &Expr) -> Result<Value, RuntimeError> {
+        let pointer = self.eval_expr(pointer_expr)?;
+        let val = self.eval_expr(right)?;
+        *self.cell_mut(pointer)? = val.clone(); // `*p = 5;` writes to the variable `p` points to
+        Ok(val)
+    }
+
+    /// Splits an assignment target such as `points[i].x` into the variable it starts from and
+    /// the indices and fields leading from it to the assigned element, evaluating the indices.
+    fn resolve_place(&mut self, target: &Expr) -> Result<(String, Vec<Access>), RuntimeError> {
+        match target {
+            Expr::Variable(name) | Expr::Local { name, .. } => Ok((name.clone(), Vec::new())),
+            Expr::ArrayIndex(array_expr, index_expr) => {
+                let (name, mut path) = self.resolve_place(array_expr)?;
+                match self.eval_expr(index_expr)? {
+                    Value::Int(i) => path.push(Access::Index(i)),
+                    other => return Err(RuntimeError::TypeMismatch(format!("Array index must be an integer, got {}", other))),
+                }
+                Ok((name, path))
+            }
+            Expr::Member(object, field) => {
+                let (name, mut path) = self.resolve_place(object)?;
+                path.push(Access::Field(field.clone()));
+                Ok((name, path))
+            }
+            _ => Err(RuntimeError::Other("Left-hand side of assignment must be a variable, array element or struct field".to_string())),
+        }
+    }
+
+    /// Returns the element of the innermost variable `name` that `path` leads to, for assignment.
+    fn place_mut(&mut self, name: &str, path: &[Access]) -> Result<&mut Value, RuntimeError> {
+        // Indexing a pointer (`p[0]`) continues in memory, so find where the last such step lands first
+        let mut start = None;
+        let mut val = self.get_variable(name);
+        for (i, access) in path.iter().enumerate() {
+            val = match (access, val) {
+                (Access::Index(idx), Some(Value::Ptr(addr))) => {
+                    let element = self.element_index(*addr, *idx)?;
+                    start = Some((element, i + 1));
+                    self.memory.get(element)
+                }
+                (Access::Index(idx), Some(Value::Array(vec))) => usize::try_from(*idx).ok().and_then(|i| vec.get(i)),
+                (Access::Field(field), Some(Value::Struct(_, fields))) => fields.iter().find(|(f, _)| f == field).map(|(_, v)| v),
+                _ => None, // Errors are reported below
+            };
+        }
+        let (mut slot, path) = match start {
+            Some((element, rest)) => (&mut self.memory[element], &path[rest..]),
+            None => match self.variable_mut(name) {
+                Some(val) => (val, path),
+                None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
+            },
+        };
+        for access in path {
+            slot = match (access, slot) {
+                (Access::Index(idx), Value::Array(vec)) => {
+                    let i = checked_index(*idx, vec.len())?;
+                    &mut vec[i]
+                }
+                (Access::Index(_), other) => return Err(RuntimeError::TypeMismatch(format!(
+                    "Attempted to index non-array value {}", other
+                ))),
+                (Access::Field(field), Value::Struct(struct_name, fields)) => match fields.iter_mut().find(|(f, _)| f == field) {
+                    Some((_, val)) => val,
+                    None => return Err(RuntimeError::Other(format!("Struct '{}' has no field '{}'", struct_name, field))),
+                },
+                (Access::Field(field), other) => return Err(RuntimeError::TypeMismatch(format!(
+                    "Cannot assign to field '{}' of non-struct value {}", field, other
+                ))),
+            };
+        }
+        Ok(slot)
+    }
+
+    /// Increments or decrements a variable in place, for the `++` and `--` operators.
+    ///
+    /// # Parameters
+    /// - `expr`: The operand, which must be a variable, a dereference (`(*p)++`), an array element
+    ///   (`a[i]++`) or a struct field (`p.x++`).
+    /// - `delta`: The amount to add to the variable (1 for `++`, -1 for `--`).
+    /// - `postfix`: Whether to return the value from before the update (`x++`) instead of after (`++x`).
+    ///
+    /// # Returns
+    /// The variable's value before or after the update.
+    fn step_variable(&mut self, expr: &Expr, delta: i32, postfix: bool) -> Result<Value, RuntimeError> {
+        let op = if delta > 0 { "++" } else { "--" };
+        // The operand, with what it is and its name for error messages
+        let (variable, kind, name) = match expr {
+            Expr::Deref(pointer) => { // `(*p)++` steps the cell the pointer refers to
+                let pointer = self.eval_expr(pointer)?;
+                (Some(self.cell_mut(pointer)?), "value", "(*pointer)")
+            }
+            Expr::Local { name, depth, slot } if self.local_mut(*depth, *slot).is_some() => {
+                (self.local_mut(*depth, *slot), "variable", name.as_str())
+            }
+            Expr::Variable(name) | Expr::Local { name, .. } => {
+                self.check_not_constant(name)?;
+                (self.variable_mut(name), "variable", name.as_str())
+            }
+            Expr::ArrayIndex(..) | Expr::Member(..) => { // `a[i]++` and `p.x++` step the element in place
+                let (name, path) = self.resolve_place(expr)?;
+                self.check_not_constant(&name)?;
+                let description = if matches!(expr, Expr::ArrayIndex(..)) { "array[index]" } else { "struct.field" };
+                (Some(self.place_mut(&name, &path)?), "element", description)
+            }
+            _ => return Err(RuntimeError::Other(format!("{} requires a variable", op))),
+        };
+        match variable {
+            Some(Value::Int(i)) => {
+                let original = *i;
+                *i = original.checked_add(delta)
+                    .ok_or_else(|| {
+                        let step = if postfix { format!("{}{}", name, op) } else { format!("{}{}", op, name) };
+                        RuntimeError::Other(format!("Integer overflow in {}", step))
+                    })?;
+                Ok(Value::Int(if postfix { original } else { *i }))
+            }
+            Some(Value::Ptr(addr)) => {
+                let original = *addr;
+                *addr = offset_pointer(original, i64::from(delta))?; // One element, like `p + 1`
+                Ok(Value::Ptr(if postfix { original } else { *addr }))
+            }
+            Some(_) => Err(RuntimeError::TypeMismatch(format!(
+                "Cannot apply '{}' to non-integer {} '{}'", op, kind, name
+            ))),
+            None => Err(RuntimeError::UndefinedVariable(name.to_string())),
+        }
+    }
+
+    /// Assigns a value to the innermost variable with the given name,
+    /// declaring it in the current scope if it doesn't exist yet.
+    ///
+    /// # Parameters
+    /// - `name`: The name of the variable.
+    /// - `val`: The value to assign.
+    fn assign_variable(&mut self, name: &str, val: Value) {
+        match self.variable_mut(name) {
+            Some(slot) => *slot = val,
+            None => self.declare(name.to_string(), val), // Only a new variable needs its own copy of the name
+        }
+    }
+
+    /// Evaluates an expression and returns its result as a boolean value.
+    ///
+    /// # Parameters
+    /// - `expr`: The expression to evaluate.
+    ///
+    /// # Returns
+    /// A boolean value (`true` or `false`).
+    fn eval_as_bool(&mut self, expr: &Expr) -> Result<bool, RuntimeError> {
+        Ok(self.eval_expr(expr)?.is_truthy())
+    }
+}
+
+/// Converts an integer to a `char` for `(char)`: the value modulo 256, in `0..256`, as an
+/// `unsigned char` in C. Negative values wrap around from 256 (`(char)-1` is `255`) and larger
+/// ones keep their lowest byte (`(char)300` is `44`).
+fn to_char(i: i32) -> i32 {
+    i.rem_euclid(256)
+}
+
+/// Converts a string to an integer for `(int)`: a decimal number with an optional sign and
+/// surrounding whitespace (e.g., `" -42 "`) gives its value, and anything else, including a
+/// number too large for an `int`, gives `0`.
+fn parse_int(s: &str) -> i32 {
+    s.trim().parse().unwrap_or(0)
+}
+
+/// Returns the position of element `idx` of an array (or block) of `len` elements,
+/// or an error if the index is negative or past the end.
+fn checked_index(idx: i32, len: usize) -> Result<usize, RuntimeError> {
+    match usize::try_from(idx) {
+        Ok(i) if i < len => Ok(i),
+        _ => Err(RuntimeError::IndexOutOfBounds { len, idx }),
+    }
+}
+
+/// Moves a pointer by `delta` cells, for `p + 1`, `p - 1` and `p++`, returning the new address.
+fn offset_pointer(addr: usize, delta: i64) -> Result<usize, RuntimeError> {
+    match (addr as i64).checked_add(delta) {
+        Some(moved) if moved >= 0 => Ok(moved as usize),
+        _ => Err(RuntimeError::Other(format!("Pointer arithmetic out of range: {} + {}", addr, delta))),
+    }
+}
+
+/// One step from a variable to an element of it in an assignment target: an array index or a struct field.
+enum Access {
+    Index(i32),
+    Field(String),
+}
+
+/// Builds a struct value from the elements of an initializer list, in field order.
+/// Fields without an initializer are zeroed, as in C.
+fn init_struct(name: &str, fields: &[(String, Type)], elements: &[Value]) -> Result<Value, RuntimeError> {
+    if elements.len() > fields.len() {
+        return Err(RuntimeError::Other(format!(
+            "Too many initializers for struct '{}': it has {} fields, got {}", name, fields.len(), elements.len()
+        )));
+    }
+    let mut values = elements.iter().cloned();
+    let fields = fields.iter()
+        .map(|(field, t)| (field.clone(), values.next().unwrap_or_else(|| zero_value(t))))
+        .collect();
+    Ok(Value::Struct(name.to_string(), fields))
+}
+
+/// Zero-pads an array initializer to its declared type, including the rows of a nested array
+/// (e.g., `{{1, 2}, {3}}` for `int[2][3]`), for `let`.
+///
+/// # Errors
+/// An error naming `name` if the initializer has more elements than its type holds.
+fn pad_array(name: &str, array_type: &Type, val: Value) -> Result<Value, RuntimeError> {
+    match (array_type, val) {
+        (Type::Array(inner, size), Value::Array(elements)) => {
+            if elements.len() > *size {
+                return Err(RuntimeError::Other(format!(
+                    "Too many initializers for '{}': declared size {}, got {}", name, size, elements.len()
+                )));
+            }
+            let mut padded = elements.into_iter().map(|e| pad_array(name, inner, e)).collect::<Result<Vec<_>, _>>()?;
+            padded.resize(*size, zero_value(inner));
+            Ok(Value::Array(padded))
+        }
+        (_, val) => Ok(val),
+    }
+}
+
+/// Returns the size in bytes of a value whose type wasn't declared, for `sizeof(variable)`:
+/// numbers are `int`s or `float`s, strings and functions are pointers, and arrays and structs
+/// are the sum of their elements' sizes.
+fn value_size(val: &Value) -> i32 {
+    match val {
+        Value::Int(_) | Value::Bool(_) => 4, // The size of an `int`
+        Value::Float(_) => 4, // The size of a `float`
+        Value::Str(_) | Value::Func(_) | Value::Ptr(_) => 8, // The size of a pointer
+        Value::Array(elements) => elements.iter().map(value_size).sum(),
+        Value::Struct(_, fields) => fields.iter().map(|(_, v)| value_size(v)).sum(),
+    }
+}
+
+/// Returns the zero value of a type: `0`, `0.0`, `""`, or an array or struct of zeroes.
+fn zero_value(t: &Type) -> Value {
+    match t {
+        Type::Float => Value::Float(0.0),
+        Type::Pointer(inner) if **inner == Type::Char => Value::Str(String::new()),
+        Type::Array(inner, size) => Value::Array(vec![zero_value(inner); *size]),
+        Type::Struct(name, fields) => Value::Struct(name.clone(), fields.iter().map(|(f, t)| (f.clone(), zero_value(t))).collect()),
+        _ => Value::Int(0),
+    }
+}
+
+/// Turns the final expression statement of a function body into a `return`, for `Vm::implicit_return`.
+/// A body ending in any other statement is left as it is.
+fn with_implicit_return(stmt: Stmt) -> Stmt {
+    match stmt {
+        Stmt::Located { line, stmt } => Stmt::Located { line, stmt: Box::new(with_implicit_return(*stmt)) },
+        Stmt::Block(mut stmts) => {
+            if let Some(last) = stmts.pop() {
+                stmts.push(with_implicit_return(last));
+            }
+            Stmt::Block(stmts)
+        }
+        Stmt::ExprStmt(expr) => Stmt::Return(expr),
+        other => other,
+    }
+}
+
+/// Applies a unary operator (`!`, `-` or `~`) to an evaluated operand.
+fn apply_unop(op: &UnOp, val: Value) -> Result<Value, RuntimeError> {
+    Ok(match op {
+        UnOp::Not => Value::Bool(!val.is_truthy()),
+        UnOp::Neg => match val {
+            Value::Int(i) => match i.checked_neg() {
+                Some(n) => Value::Int(n),
+                None => return Err(RuntimeError::Other(format!("Integer overflow negating {}", i))),
+            },
+            Value::Float(x) => Value::Float(-x),
+            other => return Err(RuntimeError::TypeMismatch(format!("Cannot negate non-numeric value {}", other))),
+        },
+        UnOp::BitNot => match val {
+            Value::Int(i) => Value::Int(!i),
+            other => return Err(RuntimeError::TypeMismatch(format!("Cannot apply '~' to non-integer value {}", other))),
+        },
+    })
+}
+
+/// Converts an evaluated value for a cast like `(int)x`.
+fn cast(to_type: &Type, val: Value) -> Result<Value, RuntimeError> {
+    Ok(match (to_type, val) {
+        (Type::Int, Value::Int(i)) => Value::Int(i),
+        (Type::Int, Value::Float(x)) => Value::Int(x as i32), // Truncates toward zero, as in C
+        (Type::Int, Value::Bool(b)) => Value::Int(b as i32),
+        (Type::Char, Value::Int(i)) => Value::Int(to_char(i)),
+        (Type::Float, Value::Int(i)) => Value::Float(f64::from(i)),
+        (Type::Float, Value::Float(x)) => Value::Float(x),
+        (Type::Int, Value::Str(s)) => Value::Int(parse_int(&s)),
+        (Type::Char, Value::Str(s)) => Value::Int(to_char(parse_int(&s))),
+        (Type::Int, Value::Ptr(addr)) => Value::Int(addr as i32), // The address as a number
+        (Type::Pointer(_), Value::Int(i)) if i >= 0 => Value::Ptr(i as usize),
+        (Type::Pointer(_), Value::Ptr(addr)) => Value::Ptr(addr),
+        (_, v) => return Err(RuntimeError::TypeMismatch(format!("Unsupported cast: {} to {}", v, to_type))),
+    })
+}
+
+/// Applies a binary operator (other than assignment) to two evaluated operands.
+///
+/// # Parameters
+/// - `op`: The operator to apply.
+/// - `l`: The value of the left operand.
+/// - `r`: The value of the right operand.
+///
+/// # Returns
+/// The result of the operation.
+fn apply_binop(op: BinOp, l: Value, r: Value) -> Result<Value, RuntimeError> {
+    if op == BinOp::And || op == BinOp::Or { // Logical operators work on any values, by truthiness
+        let (l, r) = (l.is_truthy(), r.is_truthy());
+        return Ok(Value::Bool(if op == BinOp::And { l && r } else { l || r }));
+    }
+    Ok(match (l, r) {
+        (Value::Int(li), Value::Int(ri)) => match op {
+            BinOp::Add => checked_int(op, li, ri, li.checked_add(ri))?,
+            BinOp::Sub => checked_int(op, li, ri, li.checked_sub(ri))?,
+            BinOp::Mul => checked_int(op, li, ri, li.checked_mul(ri))?,
+            BinOp::Div => {
+                if ri == 0 {
+                    return Err(RuntimeError::DivisionByZero);
+                }
+                checked_int(op, li, ri, li.checked_div(ri))? // Only `i32::MIN / -1` overflows
+            }
+            BinOp::Mod => {
+                if ri == 0 {
+                    return Err(RuntimeError::ModuloByZero);
+                }
+                checked_int(op, li, ri, li.checked_rem(ri))?
+            }
+            BinOp::Pow => {
+                if ri < 0 {
+                    return Err(RuntimeError::Other(format!("Negative exponent in integer power: {} ** {}", li, ri)));
+                }
+                match li.checked_pow(ri as u32) {
+                    Some(n) => Value::Int(n),
+                    None => return Err(RuntimeError::Other(format!("Integer overflow in {} ** {}", li, ri))),
+                }
+            }
+            BinOp::Equal => Value::Bool(li == ri),
+            BinOp::NotEqual => Value::Bool(li != ri),
+            BinOp::LessThan => Value::Bool(li < ri),
+            BinOp::GreaterThan => Value::Bool(li > ri),
+            BinOp::LessEqual => Value::Bool(li <= ri),
+            BinOp::GreaterEqual => Value::Bool(li >= ri),
+            BinOp::BitAnd => Value::Int(li & ri),
+            BinOp::BitOr => Value::Int(li | ri),
+            BinOp::BitXor => Value::Int(li ^ ri),
+            // A shift by a negative amount or by 32 or more bits is out of range
+            BinOp::Shl => checked_int(op, li, ri, u32::try_from(ri).ok().and_then(|n| li.checked_shl(n)))?,
+            BinOp::Shr => checked_int(op, li, ri, u32::try_from(ri).ok().and_then(|n| li.checked_shr(n)))?,
+            _ => unreachable!(),
+        },
+        (Value::Str(ls), Value::Str(rs)) => match op {
+            BinOp::Add => Value::Str(ls + &rs),
+            BinOp::Equal => Value::Bool(ls == rs),
+            BinOp::NotEqual => Value::Bool(ls != rs),
+            // Strings are ordered lexicographically by character, like `strcmp`
+            BinOp::LessThan => Value::Bool(ls < rs),
+            BinOp::GreaterThan => Value::Bool(ls > rs),
+            BinOp::LessEqual => Value::Bool(ls <= rs),
+            BinOp::GreaterEqual => Value::Bool(ls >= rs),
+            _ => return Err(RuntimeError::TypeMismatch(format!("Unsupported string operation: '{}'", op))),
+        },
+        // Booleans only compare for equality; arithmetic on them is a type error
+        (Value::Bool(lb), Value::Bool(rb)) => match op {
+            BinOp::Equal => Value::Bool(lb == rb),
+            BinOp::NotEqual => Value::Bool(lb != rb),
+            _ => return Err(RuntimeError::TypeMismatch(format!("Unsupported boolean operation: '{}'", op))),
+        },
+        // Pointers compare by address and move by whole cells (e.g., `p + 1`)
+        (Value::Ptr(l), Value::Ptr(r)) => match op {
+            BinOp::Sub => Value::Int(l as i32 - r as i32),
+            BinOp::Equal | BinOp::NotEqual | BinOp::LessThan | BinOp::GreaterThan | BinOp::LessEqual | BinOp::GreaterEqual => {
+                apply_binop(op, Value::Int(l as i32), Value::Int(r as i32))?
+            }
+            _ => return Err(RuntimeError::TypeMismatch(format!("Unsupported pointer operation: '{}'", op))),
+        },
+        (Value::Ptr(p), Value::Int(i)) | (Value::Int(i), Value::Ptr(p)) if op == BinOp::Add => Value::Ptr(offset_pointer(p, i64::from(i))?),
+        (Value::Ptr(p), Value::Int(i)) if op == BinOp::Sub => Value::Ptr(offset_pointer(p, -i64::from(i))?),
+        // Mixed int and float operands are promoted to floats
+        (Value::Float(lf), Value::Float(rf)) => apply_float_binop(op, lf, rf)?,
+        (Value::Int(li), Value::Float(rf)) => apply_float_binop(op, f64::from(li), rf)?,
+        (Value::Float(lf), Value::Int(ri)) => apply_float_binop(op, lf, f64::from(ri))?,
+        (l, r) => return Err(RuntimeError::TypeMismatch(format!("Mismatched types for operation '{}': {} and {}", op, l, r))),
+    })
+}
+
+/// Wraps the result of a checked integer operation, reporting `None` as an overflow of `l op r`.
+fn checked_int(op: BinOp, l: i32, r: i32, result: Option<i32>) -> Result<Value, RuntimeError> {
+    result.map(Value::Int).ok_or_else(|| RuntimeError::Other(format!("Integer overflow in {} {} {}", l, op, r)))
+}
+
+/// Applies a binary operator to two floats, following IEEE 754: comparisons involving NaN
+/// are false (so NaN is not even equal to itself, and `!=` is true), and `-0.0 == 0.0`.
+fn apply_float_binop(op: BinOp, l: f64, r: f64) -> Result<Value, RuntimeError> {
+    Ok(match op {
+        BinOp::Add => Value::Float(l + r),
+        BinOp::Sub => Value::Float(l - r),
+        BinOp::Mul => Value::Float(l * r),
+        BinOp::Div => Value::Float(l / r), // Division by zero gives infinity or NaN
+        BinOp::Mod => Value::Float(l % r),
+        BinOp::Pow => Value::Float(l.powf(r)),
+        BinOp::Equal => Value::Bool(l == r),
+        BinOp::NotEqual => Value::Bool(l != r),
+        BinOp::LessThan => Value::Bool(l < r),
+        BinOp::GreaterThan => Value::Bool(l > r),
+        BinOp::LessEqual => Value::Bool(l <= r),
+        BinOp::GreaterEqual => Value::Bool(l >= r),
+        _ => return Err(RuntimeError::TypeMismatch(format!("Unsupported float operation: '{}'", op))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::{ParseError, Parser};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// A global allocator that counts the heap allocations each thread makes, so tests can
+    /// check what running a program allocates.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1)); // Not while the thread is exiting
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the number of heap allocations `f` makes on the current thread.
+    fn count_allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    /// Helper function to run a piece of C4 code and return the result.
+    ///
+    /// # Parameters
+    /// - `code`: A string containing the C4 code to execute.
+    ///
+    /// # Returns
+    /// The integer result of executing the C4 code.
+    fn run(code: &str) -> i32 {
+        let mut vm = Vm::new();
+        vm.run(code).unwrap_or_else(|err| panic!("{}", err));
+        vm.get_result()
+    }
+
+    /// Parses a program that should be rejected and returns its syntax error.
+    fn parse_error(code: &str) -> ParseError {
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(Lexer::new(code), &mut vm);
+        parser.parse().expect_err("expected a syntax error")
+    }
+
+    /// Runs a program that should fail at runtime and returns its error.
+    fn run_error(code: &str) -> RuntimeError {
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(Lexer::new(code), &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        for stmt in stmts {
+            if let Err(err) = vm.execute(&stmt) {
+                return err;
+            }
+        }
+        panic!("expected a runtime error")
+    }
+
+    /// Tests basic arithmetic operations such as addition and multiplication.
+    #[test]
+    fn test_arithmetic() {
+        let code = "return 2 + 3 * 4;";
+        assert_eq!(run(code), 14);
+    }
+
+    /// Tests variable assignment and using those variables in calculations.
+    #[test]
+    fn test_variable_assignment() {
+        let code = "let x = 10; let y = x + 5; return y;";
+        assert_eq!(run(code), 15);
+    }
+
+    /// Tests function call with a single parameter and return value.
+    #[test]
+    fn test_function_call() {
+        let code = "
+            int square(n) {
+                return n * n;
+            }
+            let result = square(6);
+            return result;
+        ";
+        assert_eq!(run(code), 36);
+    }
+
+    /// Tests scope isolation in functions and variables.
+    #[test]
+    fn test_scope_isolation() {
+        let code = "
+            int test(x) { return x + 1; }
+            let x = 5;
+            let y = test(10);
+            return x + y;
+        ";
+        assert_eq!(run(code), 16);
+    }
+
+    /// Tests recursion in functions, specifically calculating a factorial.
+    #[test]
+    fn test_recursion() {
+        let code = "
+            int factorial(n) {
+                if (n == 0) {
+                    return 1;
+                } else {
+                    return n * factorial(n - 1);
+                }
+            }
+            let result = factorial(5);
+            return result;
+        ";
+        assert_eq!(run(code), 120);
+    }
+
+    /// Tests if-else conditionals with basic true/false evaluations.
+    #[test]
+    fn test_if_else() {
+        let code = "
+            let x = 10;
+            if (x < 5) {
+                return 0;
+            } else {
+                return 1;
+            }
+        ";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests while loop for sum calculation with a break condition.
+    #[test]
+    fn test_while_loop() {
+        let code = "
+            let i = 0;
+            let sum = 0;
+            while (i < 5) {
+                sum = sum + i;
+                i = i + 1;
+            }
+            return sum;
+        ";
+        assert_eq!(run(code), 10);
+    }
+
+    /// Tests nested if-else statements.
+    #[test]
+    fn test_nested_if_else() {
+        let code = "
+            let x = 10;
+            if (x > 5) {
+                if (x < 15) {
+                    return 1;
+                } else {
+                    return 2;
+                }
+            } else {
+                return 0;
+            }
+        ";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests nested while loops for a sum calculation.
+    #[test]
+    fn test_nested_while_loops() {
+        let code = "
+            let i = 0;
+            let sum = 0;
+            while (i < 3) {
+                let j = 0;
+                while (j < 2) {
+                    sum = sum + i + j;
+                    j = j + 1;
+                }
+                i = i + 1;
+            }
+            return sum;
+        ";
+        assert_eq!(run(code), 9);
+    }
+
+    /// Tests function call with multiple parameters.
+    #[test]
+    fn test_function_multiple_params() {
+        let code = "
+            int add(a, b, c) {
+                return a + b + c;
+            }
+            let result = add(1, 2, 3);
+            return result;
+        ";
+        assert_eq!(run(code), 6);
+    }
+
+    /// Tests variable shadowing by declaring variables with the same name in different scopes.
+    #[test]
+    fn test_variable_shadowing() {
+        let code = "
+            let x = 5;
+            {
+                let x = 10;
+                return x;
+            }
+            return x;
+        ";
+        assert_eq!(run(code), 10);
+    }
+
+    /// Tests boolean logic with `&&` and `!` operators.
+    #[test]
+    fn test_boolean_logic() {
+        let code = "
+            let a = true;
+            let b = false;
+            if (a && !b) {
+                return 1;
+            } else {
+                return 0;
+            }
+        ";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests division by zero, expecting an error.
+    #[test]
+    fn test_division_by_zero() {
+        let code = "return 10 / 0;";
+        assert_eq!(run_error(code), RuntimeError::DivisionByZero);
+    }
+
+    /// Tests accessing an undefined variable, expecting an error.
+    #[test]
+    fn test_undefined_variable() {
+        let code = "return y;";
+        assert_eq!(run_error(code), RuntimeError::UndefinedVariable("y".to_string()));
+    }
+
+    /// Tests recursion with multiple parameters in a function, such as power calculation.
+    #[test]
+    fn test_recursive_function_multiple_params() {
+        let code = "
+            int power(base, exp) {
+                if (exp == 0) {
+                    return 1;
+                } else {
+                    return base * power(base, exp - 1);
+                }
+            }
+            let result = power(2, 3);
+            return result;
+        ";
+        assert_eq!(run(code), 8);
+    }
+
+    /// Tests an empty block of code, ensuring it doesn't cause issues.
+    #[test]
+    fn test_empty_block() {
+        let code = "
+            {
+            }
+            return 42;
+        ";
+        assert_eq!(run(code), 42);
+    }
+
+    /// Tests function overwriting, where a function with the same name is defined twice.
+    #[test]
+    fn test_function_overwriting() {
+        let code = "
+            int test() {
+                return 1;
+            }
+            int test() {
+                return 2;
+            }
+            return test();
+        ";
+        assert_eq!(run(code), 2);
+    }
+
+    /// Tests implicit variable declarations without the `let` keyword.
+    #[test]
+    fn test_implicit_let() {
+        let code = "
+            x = 7;
+            y = x + 3;
+            return y;
+        ";
+        assert_eq!(run(code), 10);
+    }
+
+    /// Tests global variable usage inside a function.
+    #[test]
+    fn test_global_variable_usage() {
+        let code = "
+            let x = 42;
+
+            int show() {
+                return x;
+            }
+
+            return show();
+        ";
+        assert_eq!(run(code), 42);
+    }
+
+    /// Tests string concatenation and printing.
+    #[test]
+    fn test_string_return_and_concatenation() {
+        let code = r#"
+            let hello = "Hello, ";
+            let world = "World!";
+            let message = hello + world;
+            print(message);
+            return message;
+        "#;
+
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        match vm.last_result {
+            Value::Str(ref s) => assert_eq!(s, "Hello, World!"),
+            _ => panic!("Expected string result"),
+        }
+    }
+
+    /// Tests global variable access inside a function.
+    #[test]
+    fn test_global_variable_access_in_function() {
+        let code = r#"
+            let x = 123;
+
+            int get() {
+                return x;
+            }
+
+            return get();
+        "#;
+
+        assert_eq!(run(code), 123);
+    }
+
+    /// Tests modifying a global variable inside a function.
+    #[test]
+    fn test_global_variable_modification_in_function() {
+        let code = r#"
+            let x = 10;
+
+            int modify() {
+                x = x + 5;
+            }
+
+            modify();
+            return x;
+        "#;
+
+        assert_eq!(run(code), 15);
+    }
+
+    /// Tests global variable shadowing within nested blocks.
+    #[test]
+    fn test_global_variable_shadowing() {
+        let code = r#"
+            let x = 7;
+
+            {
+                let x = 42;
+                print(x); // should print 42
+            }
+
+            return x; // should return 7
+        "#;
+
+        assert_eq!(run(code), 7);
+    }
+
+    /// Tests comma-separated variable declarations in a single statement.
+    #[test]
+    fn test_comma_separated_let_declaration() {
+        let code = "
+            let x = 1, y = 2, z = x + y;
+            return z;
+        ";
+        assert_eq!(run(code), 3);
+    }
+
+    /// Tests parentheses overriding the default precedence in expressions.
+    #[test]
+    fn test_parentheses_override_precedence() {
+        let code = "
+            let a = 2;
+            let b = 3;
+            let c = 4;
+            let d = 20;
+            return (a + b) * c == d;
+        ";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests the `sizeof` operator for different types.
+    #[test]
+    fn test_sizeof_expression() {
+        let code = "
+            return sizeof(int);
+        ";
+        assert_eq!(run(code), 4);
+    }
+
+    /// Tests `sizeof` for multiple types.
+    #[test]
+    fn test_sizeof_multiple_types() {
+        assert_eq!(run("return sizeof(char);"), 1);
+        assert_eq!(run("return sizeof(bool);"), 1);
+        assert_eq!(run("return sizeof(str);"), 8);
+    }
+
+    /// Tests parsing and using enums in the language.
+    #[test]
+    fn test_enum_parsing_and_usage() {
+        let code = "
+            enum { A = 5, B, C = 10, D };
+            return A + B + C + D; // 5 + 6 + 10 + 11 = 32
+        ";
+
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.get_result(), 32);
+    }
+
+    /// Tests type casting with different types.
+    #[test]
+    fn test_type_casting() {
+        let code = r#"
+            let x = (int)"hello";
+            let y = (char)300;
+            let z = (int)123;
+            return x + y + z;
+        "#;
+        assert_eq!(run(code), 167);
+        assert_eq!(run(r#"let x = (int)"42"; let y = (int)"hello"; return x + y;"#), 42);
+    }
+
+    /// Tests that casting a numeric string to int parses it.
+    #[test]
+    fn test_string_to_int_cast() {
+        assert_eq!(run(r#"return (int)"42" == 42;"#), 1);
+        assert_eq!(run(r#"let input = "-15"; return (int)input * 2;"#), -30);
+        assert_eq!(run(r#"return (int)"";"#), 0);
+    }
+
+    /// Tests printing from the main function.
+    #[test]
+    fn test_print_from_main() {
+        let code = r#"
+            void greet() {
+                print("Hello from C4!");
+            }
+
+            int main() {
+                greet();
+                return 42;
+            }
+
+            return main();
+        "#;
+
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.get_result(), 42);
+    }
+
+    /// Tests pre- and post-increment operators.
+    #[test]
+    fn test_pre_post_increment() {
+        let code = "
+            let x = 5;
+            let a = ++x;  // x = 6, a = 6
+            let b = x++;  // b = 6, x = 7
+            return a + b + x;  // returns 19
+        ";
+        assert_eq!(run(code), 19);
+    }
+
+    /// Tests pre- and post-decrement operators.
+    #[test]
+    fn test_pre_post_decrement() {
+        let code = "
+            let x = 10;
+            let a = --x;  // x = 9, a = 9
+            let b = x--;  // b = 9, x = 8
+            return a + b + x;  // 9 + 9 + 8 = 26
+        ";
+        assert_eq!(run(code), 26);
+    }
+
+    /// Tests enum parsing with automatic increments.
+    #[test]
+    fn test_enum_parsing_auto_increment() {
+        let code = "
+            enum { A = 10, B, C = 20, D };
+            return A + B + C + D;
+        ";
+
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.get_result(), 62);
+    }
+
+    /// Tests modulus operation in expressions.
+    #[test]
+    fn test_modulus() {
+        let code = "return 10 % 3;";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests bitwise operations like AND, OR, XOR, and shifts.
+    #[test]
+    fn test_bitwise_operations() {
+        let code = "
+            let a = 6;      // 0b0110
+            let b = 3;      // 0b0011
+            let and = a & b;    // 0b0010 -> 2
+            let or  = a | b;    // 0b0111 -> 7
+            let xor = a ^ b;    // 0b0101 -> 5
+            let shl = a << 1;   // 0b1100 -> 12
+            let shr = a >> 1;   // 0b0011 -> 3
+            return and + or + xor + shl + shr; // 2 + 7 + 5 + 12 + 3 = 29
+        ";
+        assert_eq!(run(code), 29);
+    }
+
+    /// Tests printing an array.
+    #[test]
+    fn test_print_array() {
+        let code = r#"
+            let x = [1, 2, 3];
+            print(x); // should print: [1, 2, 3]
+            return x[1]; // return middle element to confirm indexing
+        "#;
+
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.get_result(), 2); // confirm array indexing works
+    }
+
+    /// Tests pointer dereferencing.
+    #[test]
+    fn test_pointer_deref() {
+        let code = "
+            let x = 42;
+            let p = &x;
+            let y = *p;
+            return y;
+        ";
+        assert_eq!(run(code), 42);
+    }
+
+    /// Tests identity of pointers.
+    #[test]
+    fn test_pointer_identity() {
+        let code = "
+            let x = 123;
+            let ptr = &x;
+            return *ptr + 1;
+        ";
+        assert_eq!(run(code), 124);
+    }
+
+    /// Tests array assignment functionality.
+    #[test]
+    fn test_array_assignment() {
+        let code = "
+            let arr = [0, 0, 0];
+            arr[1] = 42;
+            return arr[1];
+        ";
+        assert_eq!(run(code), 42);
+    }
+
+    /// Tests that `sizeof` a nested array type multiplies out its sizes.
+    #[test]
+    fn test_nested_array_sizeof() {
+        assert_eq!(run("return sizeof(int[3][2]);"), 24);
+        assert_eq!(run("return sizeof(char[2][3][4]);"), 24);
+    }
+
+    /// Tests pointer casting in expressions.
+    #[test]
+    fn test_pointer_casting() {
+        let code = "
+            let x = 5;
+            let ptr = &x;
+            let val = (int)ptr;
+            return *(*int)val;  // the address survives a round trip through an integer
+        ";
+        assert_eq!(run(code), 5);
+    }
+
+    /// Tests using enums inside functions.
+    #[test]
+    fn test_enum_inside_function() {
+        let code = "
+            enum { A = 1, B = 2 };
+            int sum() {
+                return A + B;
+            }
+            return sum();
+        ";
+        assert_eq!(run(code), 3);
+    }
+
+    /// Tests using enum constants as array sizes in types.
+    #[test]
+    fn test_enum_constant_array_size() {
+        let code = "
+            enum { SIZE = 3 };
+            return sizeof(int[SIZE]);
+        ";
+        assert_eq!(run(code), 12);
+    }
+
+    /// Tests that an undeclared constant cannot be used as an array size.
+    #[test]
+    fn test_unknown_constant_array_size() {
+        assert_eq!(parse_error("return sizeof(int[N]);").message, "Unknown constant 'N' in array size");
+    }
+
+    /// Tests `contains` and `index_of` on arrays with present and absent values.
+    #[test]
+    fn test_contains_and_index_of_array() {
+        assert_eq!(run_value("let a = [4, 8, 15]; return contains(a, 8);"), Value::Bool(true));
+        assert_eq!(run_value("let a = [4, 8, 15]; return contains(a, 16);"), Value::Bool(false));
+        assert_eq!(run("let a = [4, 8, 15, 8]; return index_of(a, 8);"), 1);
+        assert_eq!(run("let a = [4, 8, 15]; return index_of(a, 23);"), -1);
+    }
+
+    /// Tests `contains` and `index_of` on strings, which search for substrings.
+    #[test]
+    fn test_contains_and_index_of_string() {
+        assert_eq!(run_value(r#"return contains("hello", "ll");"#), Value::Bool(true));
+        assert_eq!(run_value(r#"return contains("hello", "world");"#), Value::Bool(false));
+        assert_eq!(run(r#"return index_of("hello", "lo");"#), 3);
+        assert_eq!(run(r#"return index_of("hello", "x");"#), -1);
+    }
+
+    /// Tests that a user-defined function takes precedence over a builtin.
+    #[test]
+    fn test_user_function_shadows_builtin() {
+        let code = "
+            int contains(a, b) { return 42; }
+            return contains([1], 1);
+        ";
+        assert_eq!(run(code), 42);
+    }
+
+    /// Tests that arrays are passed by value by default.
+    #[test]
+    fn test_array_passed_by_value() {
+        let code = "
+            void fill(arr) { arr[0] = 9; }
+            let a = [1, 2];
+            fill(a);
+            return a[0];
+        ";
+        assert_eq!(run(code), 1);
+    }
+
+    /// Tests that a function can mutate an array passed by reference.
+    #[test]
+    fn test_array_passed_by_reference() {
+        let code = "
+            void fill(ref arr, v) {
+                let i = 0;
+                while (i < 3) {
+                    arr[i] = v;
+                    i = i + 1;
+                }
+            }
+            let a = [1, 2, 3];
+            fill(a, 7);
+            return a[0] + a[1] + a[2];
+        ";
+        assert_eq!(run(code), 21);
+    }
+
+    /// Tests that a by-reference parameter requires a variable argument.
+    #[test]
+    fn test_reference_parameter_requires_variable() {
+        assert_eq!(
+            run_error("void fill(ref arr) { arr[0] = 1; } fill([0]);"),
+            RuntimeError::Other("Argument for 'ref' parameter 'arr' of 'fill' must be a variable".to_string())
+        );
+    }
+
+    /// Tests that character literals act as their code points in arithmetic.
+    #[test]
+    fn test_char_arithmetic() {
+        assert_eq!(run("return 'A' + 1;"), 66);
+        assert_eq!(run("return 'a' + 25 == 'z';"), 1);
+        assert_eq!(run("return 'z' - 'a';"), 25);
+        assert_eq!(run("let c = 'b'; return c * 2;"), 196);
+    }
+
+    /// Tests comparisons between character literals and integers.
+    #[test]
+    fn test_char_comparison() {
+        assert_eq!(run("return 'a' < 'b';"), 1);
+        assert_eq!(run("return 'Z' > 'a';"), 0);
+        assert_eq!(run("return 'A' == 65;"), 1);
+        assert_eq!(run("return '0' + 9 != '9';"), 0);
+    }
+
+    /// Tests collecting the values of several top-level expressions.
+    #[test]
+    fn test_collect_results() {
+        let code = r#"
+            int double(x) { x * 100; return x * 2; }
+            let a = 3;
+            a + 1;
+            double(a);
+            "done";
+        "#;
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        vm.collect_results = true;
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.results(), &[Value::Int(4), Value::Int(6), Value::Str("done".to_string())]);
+        assert_eq!(vm.get_result(), 0); // The single result is unaffected
+    }
+
+    /// Tests that results are not collected by default.
+    #[test]
+    fn test_results_empty_by_default() {
+        let lexer = Lexer::new("1 + 2;");
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+            vm.execute(&stmt).unwrap();
+        }
+        assert!(vm.results().is_empty());
+    }
+
+    /// Tests that programs without statements parse to nothing and leave the default result.
+    #[test]
+    fn test_empty_programs() {
+        for code in ["", "   \n\t  \n", "// just a comment\n// and another"].iter() {
+            let lexer = Lexer::new(code);
+            let mut vm = Vm::new();
+            let mut parser = Parser::new(lexer, &mut vm);
+            let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+            assert!(stmts.is_empty(), "expected no statements for {:?}", code);
+            assert_eq!(vm.get_result(), 0);
+        }
+    }
+
+    /// Tests that `++`/`--` report a type error for non-integer variables.
+    #[test]
+    fn test_increment_non_integer() {
+        assert_eq!(
+            run_error("let arr = [1, 2]; arr++; return 0;"),
+            RuntimeError::TypeMismatch("Cannot apply '++' to non-integer variable 'arr'".to_string())
+        );
+    }
+
+    /// Tests that `--` on an undefined variable reports it as missing.
+    #[test]
+    fn test_decrement_undefined_variable() {
+        assert_eq!(run_error("--missing; return 0;"), RuntimeError::UndefinedVariable("missing".to_string()));
+    }
+
+    /// Tests the `sum` and `product` array reductions.
+    #[test]
+    fn test_sum_and_product() {
+        assert_eq!(run("return sum([1, 2, 3, 4]);"), 10);
+        assert_eq!(run("return sum([]);"), 0);
+        assert_eq!(run("return product([2, 3, 4]);"), 24);
+        assert_eq!(run("return product([]);"), 1);
+        assert_eq!(run_error("return sum([2147483647, 1]);"), RuntimeError::Other("Integer overflow in sum()".to_string()));
+        assert_eq!(run_error("return product([65536, 65536]);"), RuntimeError::Other("Integer overflow in product()".to_string()));
+        assert_eq!(run_error("return sum([2147483647, 1, -1]);"), RuntimeError::Other("Integer overflow in sum()".to_string())); // Even if a later element would undo it
+    }
+
+    /// Tests `min` and `max` on arrays and on two scalars.
+    #[test]
+    fn test_min_and_max() {
+        assert_eq!(run("return min([5, 2, 9]);"), 2);
+        assert_eq!(run("return max([5, 2, 9]);"), 9);
+        assert_eq!(run("return min(3, 7);"), 3);
+        assert_eq!(run("return max(3, 7);"), 7);
+    }
+
+    /// Tests that `min` of an empty array is an error.
+    #[test]
+    fn test_min_empty_array() {
+        assert_eq!(run_error("return min([]);"), RuntimeError::Other("min() of an empty array".to_string()));
+    }
+
+    /// Tests the user-facing display of runtime values.
+    #[test]
+    fn test_value_display() {
+        assert_eq!(Value::Int(65).to_string(), "65");
+        assert_eq!(Value::Str("hi".to_string()).to_string(), "\"hi\"");
+        let nested = Value::Array(vec![Value::Int(1), Value::Array(vec![Value::Str("a".to_string())])]);
+        assert_eq!(nested.to_string(), "[1, [\"a\"]]");
+    }
+
+    /// Tests that binary-operation errors show clean values rather than debug output.
+    #[test]
+    fn test_binary_op_error_formatting() {
+        assert_eq!(
+            run_error(r#"return 1 + "a";"#),
+            RuntimeError::TypeMismatch("Mismatched types for operation '+': 1 and \"a\"".to_string())
+        );
+    }
+
+    /// Tests that cast errors show clean values and types.
+    #[test]
+    fn test_cast_error_formatting() {
+        assert_eq!(
+            run_error("return (int)[1, 2];"),
+            RuntimeError::TypeMismatch("Unsupported cast: [1, 2] to int".to_string())
+        );
+    }
+
+    /// Tests that index errors show clean values.
+    #[test]
+    fn test_index_error_formatting() {
+        assert_eq!(
+            run_error("let x = 5; return x[0];"),
+            RuntimeError::TypeMismatch("Attempted to index non-array value 5".to_string())
+        );
+    }
+
+    /// Tests that `current_line` follows the statements as they are executed one by one.
+    #[test]
+    fn test_current_line() {
+        let code = "let x = 1;\n\nint f() {\n    return 2;\n}\nlet y = f();\nreturn x + y;";
+        let lexer = Lexer::new(code);
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(vm.current_line(), None);
+
+        let mut lines = Vec::new();
+        for stmt in stmts {
+            vm.execute(&stmt).unwrap();
+            lines.push(vm.current_line());
+        }
+        // The call to `f` on line 6 runs line 4, but the VM is back on the caller's line afterwards
+        assert_eq!(lines, vec![Some(1), Some(3), Some(6), Some(7)]);
+        assert_eq!(vm.get_result(), 3);
+    }
+
+    /// Runs a piece of C4 code and returns the final result as a `Value`.
+    fn run_value(code: &str) -> Value {
+        let mut vm = Vm::new();
+        vm.run(code).unwrap_or_else(|err| panic!("{}", err));
+        vm.last_result
+    }
+
+    /// Tests true division with `fdiv`.
+    #[test]
+    fn test_fdiv() {
+        assert_eq!(run_value("return fdiv(7, 2);"), Value::Float(3.5));
+        assert_eq!(run_value("return fdiv(0 - 1, 4);"), Value::Float(-0.25));
+        assert_eq!(run("return (int)fdiv(7, 2);"), 3);
+        assert_eq!(Value::Float(3.0).to_string(), "3.0");
+    }
+
+    /// Tests that `fdiv` by zero follows IEEE 754 instead of failing.
+    #[test]
+    fn test_fdiv_by_zero() {
+        assert_eq!(run_value("return fdiv(1, 0);"), Value::Float(f64::INFINITY));
+        match run_value("return fdiv(0, 0);") {
+            Value::Float(x) => assert!(x.is_nan()),
+            other => panic!("Expected float result, got {}", other),
+        }
+    }
+
+    /// Tests that a keyword used as a variable name is reported as reserved.
+    #[test]
+    fn test_keyword_as_variable_name() {
+        assert_eq!(parse_error("let if = 1;").message, "Expected identifier after 'let': 'if' is a reserved keyword");
+    }
+
+    /// Tests that a keyword used as a function name is reported as reserved.
+    #[test]
+    fn test_keyword_as_function_name() {
+        assert_eq!(parse_error("int while() {}").message, "Expected name after type: 'while' is a reserved keyword");
+    }
+
+    /// Tests initializing a sized array with exactly as many elements as declared.
+    #[test]
+    fn test_sized_array_exact_initializer() {
+        assert_eq!(run_value("let arr: int[3] = [1, 2, 3]; return arr;"), run_value("return [1, 2, 3];"));
+    }
+
+    /// Tests that a short initializer list is zero-padded to the declared size.
+    #[test]
+    fn test_sized_array_short_initializer() {
+        let expected = Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3), Value::Int(0), Value::Int(0)]);
+        assert_eq!(run_value("let arr: int[5] = [1, 2, 3]; return arr;"), expected);
+        assert_eq!(run("int[4] arr = {7}; return arr[0] + arr[3];"), 7);
+    }
+
+    /// Tests that an initializer list longer than the declared size is an error.
+    #[test]
+    fn test_sized_array_long_initializer() {
+        assert_eq!(
+            run_error("let arr: int[2] = [1, 2, 3];"),
+            RuntimeError::Other("Too many initializers for 'arr': declared size 2, got 3".to_string())
+        );
+    }
+
+    /// Tests calling a C4 function from Rust after parsing a script.
+    #[test]
+    fn test_call_function_from_host() {
+        let lexer = Lexer::new("int add(a, b) { return a + b; }");
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+            vm.execute(&stmt).unwrap();
+        }
+
+        assert_eq!(vm.call_function("add", vec![Value::Int(2), Value::Int(3)]), Ok(Value::Int(5)));
+        assert_eq!(vm.call_function("add", vec![Value::Int(1), Value::Int(1)]), Ok(Value::Int(2)));
+        assert_eq!(
+            vm.call_function("add", vec![Value::Int(2)]),
+            Err(RuntimeError::ArgumentCount { function: "add".to_string(), expected: "2".to_string(), got: 1 })
+        );
+        assert_eq!(vm.call_function("missing", vec![]), Err(RuntimeError::UndefinedFunction("missing".to_string())));
+        assert_eq!(vm.variables.len(), 1); // Scopes are torn down after each call
+    }
+
+    /// Tests that a `return` inside a loop body exits the loop and the function immediately.
+    #[test]
+    fn test_return_inside_loop_body() {
+        let code = "
+            int steps = 0;
+            int find() {
+                int i = 0;
+                while (1) {
+                    i = i + 1;
+                    if (i == 3) { return i * 10; }
+                    steps = steps + 1;
+                }
+                return 0 - 1;
             }
-            let result = square(6);
-            return result;
+            let found = find();
+            return found + steps;
         ";
-        assert_eq!(run(code), 36);
+        assert_eq!(run(code), 32); // The third pass stops at the `return`, before `steps` is bumped
+    }
+
+    /// Tests that a function returning inside the loop condition doesn't end the caller's loop.
+    #[test]
+    fn test_return_inside_loop_condition_call() {
+        let code = "
+            int n = 0;
+            int below(limit) {
+                if (n < limit) { return 1; }
+                return 0;
+            }
+            int count() {
+                while (below(4)) { n = n + 1; }
+                return n * 10;
+            }
+            return count();
+        ";
+        assert_eq!(run(code), 40);
+    }
+
+    /// Tests that `print` truncates long arrays only when a limit is set.
+    #[test]
+    fn test_array_print_limit() {
+        let mut vm = Vm::new();
+        let big = Value::Array((1..=1000).map(Value::Int).collect());
+        assert_eq!(vm.format_for_print(&big).matches(", ").count(), 999); // Off by default
+
+        vm.array_print_limit = Some(5);
+        assert_eq!(vm.format_for_print(&big), "[1, 2, 3, 4, ..., 1000]");
+        let small = Value::Array(vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(vm.format_for_print(&small), "[1, 2]");
+        assert_eq!(vm.format_for_print(&Value::Str("hi".to_string())), "hi");
+    }
+
+    /// Tests listing the defined function and constant names in sorted order.
+    #[test]
+    fn test_function_and_constant_names() {
+        let lexer = Lexer::new("
+            int sub(a, b) { return a - b; }
+            enum { RED, GREEN };
+            int add(a, b) { return a + b; }
+        ");
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+            vm.execute(&stmt).unwrap();
+        }
+        assert_eq!(vm.function_names(), vec!["add", "sub"]);
+        assert_eq!(vm.constant_names(), vec!["GREEN", "RED"]);
+    }
+
+    /// Tests that `++` on a literal is reported at the operator's position.
+    #[test]
+    fn test_increment_literal() {
+        assert_eq!(parse_error("let x = ++5;").to_string(), "Operand of '++' must be assignable (got literal) at line 1, column 9");
+    }
+
+    /// Tests that postfix `--` on a compound expression is rejected.
+    #[test]
+    fn test_decrement_expression() {
+        assert_eq!(parse_error("let a = 1; let b = 2; (a + b)--;").to_string(), "Operand of '--' must be assignable (got expression) at line 1, column 30");
+    }
+
+    /// Tests that `++` and `--` step array elements and struct fields in place.
+    #[test]
+    fn test_increment_element() {
+        assert_eq!(run("let a = [1, 5]; let old = a[1]++; return old * 10 + a[1];"), 56);
+        assert_eq!(run("let a = [1, 5]; let i = 0; return ++a[i] + a[0];"), 4);
+        assert_eq!(run("let grid = [[1, 2], [3, 4]]; grid[1][0]--; --grid[1][0]; return grid[1][0];"), 1);
+        assert_eq!(run("let counts = [0, 0]; for (let i = 0; i < 5; i++) { counts[i % 2]++; } return counts[0] * 10 + counts[1];"), 32);
+        assert_eq!(run("let p = malloc(2); p[1]++; p[1]++; return p[1];"), 2);
+        let point = "struct Point { int x; int y; }; let p: Point = {1, 2};";
+        assert_eq!(run(&format!("{} p.x++; ++p.y; return p.x * 10 + p.y;", point)), 23);
+        assert_eq!(run(&format!("{} let old = p.y--; return old * 10 + p.y;", point)), 21);
+    }
+
+    /// Tests the errors of `++` and `--` on array elements and struct fields.
+    #[test]
+    fn test_increment_element_errors() {
+        assert_eq!(
+            run_error("let a = [[1]]; a[0]++;"),
+            RuntimeError::TypeMismatch("Cannot apply '++' to non-integer element 'array[index]'".to_string())
+        );
+        assert_eq!(run_error("let a = [1]; a[3]--;"), RuntimeError::IndexOutOfBounds { len: 1, idx: 3 });
+        assert_eq!(run_error("let a = [2147483647]; a[0]++;").to_string(), "Integer overflow in array[index]++");
+        assert_eq!(run_error("const PI = [3]; PI[0]++;"), RuntimeError::Other("Cannot assign to constant 'PI'".to_string()));
+    }
+
+    /// Tests that a final expression without a semicolon becomes the result when enabled.
+    #[test]
+    fn test_trailing_expression() {
+        let lexer = Lexer::new("let x = 2; x + 3");
+        let mut vm = Vm::new();
+        let mut parser = Parser::new(lexer, &mut vm);
+        parser.set_trailing_expression(true);
+        for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+            vm.execute(&stmt).unwrap();
+        }
+        assert_eq!(vm.get_result(), 5);
+    }
+
+    /// Tests that a missing semicolon is still an error by default.
+    #[test]
+    fn test_trailing_expression_off_by_default() {
+        assert_eq!(parse_error("2 + 3").message, "Expected ';' after expression");
+    }
+
+    /// Tests that functions with the same name but different parameter counts coexist.
+    #[test]
+    fn test_function_overloading_by_arity() {
+        let code = "
+            int area(side) { return side * side; }
+            int area(w, h) { return w * h; }
+            return area(3) + area(2, 5);
+        ";
+        assert_eq!(run(code), 19);
+    }
+
+    /// Tests that a call matching none of the overloads lists the accepted argument counts.
+    #[test]
+    fn test_function_overload_arity_mismatch() {
+        let err = run_error("
+            int area(side) { return side * side; }
+            int area(w, h) { return w * h; }
+            return area(1, 2, 3);
+        ");
+        assert_eq!(
+            err,
+            RuntimeError::ArgumentCount { function: "area".to_string(), expected: "1 or 2".to_string(), got: 3 }
+        );
+    }
+
+    /// Tests that deeply nested parentheses are reported instead of overflowing the stack.
+    #[test]
+    fn test_deeply_nested_parentheses() {
+        assert_eq!(parse_error(&format!("return {}1{};", "(".repeat(1000), ")".repeat(1000))).message, "Expression nesting too deep (limit 256)");
+    }
+
+    /// Tests that reasonable nesting is still accepted.
+    #[test]
+    fn test_nested_parentheses_within_limit() {
+        assert_eq!(run(&format!("return {}1{};", "(1 + ".repeat(100), ")".repeat(100))), 101);
+    }
+
+    /// Tests that a long chain of operators is evaluated without recursing per operator.
+    #[test]
+    fn test_long_operator_chain() {
+        assert_eq!(run(&format!("return 0{};", " + 1".repeat(100_000))), 100_000);
+    }
+
+    /// Tests that `printf` returns the number of characters it printed.
+    #[test]
+    fn test_printf_returns_length() {
+        assert_eq!(run(r#"return printf("%c=%d\n", 'x', true);"#), 4);
+    }
+
+    /// Tests that a wide array literal is built without deep recursion or excessive time.
+    #[test]
+    fn test_large_flat_array_literal() {
+        let elements = (0..100_000).map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+        let start = std::time::Instant::now();
+        let code = format!("let a = [{}]; return a[99999];", elements);
+        assert_eq!(run(&code), 99_999);
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    /// Tests that `sizeof` a struct sums the sizes of its fields.
+    #[test]
+    fn test_sizeof_struct() {
+        let code = "
+            struct Point { int x; int y; };
+            struct Tagged { char tag; int[3] values; };
+            return sizeof(Point) * 100 + sizeof(struct Tagged);
+        ";
+        assert_eq!(run(code), 813);
+    }
+
+    /// Tests that `sizeof` an undeclared struct is an error.
+    #[test]
+    fn test_sizeof_unknown_struct() {
+        assert_eq!(parse_error("return sizeof(struct Missing);").message, "Unknown struct 'Missing'");
+    }
+
+    /// Tests that `sizeof` a type whose size doesn't fit in an `int` is an error, not an overflow.
+    #[test]
+    fn test_sizeof_too_large() {
+        let err = parse_error("return sizeof(int[1000000000]);");
+        assert_eq!((err.message.as_str(), err.line, err.col), ("Type too large for sizeof", 1, 8));
+        let err = parse_error("struct Big { int[300000000] a; int[300000000] b; };\nreturn sizeof(struct Big);");
+        assert_eq!((err.message.as_str(), err.line, err.col), ("Type too large for sizeof", 2, 8));
+        assert_eq!(run("return sizeof(int[536870911]);"), 2147483644);
+    }
+
+    /// Tests that the result value distinguishes a returned value from a void result.
+    #[test]
+    fn test_get_result_value() {
+        let result_of = |code: &str| {
+            let mut vm = Vm::new();
+            let mut parser = Parser::new(Lexer::new(code), &mut vm);
+            for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+                vm.execute(&stmt).unwrap();
+            }
+            vm.get_result_value().cloned()
+        };
+        assert_eq!(result_of("return [1, 2];"), Some(Value::Array(vec![Value::Int(1), Value::Int(2)])));
+        assert_eq!(result_of("int f() { return 5; } let x = f();"), None); // Returns inside calls don't count
+    }
+
+    /// Tests a variadic function receiving its extra arguments in `args`.
+    #[test]
+    fn test_variadic_function() {
+        let code = "
+            int total(base, ...) {
+                return base + sum(args);
+            }
+            return total(100) + total(0, 1, 2, 3) * 10 + total(0, 5) * 1000;
+        ";
+        assert_eq!(run(code), 5160);
+    }
+
+    /// Tests that a variadic function still requires its fixed parameters.
+    #[test]
+    fn test_variadic_function_too_few_arguments() {
+        assert_eq!(
+            run_error("int total(base, ...) { return base; } return total();"),
+            RuntimeError::ArgumentCount { function: "total".to_string(), expected: "at least 1".to_string(), got: 0 }
+        );
+        let err = run_error("int total(base, ...) { return base; } return total();");
+        assert_eq!(err.to_string(), "Function 'total' expected at least 1 argument, got 0");
+        assert_eq!(run_error("int id(n) { return n; } return id();").to_string(), "Function 'id' expected 1 argument, got 0");
+    }
+
+    /// Tests that a variadic function can't name a parameter `args`, since `args` holds its extra arguments.
+    #[test]
+    fn test_variadic_function_args_parameter() {
+        assert_eq!(
+            parse_error("int total(args, ...) { return args; }").message,
+            "A variadic function can't have a parameter named 'args', which holds its extra arguments"
+        );
+        assert_eq!(run("int total(args) { return args; } return total(3);"), 3); // Fine without `...`
+    }
+
+    /// Tests calling a function with and without its optional trailing argument.
+    #[test]
+    fn test_default_parameter_values() {
+        let code = "
+            int scale(x, factor = 10, offset = factor + 1) {
+                return x * factor + offset;
+            }
+            return scale(2) * 10000 + scale(2, 3) * 100 + scale(2, 3, 0);
+        ";
+        assert_eq!(run(code), 31 * 10000 + 10 * 100 + 6);
+    }
+
+    /// Tests that parameters with defaults must come last.
+    #[test]
+    fn test_default_parameter_order() {
+        assert_eq!(parse_error("int f(a = 1, b) { return a + b; }").message, "Parameter 'b' without a default follows a parameter with a default");
+    }
+
+    /// Tests that leaving out a required argument is still an error.
+    #[test]
+    fn test_default_parameter_missing_required() {
+        assert_eq!(
+            run_error("int f(a, b = 1) { return a + b; } return f();"),
+            RuntimeError::ArgumentCount { function: "f".to_string(), expected: "1 to 2".to_string(), got: 0 }
+        );
+    }
+
+    /// Tests that comparisons bind tighter than bitwise `&`, `^` and `|`, as in C.
+    #[test]
+    fn test_bitwise_looser_than_comparison() {
+        // Comparisons give booleans, which bitwise operators reject, so the errors show the grouping
+        assert_eq!(run_error("return 1 & 2 == 2;"), RuntimeError::TypeMismatch("Mismatched types for operation '&': 1 and true".to_string())); // 1 & (2 == 2)
+        assert_eq!(run("return (1 & 2) == 2;"), 0);
+        assert_eq!(run_error("return 6 | 1 != 0;"), RuntimeError::TypeMismatch("Mismatched types for operation '|': 6 and true".to_string())); // 6 | (1 != 0)
+        assert_eq!(run_error("return 3 ^ 1 < 2;"), RuntimeError::TypeMismatch("Mismatched types for operation '^': 3 and true".to_string())); // 3 ^ (1 < 2)
+        assert_eq!(run_error("return 1 | 2 & 3 == 3;"), RuntimeError::TypeMismatch("Mismatched types for operation '&': 2 and true".to_string())); // 1 | (2 & (3 == 3))
+    }
+
+    /// Tests that equality binds looser than relational operators, which bind looser than shifts, as in C.
+    #[test]
+    fn test_equality_and_relational_precedence() {
+        assert_eq!(run("return false == 1 < 2;"), 0); // false == (1 < 2)
+        assert_eq!(run("return 1 < 1 << 2;"), 1); // 1 < (1 << 2)
+        assert_eq!(run_error("let x = 6; let mask = 2; let flag = 2; return x & mask == flag;"), RuntimeError::TypeMismatch("Mismatched types for operation '&': 6 and true".to_string())); // x & (mask == flag)
+        assert_eq!(run_error("let a = 1; let b = 2; let c = 4; return a == b | c;"), RuntimeError::TypeMismatch("Mismatched types for operation '|': false and 4".to_string())); // (a == b) | c
+    }
+
+    /// Tests that addition binds tighter than shifts, as in C.
+    #[test]
+    fn test_shift_looser_than_addition() {
+        assert_eq!(run("return 1 + 2 << 3;"), 24); // (1 + 2) << 3
+        assert_eq!(run("return 16 >> 1 + 1;"), 4); // 16 >> (1 + 1)
+        assert_eq!(run("return 2 * 3 << 1 - 0;"), 12); // (2 * 3) << (1 - 0)
+    }
+
+    /// Tests that `!` treats empty arrays and strings as false, like conditions do.
+    #[test]
+    fn test_not_on_arrays_and_strings() {
+        assert_eq!(run("return ![];"), 1);
+        assert_eq!(run("return ![0];"), 0);
+        assert_eq!(run(r#"return !"";"#), 1);
+        assert_eq!(run(r#"return !"x";"#), 0);
+        assert_eq!(run(r#"if ("") { return 1; } else { return 2; }"#), 2);
+    }
+
+    /// A writer that appends to a buffer shared with the test, for capturing program output.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Tests that `eprint` writes to the error sink, separately from `println` and `printf`.
+    #[test]
+    fn test_eprint_goes_to_error_output() {
+        let (out, err) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(out.clone()));
+        vm.set_error_output(Box::new(err.clone()));
+        let code = r#"
+            println("result");
+            eprint("debug: x is");
+            eprint([1, 2]);
+            printf("%d\n", 42);
+        "#;
+        let mut parser = Parser::new(Lexer::new(code), &mut vm);
+        for stmt in parser.parse().unwrap_or_else(|err| panic!("{}", err)) {
+            vm.execute(&stmt).unwrap();
+        }
+        assert_eq!(out.contents(), "result\n42\n");
+        assert_eq!(err.contents(), "debug: x is\n[1, 2]\n");
+    }
+
+    /// Tests returning several values in an array and destructuring them.
+    #[test]
+    fn test_let_destructuring() {
+        let code = "
+            int divmod(a, b) { return [a / b, a % b]; }
+            let [q, r] = divmod(17, 5);
+            return q * 10 + r;
+        ";
+        assert_eq!(run(code), 32);
+    }
+
+    /// Tests that destructuring requires the array length to match.
+    #[test]
+    fn test_let_destructuring_length_mismatch() {
+        assert_eq!(
+            run_error("let [a, b] = [1, 2, 3];"),
+            RuntimeError::Other("Cannot destructure an array of 3 elements into 2 variables".to_string())
+        );
+    }
+
+    /// Tests `floor_div` and `ceil_div` with positive and negative operands.
+    #[test]
+    fn test_floor_and_ceil_div() {
+        assert_eq!(run("return (0 - 7) / 2;"), -3); // `/` truncates toward zero
+        assert_eq!(run("return floor_div(0 - 7, 2);"), -4);
+        assert_eq!(run("return ceil_div(0 - 7, 2);"), -3);
+        assert_eq!(run("return floor_div(7, 0 - 2);"), -4);
+        assert_eq!(run("return ceil_div(7, 2);"), 4);
+        assert_eq!(run("return floor_div(7, 2);"), 3);
+        assert_eq!(run("return floor_div(0 - 8, 2) + ceil_div(0 - 8, 2);"), -8); // Exact quotients are unchanged
+        assert_eq!(run("return ceil_div(0 - 7, 0 - 2);"), 4);
+    }
+
+    /// Tests that `floor_div` by zero is an error, like `/`, and so is dividing `i32::MIN` by -1.
+    #[test]
+    fn test_floor_div_by_zero() {
+        assert_eq!(run_error("return floor_div(1, 0);"), RuntimeError::DivisionByZero);
+        let overflow = |name: &str| RuntimeError::Other(format!("Integer overflow in {}(-2147483648, -1)", name));
+        assert_eq!(run_error("return floor_div(-2147483647 - 1, -1);"), overflow("floor_div"));
+        assert_eq!(run_error("return ceil_div(-2147483647 - 1, -1);"), overflow("ceil_div"));
+    }
+
+    /// Tests splitting a string and joining the pieces back together.
+    #[test]
+    fn test_split_and_join() {
+        assert_eq!(run(r#"return join(split("a,b,c", ","), "-") == "a-b-c";"#), 1);
+        assert_eq!(
+            run_value(r#"return split("ab", "");"#),
+            Value::Array(vec![Value::Str("a".to_string()), Value::Str("b".to_string())])
+        );
+        assert_eq!(run_value(r#"return join([1, "x", 2], ", ");"#), Value::Str("1, x, 2".to_string()));
+        assert_eq!(run_value(r#"return join([], "-");"#), Value::Str(String::new()));
+    }
+
+    /// Tests that `max_value_size` stops oversized strings and arrays before they are built.
+    #[test]
+    fn test_max_value_size() {
+        let limited = |code: &str| {
+            let mut vm = Vm::new();
+            vm.max_value_size = Some(1000);
+            let mut parser = Parser::new(Lexer::new(code), &mut vm);
+            let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+            for stmt in stmts {
+                vm.execute(&stmt)?;
+            }
+            Ok(vm.last_result.clone())
+        };
+        assert_eq!(limited(r#"return repeat("ab", 3);"#), Ok(Value::Str("ababab".to_string())));
+        assert_eq!(
+            limited(r#"return repeat("x", 1000000000);"#),
+            Err(RuntimeError::ValueTooLarge { kind: "string", size: 1000000000, limit: 1000 })
+        );
+        let err = limited(r#"let s = repeat("x", 600); return s + s;"#).unwrap_err();
+        assert_eq!(err.to_string(), "Value too large: string of size 1200 exceeds the limit of 1000");
     }
 
-    /// Tests scope isolation in functions and variables.
+    /// Tests storing a function in a variable and calling it through the variable.
     #[test]
-    fn test_scope_isolation() {
+    fn test_function_in_variable() {
         let code = "
-            int test(x) { return x + 1; }
-            let x = 5;
-            let y = test(10);
-            return x + y;
+            int square(n) { return n * n; }
+            let f = square;
+            let g = f;
+            return f(3) + g(4);
         ";
-        assert_eq!(run(code), 16);
+        assert_eq!(run(code), 25);
+        assert_eq!(run_value("int square(n) { return n * n; } return square;"), Value::Func("square".to_string()));
     }
 
-    /// Tests recursion in functions, specifically calculating a factorial.
+    /// Tests passing a function to another function and calling it there.
     #[test]
-    fn test_recursion() {
+    fn test_function_as_argument() {
         let code = "
-            int factorial(n) {
-                if (n == 0) {
-                    return 1;
-                } else {
-                    return n * factorial(n - 1);
-                }
-            }
-            let result = factorial(5);
-            return result;
+            int square(n) { return n * n; }
+            int twice(f, x) { return f(f(x)); }
+            int apply(f, x) { return f(x); }
+            return apply(square, 3) * 1000 + twice(square, 3);
         ";
-        assert_eq!(run(code), 120);
+        assert_eq!(run(code), 9081);
     }
 
-    /// Tests if-else conditionals with basic true/false evaluations.
+    /// Tests that a function called through a parameter still has its arguments checked.
     #[test]
-    fn test_if_else() {
+    fn test_callback_arity_checked() {
+        let err = run_error("
+            int square(n) { return n * n; }
+            int apply2(f, x) { return f(x, x); }
+            return apply2(square, 3);
+        ");
+        assert_eq!(
+            err,
+            RuntimeError::ArgumentCount { function: "square".to_string(), expected: "1".to_string(), got: 2 }
+        );
+    }
+
+    /// Tests the `map` and `filter` builtins with user-defined callbacks.
+    #[test]
+    fn test_map_and_filter() {
         let code = "
-            let x = 10;
-            if (x < 5) {
-                return 0;
-            } else {
-                return 1;
-            }
+            int double(n) { return n * 2; }
+            int is_even(n) { return n % 2 == 0; }
+            let evens = filter([1, 2, 3, 4, 5, 6], is_even);
+            return sum(map(evens, double));
         ";
-        assert_eq!(run(code), 1);
+        assert_eq!(run(code), 24);
+        assert_eq!(
+            run_value("int double(n) { return n * 2; } return map([1, 2, 3], double);"),
+            Value::Array(vec![Value::Int(2), Value::Int(4), Value::Int(6)])
+        );
     }
 
-    /// Tests while loop for sum calculation with a break condition.
+    /// Tests that an error inside a `map` callback propagates.
     #[test]
-    fn test_while_loop() {
+    fn test_map_callback_error() {
+        assert_eq!(run_error("int inv(n) { return 10 / n; } return map([1, 0], inv);"), RuntimeError::DivisionByZero);
+    }
+
+    /// Tests unary minus on literals and variables, binding tighter than `*`.
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(run("return -5;"), -5);
+        assert_eq!(run("let x = 3; return -x;"), -3);
+        assert_eq!(run("return 2 * -3;"), -6);
+        assert_eq!(run("return - -4 - -1;"), 5);
+        assert_eq!(run_value("return -fdiv(1, 2);"), Value::Float(-0.5));
+    }
+
+    /// Tests that negating a string is an error.
+    #[test]
+    fn test_unary_minus_on_string() {
+        assert_eq!(
+            run_error(r#"return -"a";"#),
+            RuntimeError::TypeMismatch("Cannot negate non-numeric value \"a\"".to_string())
+        );
+    }
+
+    /// Tests bitwise NOT, which binds at unary precedence like `!`.
+    #[test]
+    fn test_bitwise_not() {
+        assert_eq!(run("return ~0;"), -1);
+        assert_eq!(run("return ~5;"), -6);
+        assert_eq!(run("return ~1 + 3;"), 1); // (~1) + 3
+        assert_eq!(run("return ~~7 & 3;"), 3);
+    }
+
+    /// Tests that `~` on a string is an error.
+    #[test]
+    fn test_bitwise_not_on_string() {
+        assert_eq!(
+            run_error(r#"return ~"a";"#),
+            RuntimeError::TypeMismatch("Cannot apply '~' to non-integer value \"a\"".to_string())
+        );
+    }
+
+    /// Tests IEEE 754 comparisons of NaN and signed zeros, and mixed int/float arithmetic.
+    #[test]
+    fn test_float_comparisons() {
+        assert_eq!(run("return fdiv(0, 0) != fdiv(0, 0);"), 1); // NaN is unequal to itself
+        assert_eq!(run("let nan = fdiv(0, 0); return (nan == nan) || (nan < 1) || (nan >= 1);"), 0);
+        assert_eq!(run("return -fdiv(0, 1) == fdiv(0, 1);"), 1); // -0.0 == 0.0
+        assert_eq!(run("return fdiv(1, 2) < 1;"), 1);
+        assert_eq!(run_value("return fdiv(1, 2) + 1;"), Value::Float(1.5));
+    }
+
+    /// Tests how NaN and infinities are printed.
+    #[test]
+    fn test_float_special_values_printing() {
+        let vm = Vm::new();
+        assert_eq!(vm.format_for_print(&run_value("return fdiv(1, 0);")), "inf");
+        assert_eq!(vm.format_for_print(&run_value("return fdiv(0 - 1, 0);")), "-inf");
+        assert_eq!(vm.format_for_print(&run_value("return fdiv(0, 0);")), "NaN");
+        assert_eq!(vm.format_for_print(&run_value("return -fdiv(0, 1);")), "-0.0");
+    }
+
+    /// Tests a counting `for` loop.
+    #[test]
+    fn test_for_loop() {
         let code = "
-            let i = 0;
             let sum = 0;
-            while (i < 5) {
-                sum = sum + i;
-                i = i + 1;
-            }
+            for (let i = 0; i < 5; i = i + 1) { sum = sum + i; }
             return sum;
         ";
         assert_eq!(run(code), 10);
     }
 
-    /// Tests nested if-else statements.
+    /// Tests a `for` loop with every clause left out, ended by `return`.
     #[test]
-    fn test_nested_if_else() {
+    fn test_for_loop_without_clauses() {
         let code = "
-            let x = 10;
-            if (x > 5) {
-                if (x < 15) {
-                    return 1;
-                } else {
-                    return 2;
+            int first_square_above(n) {
+                let i = 0;
+                for (;;) {
+                    if (i * i > n) { return i; }
+                    i++;
                 }
-            } else {
-                return 0;
             }
+            return first_square_above(50);
         ";
-        assert_eq!(run(code), 1);
+        assert_eq!(run(code), 8);
     }
 
-    /// Tests nested while loops for a sum calculation.
+    /// Tests that a `return` in a `for` body exits at once, without running the update.
     #[test]
-    fn test_nested_while_loops() {
+    fn test_return_inside_for_body() {
         let code = "
-            let i = 0;
-            let sum = 0;
-            while (i < 3) {
-                let j = 0;
-                while (j < 2) {
-                    sum = sum + i + j;
-                    j = j + 1;
+            let updates = 0;
+            int find() {
+                for (let i = 0; i < 10; updates = updates + 1) {
+                    if (i == 2) { return i * 10; }
+                    i = i + 1;
                 }
-                i = i + 1;
+                return 0 - 1;
             }
-            return sum;
+            let found = find();
+            return found + updates;
         ";
-        assert_eq!(run(code), 9);
+        assert_eq!(run(code), 22);
     }
 
-    /// Tests function call with multiple parameters.
+    /// Tests that functions returning from the condition and update don't end the loop.
     #[test]
-    fn test_function_multiple_params() {
+    fn test_return_inside_for_condition_and_update_calls() {
         let code = "
-            int add(a, b, c) {
-                return a + b + c;
-            }
-            let result = add(1, 2, 3);
-            return result;
+            int below(i, n) { return i < n; }
+            int next(i) { return i + 1; }
+            let count = 0;
+            for (let i = 0; below(i, 4); i = next(i)) { count = count + 1; }
+            return count;
         ";
-        assert_eq!(run(code), 6);
+        assert_eq!(run(code), 4);
     }
 
-    /// Tests variable shadowing by declaring variables with the same name in different scopes.
+    /// Tests that a block expression evaluates to its final expression.
     #[test]
-    fn test_variable_shadowing() {
+    fn test_block_expression() {
+        assert_eq!(run("let x = { let a = 2; a * 3 }; return x;"), 6);
+    }
+
+    /// Tests that a block expression's variables don't outlive it, while outer ones can be assigned.
+    #[test]
+    fn test_block_expression_scope() {
         let code = "
-            let x = 5;
-            {
-                let x = 10;
-                return x;
-            }
-            return x;
+            let a = 10;
+            let count = 0;
+            let x = { let a = 1; count = count + 1; a + 1 };
+            return a + x + count;
         ";
-        assert_eq!(run(code), 10);
+        assert_eq!(run(code), 13);
     }
 
-    /// Tests boolean logic with `&&` and `!` operators.
+    /// Tests that braces around plain expressions are still array literals.
     #[test]
-    fn test_boolean_logic() {
+    fn test_brace_array_literal_is_not_a_block() {
+        assert_eq!(run("let arr = {4, 5}; let one = {7}; return arr[1] + one[0];"), 12);
+    }
+
+    /// Tests that a `return` inside a block expression returns from the enclosing function.
+    #[test]
+    fn test_return_inside_block_expression() {
         let code = "
-            let a = true;
-            let b = false;
-            if (a && !b) {
-                return 1;
-            } else {
-                return 0;
+            int clamp(n) {
+                let m = { if (n > 9) { return 9; } n * 2 };
+                return m + 1;
             }
+            return clamp(3) * 100 + clamp(50);
         ";
-        assert_eq!(run(code), 1);
+        assert_eq!(run(code), 709);
     }
 
-    /// Tests division by zero, expecting a panic.
+    /// Tests a block expression without a final expression.
     #[test]
-    #[should_panic(expected = "Division by zero")]
-    fn test_division_by_zero() {
-        let code = "return 10 / 0;";
-        run(code);
+    fn test_block_expression_without_value() {
+        assert_eq!(parse_error("let x = { let a = 2; }; return x;").message, "Expected an expression at the end of block expression");
     }
 
-    /// Tests accessing an undefined variable, expecting a panic.
+    /// Tests a `#define` constant used as an array dimension and in arithmetic.
     #[test]
-    #[should_panic(expected = "Variable 'y' not found")]
-    fn test_undefined_variable() {
-        let code = "return y;";
-        run(code);
+    fn test_define_constant() {
+        let code = "
+            #define SIZE 10
+            int[SIZE] arr = {1, 2};
+            arr[SIZE - 1] = SIZE * 2;
+            return sizeof(int[SIZE]) + arr[9];
+        ";
+        assert_eq!(run(code), 60);
     }
 
-    /// Tests recursion with multiple parameters in a function, such as power calculation.
+    /// Tests hexadecimal and binary literals in programs.
     #[test]
-    fn test_recursive_function_multiple_params() {
-        let code = "
-            int power(base, exp) {
-                if (exp == 0) {
-                    return 1;
-                } else {
-                    return base * power(base, exp - 1);
-                }
-            }
-            let result = power(2, 3);
-            return result;
-        ";
-        assert_eq!(run(code), 8);
+    fn test_hex_and_binary_literals() {
+        assert_eq!(run("return 0xFF;"), 255);
+        assert_eq!(run("return 0b1010;"), 10);
+        assert_eq!(run("return 0x10 + 1;"), 17);
     }
 
-    /// Tests an empty block of code, ensuring it doesn't cause issues.
+    /// Tests an array sized by `sizeof`, which is folded to a constant.
     #[test]
-    fn test_empty_block() {
-        let code = "
-            {
-            }
-            return 42;
-        ";
-        assert_eq!(run(code), 42);
+    fn test_array_sized_by_sizeof() {
+        assert_eq!(run("let buf: char[sizeof(int)] = {}; return len(buf);"), 4);
+        assert_eq!(run("return sizeof(char[sizeof(int) * 2 + 1]);"), 9);
     }
 
-    /// Tests function overwriting, where a function with the same name is defined twice.
+    /// Tests that an array size must be known before the program runs.
     #[test]
-    fn test_function_overwriting() {
+    fn test_array_size_not_constant() {
+        assert_eq!(parse_error("let n = 3; let buf: int[n + 1] = {}; return 0;").message, "Array size must be a constant expression");
+    }
+
+    /// Tests the `len` builtin on arrays and strings.
+    #[test]
+    fn test_len() {
+        assert_eq!(run("return len([1, 2, 3]) * 10 + len(\"héllo\");"), 35);
+        assert_eq!(run("return len(\"hello\");"), 5);
+        assert_eq!(run("let arr = [1, 2, 3]; return len(arr);"), 3);
+        assert_eq!(run_error("return len(5);"), RuntimeError::TypeMismatch("len() requires an array or a string, got 5".to_string()));
+        // A user-defined `len` takes precedence over the builtin
+        assert_eq!(run("int len(x) { return 99; } return len(\"hello\");"), 99);
+    }
+
+    /// Tests float literals in arithmetic, including division, which doesn't truncate.
+    #[test]
+    fn test_float_literals() {
+        assert_eq!(run_value("return 3.0 / 2.0;"), Value::Float(1.5));
+        assert_eq!(run_value("return 1 + 2.5;"), Value::Float(3.5));
+        assert_eq!(run_value("return 2.5 * 2 > 4;"), Value::Bool(true));
+    }
+
+    /// Tests the `float` type in declarations, casts and `sizeof`.
+    #[test]
+    fn test_float_type() {
+        assert_eq!(run_value("float x = 1; return x / 4;"), Value::Float(0.25));
+        assert_eq!(run_value("let y: float = 2; return (int)(y * 1.75);"), Value::Int(3));
+        assert_eq!(run_value("return (float)7 / 2;"), Value::Float(3.5));
+        assert_eq!(run("return sizeof(float);"), 4);
+    }
+
+    /// Tests that a `while (arr)` loop draining the array with `pop` stops once it is empty.
+    #[test]
+    fn test_pop_drain_loop() {
         let code = "
-            int test() {
-                return 1;
-            }
-            int test() {
-                return 2;
+            let arr = [1, 2, 3];
+            let total = 0;
+            let count = 0;
+            while (arr) {
+                total = total * 10 + pop(arr);
+                count++;
             }
-            return test();
+            return total * 10 + count + len(arr);
         ";
-        assert_eq!(run(code), 2);
+        assert_eq!(run(code), 3213);
     }
 
-    /// Tests implicit variable declarations without the `let` keyword.
+    /// Tests that `push` grows the array variable and `pop` works through a `ref` parameter.
     #[test]
-    fn test_implicit_let() {
+    fn test_push_and_pop_through_ref() {
         let code = "
-            x = 7;
-            y = x + 3;
-            return y;
+            void drain(ref a) { while (a) { pop(a); } }
+            let arr = [];
+            push(arr, 4);
+            let n = push(arr, 5);
+            drain(arr);
+            return n * 10 + len(arr);
         ";
-        assert_eq!(run(code), 10);
+        assert_eq!(run(code), 20);
     }
 
-    /// Tests global variable usage inside a function.
+    /// Tests that popping from an empty array is an error.
     #[test]
-    fn test_global_variable_usage() {
-        let code = "
-            let x = 42;
+    fn test_pop_empty_array() {
+        assert_eq!(
+            run_error("let arr = []; pop(arr); return 0;"),
+            RuntimeError::Other("pop() from an empty array".to_string())
+        );
+    }
 
-            int show() {
-                return x;
-            }
+    /// Tests that `pop` needs an array variable to write back to.
+    #[test]
+    fn test_pop_requires_variable() {
+        let err = run_error("return pop([1, 2]);");
+        assert!(err.to_string().starts_with("The array passed to 'pop' must be a variable"));
+    }
 
-            return show();
-        ";
-        assert_eq!(run(code), 42);
+    /// Tests that runtime errors are returned as specific variants instead of panicking.
+    #[test]
+    fn test_runtime_error_variants() {
+        assert_eq!(run_error("let a = [1, 2]; return a[5];"), RuntimeError::IndexOutOfBounds { len: 2, idx: 5 });
+        assert_eq!(run_error("let a = [1, 2]; a[2] = 0;"), RuntimeError::IndexOutOfBounds { len: 2, idx: 2 });
+        assert_eq!(run_error("return 7 % 0;"), RuntimeError::ModuloByZero);
+        assert_eq!(run_error("return nope(1);"), RuntimeError::UndefinedFunction("nope".to_string()));
+        assert!(matches!(run_error(r#"return "a" - "b";"#), RuntimeError::TypeMismatch(_)));
     }
 
-    /// Tests string concatenation and printing.
+    /// Tests that a negative index is out of bounds and reported as written, rather than wrapping around.
     #[test]
-    fn test_string_return_and_concatenation() {
-        let code = r#"
-            let hello = "Hello, ";
-            let world = "World!";
-            let message = hello + world;
-            print(message);
-            return message;
-        "#;
+    fn test_negative_index() {
+        assert_eq!(run_error("let a = [1, 2]; return a[-1];"), RuntimeError::IndexOutOfBounds { len: 2, idx: -1 });
+        assert_eq!(run_error("let a = [1, 2]; a[-1] = 0;"), RuntimeError::IndexOutOfBounds { len: 2, idx: -1 });
+        assert_eq!(run_error("let s = \"abc\"; return s[-2];"), RuntimeError::IndexOutOfBounds { len: 3, idx: -2 });
+        assert_eq!(run_error("let p = malloc(2); return p[-1];"), RuntimeError::IndexOutOfBounds { len: 2, idx: -1 });
+        assert_eq!(run_error("let p = malloc(2); p[-1] = 3;").to_string(), "Array index out of bounds: -1 (length 2)");
+    }
 
-        let lexer = Lexer::new(code);
+    /// Tests that the VM can keep running after an error is returned.
+    #[test]
+    fn test_vm_usable_after_runtime_error() {
         let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
+        let mut parser = Parser::new(Lexer::new("let x = 1 / 0; return 5;"), &mut vm);
+        let stmts = parser.parse().unwrap_or_else(|err| panic!("{}", err));
+        let results: Vec<_> = stmts.into_iter().map(|stmt| vm.execute(&stmt)).collect();
+        assert_eq!(results, vec![Err(RuntimeError::DivisionByZero), Ok(())]);
+        assert_eq!(vm.get_result(), 5);
+    }
 
-        match vm.last_result {
-            Value::Str(ref s) => assert_eq!(s, "Hello, World!"),
-            _ => panic!("Expected string result"),
-        }
+    /// Tests running source text with `Vm::run`, keeping definitions between calls.
+    #[test]
+    fn test_vm_run() {
+        let mut vm = Vm::new();
+        let v = vm.run("return 2+2;");
+        assert_eq!(v, Ok(Some(Value::Int(4))));
+        assert_eq!(vm.run("int twice(n) { return n * 2; } let x = 21;"), Ok(None));
+        assert_eq!(vm.run("return twice(x);"), Ok(Some(Value::Int(42))));
+        assert!(matches!(vm.run("return x +;"), Err(Error::Parse(_))));
     }
 
-    /// Tests global variable access inside a function.
+    /// Tests destructuring with a rest element collecting the remaining elements.
     #[test]
-    fn test_global_variable_access_in_function() {
-        let code = r#"
-            let x = 123;
+    fn test_let_destructuring_rest() {
+        assert_eq!(run_value("let [head, ...tail] = [1, 2, 3]; return [head, tail];"), run_value("return [1, [2, 3]];"));
+        assert_eq!(run_value("let [a, b, ...rest] = [1, 2]; return rest;"), Value::Array(vec![]));
+        assert_eq!(run_value("let [...all] = [4, 5]; return all;"), Value::Array(vec![Value::Int(4), Value::Int(5)]));
+    }
 
-            int get() {
-                return x;
-            }
+    /// Tests that a rest pattern still needs an element for each name before it.
+    #[test]
+    fn test_let_destructuring_rest_empty_array() {
+        assert_eq!(
+            run_error("let [head, ...tail] = [];"),
+            RuntimeError::Other("Cannot destructure an array of 0 elements into at least 1 variables".to_string())
+        );
+    }
 
-            return get();
-        "#;
+    /// Tests that the rest element must come last in the pattern.
+    #[test]
+    fn test_let_destructuring_rest_not_last() {
+        assert_eq!(
+            parse_error("let [...init, last] = [1, 2];").message,
+            "Expected ']' after the rest element in 'let [...]'"
+        );
+    }
 
-        assert_eq!(run(code), 123);
+    /// Tests creating a VM from a fully populated `VmConfig`.
+    #[test]
+    fn test_vm_with_config() {
+        let (out, err) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut vm = Vm::with_config(VmConfig {
+            collect_results: true,
+            array_print_limit: Some(3),
+            max_value_size: Some(10),
+            implicit_return: true,
+            output: Box::new(out.clone()),
+            error_output: Box::new(err.clone()),
+            max_call_depth: 10,
+        });
+        assert_eq!(vm.run(r#"println([1, 2, 3, 4, 5]); eprint("warn"); 6 * 7;"#), Ok(None));
+        assert_eq!(out.contents(), "[1, 2, ..., 5]\n");
+        assert_eq!(err.contents(), "warn\n");
+        assert_eq!(vm.results(), &[Value::Int(0), Value::Int(42)]);
+        assert!(matches!(vm.run(r#"return repeat("ab", 6);"#), Err(Error::Runtime { .. })));
+    }
+
+    /// Tests that a misplaced token is reported at its own column, not after the whitespace before it.
+    #[test]
+    fn test_error_reports_token_column() {
+        assert_eq!(parse_error("let x = 1    2;").to_string(), "Expected ';' after let at line 1, column 14");
+        assert_eq!(parse_error("if (1) {}\nprint 5;").to_string(), "Expected '(' after 'print' at line 2, column 7");
+    }
+
+    /// Runs a program with `implicit_return` enabled and returns its result.
+    fn run_implicit(code: &str) -> Value {
+        let mut vm = Vm::with_config(VmConfig { implicit_return: true, ..VmConfig::default() });
+        vm.run(code).unwrap_or_else(|err| panic!("{}", err));
+        vm.last_result
+    }
+
+    /// Tests that a final expression statement gives the same result as an explicit `return`.
+    #[test]
+    fn test_implicit_return_matches_explicit() {
+        let explicit = "int sum_to(n) { let total = 0; while (n > 0) { total = total + n; n--; } return total; } return sum_to(4);";
+        let implicit = "int sum_to(n) { let total = 0; while (n > 0) { total = total + n; n--; } total; } return sum_to(4);";
+        assert_eq!(run_implicit(explicit), Value::Int(10));
+        assert_eq!(run_implicit(implicit), Value::Int(10));
+        assert_eq!(run_value(implicit), Value::Int(0)); // Off by default
+    }
+
+    /// Tests that an earlier `return` still wins over the final expression.
+    #[test]
+    fn test_implicit_return_after_early_return() {
+        let code = "int sign(n) { if (n < 0) { return 0 - 1; } n > 0; } return [sign(0 - 5), sign(0), sign(3)];";
+        assert_eq!(run_implicit(code), Value::Array(vec![Value::Int(-1), Value::Bool(false), Value::Bool(true)]));
+    }
+
+    /// Tests that `void` functions don't return their final expression.
+    #[test]
+    fn test_implicit_return_skips_void() {
+        assert_eq!(run_implicit("void twice(n) { n * 2; } return twice(21);"), Value::Int(0));
+        assert_eq!(run_implicit("int twice(n) { n * 2; } return twice(21);"), Value::Int(42));
+    }
+
+    /// Tests that a misplaced number is reported at its first digit.
+    #[test]
+    fn test_error_column_at_token_start() {
+        let err = parse_error("let 123 = 1;");
+        assert_eq!((err.line, err.col), (1, 5)); // The `1` of `123`
+        assert_eq!(err.message, "Expected identifier after 'let'");
+    }
+
+    /// Tests that `print_base` and `println_base` print an integer in the given base.
+    #[test]
+    fn test_print_base() {
+        let out = SharedBuffer::default();
+        let mut vm = Vm::with_config(VmConfig { output: Box::new(out.clone()), ..VmConfig::default() });
+        vm.run("println_base(255, 16); println_base(0xF0 & 0x3C, 2); print_base(0 - 8, 8); print_base(5, 10);").unwrap();
+        assert_eq!(out.contents(), "ff\n110000\n-105"); // `print_base` leaves the line open, like `print`
+        assert!(matches!(vm.run("print_base(1, 7);"), Err(Error::Runtime { .. })));
+    }
+
+    /// Tests assigning to struct fields, including from an inner block.
+    #[test]
+    fn test_struct_field_assignment() {
+        let code = "struct Point { int x; int y; }; let p: Point = {1, 2}; p.x = 3; p.y = p.y + p.x; return p.x * 10 + p.y;";
+        assert_eq!(run(code), 35);
+        let code = "struct Point { int x; int y; }; let p: Point = {}; p.y = 7; return p;";
+        let fields = vec![("x".to_string(), Value::Int(0)), ("y".to_string(), Value::Int(7))];
+        assert_eq!(run_value(code), Value::Struct("Point".to_string(), fields));
+        // The assignment writes back to the variable's own scope
+        let code = "struct Point { int x; int y; }; let p: Point = {}; { p.x = 4; } return p.x;";
+        assert_eq!(run(code), 4);
+    }
+
+    /// Tests that assigning to or reading a missing field, or a field of a non-struct, is an error.
+    #[test]
+    fn test_struct_field_assignment_errors() {
+        let code = "struct Point { int x; int y; }; let p: Point = {}; p.z = 1;";
+        assert_eq!(run_error(code), RuntimeError::Other("Struct 'Point' has no field 'z'".to_string()));
+        let code = "let n = 5; n.x = 1;";
+        assert_eq!(run_error(code), RuntimeError::TypeMismatch("Cannot assign to field 'x' of non-struct value 5".to_string()));
+        let code = "let n = 5; return n.x;";
+        assert_eq!(run_error(code), RuntimeError::TypeMismatch("Cannot access field 'x' of non-struct value 5".to_string()));
+    }
+
+    /// Tests `sizeof` applied to variables rather than types.
+    #[test]
+    fn test_sizeof_variable() {
+        assert_eq!(run("let a: int[4]; return sizeof(a);"), 16);
+        assert_eq!(run("let buf: char[3] = {}; return sizeof(buf);"), 3);
+        assert_eq!(run("let a = [1, 2, 3]; return sizeof(a) / sizeof(a[0]);"), 3);
+        assert_eq!(run("struct Point { int x; int y; }; let p: Point; return sizeof(p);"), 8);
+        assert_eq!(run("float f = 1.5; let n = 7; return sizeof(f) + sizeof(n);"), 8);
     }
 
-    /// Tests modifying a global variable inside a function.
+    /// Tests declarations without an initializer, which hold zeroes.
     #[test]
-    fn test_global_variable_modification_in_function() {
-        let code = r#"
-            let x = 10;
-
-            int modify() {
-                x = x + 5;
-            }
-
-            modify();
-            return x;
-        "#;
+    fn test_declaration_without_initializer() {
+        assert_eq!(run_value("let a: int[3]; return a;"), Value::Array(vec![Value::Int(0); 3]));
+        assert_eq!(run_value("int n; float x; return n + x;"), Value::Float(0.0));
+        assert_eq!(run("let a: int[2], n; a[1] = 5; return a[1] + n;"), 5);
+    }
 
-        assert_eq!(run(code), 15);
+    /// Tests chains of field access, indexing and calls, including as assignment targets.
+    #[test]
+    fn test_chained_access() {
+        let code = "
+            struct Point { int x; int y; };
+            let a: Point = {1, 2};
+            let b: Point = {3, 4};
+            let points = [a, b];
+            points[1].x = 30;
+            return points[1].x + points[0].y;
+        ";
+        assert_eq!(run(code), 32);
+        let code = "
+            struct Shape { int[3] vertices; int id; };
+            let shape: Shape = {};
+            shape.vertices[2] = 7;
+            return shape.vertices;
+        ";
+        assert_eq!(run_value(code), Value::Array(vec![Value::Int(0), Value::Int(0), Value::Int(7)]));
+        assert_eq!(run("let grid = [[1, 2], [3, 4]]; grid[1][0] = 9; return grid[1][0] + grid[0][1];"), 11);
+        assert_eq!(run("int square(n) { return n * n; } let fs = [square]; return fs[0](4);"), 16);
+        let code = "let grid = [[1, 2]]; grid[0][5] = 1;";
+        assert_eq!(run_error(code), RuntimeError::IndexOutOfBounds { len: 2, idx: 5 });
     }
 
-    /// Tests global variable shadowing within nested blocks.
+    /// Tests indexing into a string, which gives character codes.
     #[test]
-    fn test_global_variable_shadowing() {
-        let code = r#"
-            let x = 7;
+    fn test_string_index() {
+        assert_eq!(run("let s = \"abc\"; return s[1];"), 98);
+        assert_eq!(run("let s = \"héllo\"; return s[1] == 'é';"), 1);
+        let code = "let s = \"abc\"; let n = 0; for (let i = 0; i < len(s); i++) { n = n + s[i]; } return n;";
+        assert_eq!(run(code), 97 + 98 + 99);
+        assert_eq!(run_error("let s = \"abc\"; return s[3];"), RuntimeError::IndexOutOfBounds { len: 3, idx: 3 });
+    }
 
-            {
-                let x = 42;
-                print(x); // should print 42
-            }
+    /// Tests ordering comparisons between strings.
+    #[test]
+    fn test_string_ordering() {
+        assert_eq!(run("return \"abc\" < \"abd\";"), 1);
+        assert_eq!(run("return \"b\" > \"a\";"), 1);
+        assert_eq!(run("return \"ab\" < \"abc\";"), 1);
+        assert_eq!(run("return (\"a\" <= \"a\") && !(\"a\" >= \"b\");"), 1);
+        assert_eq!(run_error("return \"a\" - \"b\";"), RuntimeError::TypeMismatch("Unsupported string operation: '-'".to_string()));
+    }
 
-            return x; // should return 7
-        "#;
+    /// Tests that `\0` is a one-character string holding code 0.
+    #[test]
+    fn test_nul_escape() {
+        assert_eq!(run("let s = \"\\0\"; return len(s) * 10 + s[0];"), 10);
+        assert_eq!(run("return \"a\\x41b\"[1];"), 65);
+    }
 
-        assert_eq!(run(code), 7);
+    /// Tests declaring and using variables and functions with non-ASCII names.
+    #[test]
+    fn test_unicode_variable_names() {
+        assert_eq!(run("let größe = 4; let 面积 = größe * größe; return 面积;"), 16);
+        assert_eq!(run("int dòppio(n) { return n * 2; } return dòppio(21);"), 42);
     }
 
-    /// Tests comma-separated variable declarations in a single statement.
+    /// Tests `switch` dispatching to the matching case, or to `default`.
     #[test]
-    fn test_comma_separated_let_declaration() {
+    fn test_switch() {
         let code = "
-            let x = 1, y = 2, z = x + y;
-            return z;
+            int name(n) {
+                let result = 0;
+                switch (n) {
+                    case 1: result = 10;
+                    case 2:
+                        result = 20;
+                        result = result + 1;
+                    default: result = -1;
+                }
+                return result;
+            }
+            return [name(1), name(2), name(3)];
         ";
-        assert_eq!(run(code), 3);
+        assert_eq!(run_value(code), Value::Array(vec![Value::Int(10), Value::Int(21), Value::Int(-1)]));
+        // Without a matching case or a `default`, nothing runs
+        assert_eq!(run("let x = 5; switch (x) { case 1: x = 0; } return x;"), 5);
+        // `return` inside a case returns from the function
+        assert_eq!(run("int f(n) { switch (n) { case 3: return 30; } return 0; } return f(3);"), 30);
     }
 
-    /// Tests parentheses overriding the default precedence in expressions.
+    /// Tests that empty cases share the next case's body, and that case values may be strings.
     #[test]
-    fn test_parentheses_override_precedence() {
+    fn test_switch_shared_cases() {
         let code = "
-            let a = 2;
-            let b = 3;
-            let c = 4;
-            let d = 20;
-            return (a + b) * c == d;
+            int kind(c) {
+                switch (c) {
+                    case 'a': case 'e': case 'i': return 1;
+                    case \"x\": return 2;
+                    default: return 0;
+                }
+            }
+            return kind('e') * 100 + kind(\"x\") * 10 + kind('z');
         ";
-        assert_eq!(run(code), 1);
+        assert_eq!(run(code), 120);
     }
 
-    /// Tests the `sizeof` operator for different types.
+    /// Tests that a switch may have only one `default`.
     #[test]
-    fn test_sizeof_expression() {
-        let code = "
-            return sizeof(int);
-        ";
-        assert_eq!(run(code), 4);
+    fn test_switch_duplicate_default() {
+        let err = parse_error("switch (1) { default: print(1); default: print(2); }");
+        assert_eq!(err.message, "Multiple 'default' labels in switch");
     }
 
-    /// Tests `sizeof` for multiple types.
+    /// Tests that variables still shadow enum constants once constants are folded.
     #[test]
-    fn test_sizeof_multiple_types() {
-        assert_eq!(run("return sizeof(char);"), 1);
-        assert_eq!(run("return sizeof(bool);"), 1);
-        assert_eq!(run("return sizeof(str);"), 8);
+    fn test_variable_shadows_folded_constant() {
+        assert_eq!(run("enum { A = 5 }; int twice(A) { return A * 2; } return twice(3) + A;"), 11);
+        assert_eq!(run("enum { A = 5 }; A = 7; return A * 2;"), 14);
+        let mut vm = Vm::new();
+        vm.run("enum { N = 1 }; N = 4;").unwrap();
+        assert_eq!(vm.run("return N * 10;").unwrap(), Some(Value::Int(40)));
     }
 
-    /// Tests parsing and using enums in the language.
+    /// Tests writing through a pointer to the variable it points to.
     #[test]
-    fn test_enum_parsing_and_usage() {
+    fn test_pointer_write() {
+        assert_eq!(run("let x = 1; let p = &x; *p = 9; return x;"), 9);
+        assert_eq!(run("let x = 1; let p = &x; x = 4; return *p;"), 4);
         let code = "
-            enum { A = 5, B, C = 10, D };
-            return A + B + C + D; // 5 + 6 + 10 + 11 = 32
+            void set(p, v) { *p = v; }
+            let n = 0;
+            set(&n, 7);
+            return n;
         ";
+        assert_eq!(run(code), 7);
+        assert_eq!(run("let x = 1; let p = &x; let q = &p; **q = 3; return x;"), 3);
+    }
 
-        let lexer = Lexer::new(code);
-        let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
-
-        assert_eq!(vm.get_result(), 32);
+    /// Tests that distinct variables get distinct addresses and that an address is stable.
+    #[test]
+    fn test_pointer_addresses() {
+        assert_eq!(run("let x = 1; let y = 1; return &x != &y;"), 1);
+        assert_eq!(run("let x = 1; return &x == &x;"), 1);
+        assert_eq!(run("let x = 1; let y = 2; let p = &x; let q = &y; return q - p;"), 1);
     }
 
-    /// Tests type casting with different types.
+    /// Tests errors from invalid pointer use.
     #[test]
-    fn test_type_casting() {
-        let code = r#"
-            let x = (int)"hello";
-            let y = (char)300;
-            let z = (int)123;
-            return x + y + z;
-        "#;
-        assert_eq!(run(code), 167);
+    fn test_pointer_errors() {
+        assert_eq!(run_error("let n = 5; return *n;"), RuntimeError::TypeMismatch("Invalid pointer dereference".to_string()));
+        assert_eq!(run_error("return *(*int)0;"), RuntimeError::Other("Null pointer dereference".to_string()));
+        assert_eq!(run_error("return *(*int)99;"), RuntimeError::Other("Invalid pointer: no memory at address 99".to_string()));
+        assert_eq!(run_error("return &5;"), RuntimeError::Other("Can only take the address of a variable".to_string()));
     }
 
-    /// Tests printing from the main function.
+    /// Tests the rules for `(char)` and for casting strings to integers.
     #[test]
-    fn test_print_from_main() {
-        let code = r#"
-            void greet() {
-                print("Hello from C4!");
-            }
+    fn test_cast_semantics() {
+        assert_eq!(run("return (char)-1;"), 255);
+        assert_eq!(run("return (char)300;"), 44);
+        assert_eq!(run("return (char)-256;"), 0);
+        assert_eq!(run("return (int)\"42\";"), 42);
+        assert_eq!(run("return (int)\" -7 \";"), -7);
+        assert_eq!(run("return (int)\"4x\" + (int)\"99999999999\";"), 0);
+        assert_eq!(run("return (char)\"321\";"), 65);
+    }
 
-            int main() {
-                greet();
-                return 42;
-            }
+    /// Tests allocating memory with `malloc`, using it through the pointer and freeing it.
+    #[test]
+    fn test_malloc_free() {
+        assert_eq!(run("let p = malloc(3); p[0] = 40; p[2] = 2; let total = p[0] + p[1] + p[2]; free(p); return total;"), 42);
+        assert_eq!(run("let p = malloc(2); *p = 7; return p[0] + *(p + 1);"), 7);
+        assert_eq!(run("let p = malloc(2); free(p); let q = malloc(2); return p == q;"), 0); // Cells are reused, addresses aren't
+        assert_eq!(run("let p = malloc(4); p[3] = 9; free(p); let q = malloc(4); return q[3];"), 0); // Reused cells start zeroed
+        assert_eq!(run("free((*int)0); return 1;"), 1);
+    }
 
-            return main();
-        "#;
+    /// Tests errors from misusing `malloc` and `free`.
+    #[test]
+    fn test_malloc_free_errors() {
+        assert_eq!(run_error("let p = malloc(1); free(p); free(p);"), RuntimeError::Other(format!("Double free of address {}", 1)));
+        assert_eq!(run_error("let p = malloc(1); free(p); return p[0];"), RuntimeError::Other("Use after free: address 1 was freed".to_string()));
+        assert_eq!(run_error("let p = malloc(1); free(p); *p = 3;"), RuntimeError::Other("Use after free: address 1 was freed".to_string()));
+        assert_eq!(run_error("let x = 1; free(&x);"), RuntimeError::Other("free() of address 1 that wasn't returned by malloc".to_string()));
+        assert_eq!(run_error("malloc(0);"), RuntimeError::TypeMismatch("malloc() requires a positive number of cells, got 0".to_string()));
+        assert_eq!(run_error("free(5);"), RuntimeError::TypeMismatch("free() requires a pointer, got 5".to_string()));
+    }
 
-        let lexer = Lexer::new(code);
+    /// Tests that comparisons and logical operators give booleans, which print as `true`/`false`.
+    #[test]
+    fn test_bool_values() {
+        let out = SharedBuffer::default();
         let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
+        vm.set_output(Box::new(out.clone()));
+        vm.run("println(1 < 2); println(!1); println(true && 0); printf(\"%d\\n\", 3 == 3);").unwrap();
+        assert_eq!(out.contents(), "true\nfalse\nfalse\n1\n");
+        assert_eq!(run_value("return \"a\" == \"a\" || false;"), Value::Bool(true));
+        assert_eq!(run("if (1) { if (2 > 1) { return (int)true + (int)false; } } return 9;"), 1); // Conditions accept ints and bools
+        assert_eq!(run("return true == (1 < 2);"), 1);
+    }
 
-        assert_eq!(vm.get_result(), 42);
+    /// Tests that arithmetic on booleans is a type error.
+    #[test]
+    fn test_bool_arithmetic_errors() {
+        assert_eq!(run_error("return true + 1;"), RuntimeError::TypeMismatch("Mismatched types for operation '+': true and 1".to_string()));
+        assert_eq!(run_error("return true + false;"), RuntimeError::TypeMismatch("Unsupported boolean operation: '+'".to_string()));
+        assert_eq!(run_error("return -(1 < 2);"), RuntimeError::TypeMismatch("Cannot negate non-numeric value true".to_string()));
     }
 
-    /// Tests pre- and post-increment operators.
+    /// Tests that a `do/while` loop runs its body before checking the condition.
     #[test]
-    fn test_pre_post_increment() {
-        let code = "
-            let x = 5;
-            let a = ++x;  // x = 6, a = 6
-            let b = x++;  // b = 6, x = 7
-            return a + b + x;  // returns 19
-        ";
-        assert_eq!(run(code), 19);
+    fn test_do_while() {
+        assert_eq!(run("let runs = 0; do { runs++; } while (false); return runs;"), 1);
+        assert_eq!(run("let i = 0; let sum = 0; do { sum = sum + i; i++; } while (i < 5); return sum;"), 10);
+        assert_eq!(run("int f() { do { return 7; } while (true); } return f();"), 7);
+        assert_eq!(parse_error("do { } while (true)").message, "Expected ';' after do-while condition");
     }
 
-    /// Tests pre- and post-decrement operators.
+    /// Tests slicing arrays and strings with `[start..end]`.
     #[test]
-    fn test_pre_post_decrement() {
-        let code = "
-            let x = 10;
-            let a = --x;  // x = 9, a = 9
-            let b = x--;  // b = 9, x = 8
-            return a + b + x;  // 9 + 9 + 8 = 26
-        ";
-        assert_eq!(run(code), 26);
+    fn test_slice() {
+        assert_eq!(run_value("let arr = [10, 20, 30, 40]; return arr[1..3];"), run_value("return [20, 30];"));
+        assert_eq!(run_value("let arr = [10, 20, 30]; return arr[0..3];"), run_value("return [10, 20, 30];"));
+        assert_eq!(run_value("let arr = [10, 20, 30]; return arr[3..3];"), Value::Array(vec![]));
+        assert_eq!(run_value("let n = 2; return \"héllo\"[1..n + 2];"), Value::Str("éll".to_string()));
+        assert_eq!(run("let arr = [1, 2, 3, 4]; return arr[1..4][2];"), 4);
     }
 
-    /// Tests enum parsing with automatic increments.
+    /// Tests errors from slices with bad bounds.
     #[test]
-    fn test_enum_parsing_auto_increment() {
-        let code = "
-            enum { A = 10, B, C = 20, D };
-            return A + B + C + D;
-        ";
+    fn test_slice_errors() {
+        assert_eq!(run_error("let arr = [1, 2]; return arr[1..3];"), RuntimeError::Other("Slice 1..3 out of range for length 2".to_string()));
+        assert_eq!(run_error("let arr = [1, 2]; return arr[0 - 1..1];"), RuntimeError::Other("Slice -1..1 out of range for length 2".to_string()));
+        assert_eq!(run_error("return \"abc\"[2..1];"), RuntimeError::Other("Slice start 2 is greater than its end 1".to_string()));
+        assert_eq!(run_error("return 5[0..1];"), RuntimeError::TypeMismatch("Attempted to slice non-array value 5".to_string()));
+        assert_eq!(parse_error("let arr = [1]; return arr[0..1;").message, "Expected ']' after slice");
+    }
 
-        let lexer = Lexer::new(code);
-        let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
+    /// Tests building a struct with a literal that names its fields.
+    #[test]
+    fn test_struct_literal() {
+        let point = "struct Point { int x; int y; };";
+        assert_eq!(run(&format!("{} let p = Point {{ x: 3, y: 4 }}; return p.x * 10 + p.y;", point)), 34);
+        assert_eq!(run(&format!("{} let p = Point {{ y: 4, x: 3 }}; p.x = p.y + 1; return p.x;", point)), 5);
+        let fields = vec![("x".to_string(), Value::Int(0)), ("y".to_string(), Value::Int(2))];
+        assert_eq!(run_value(&format!("{} return Point {{ y: 2 }};", point)), Value::Struct("Point".to_string(), fields));
+        assert_eq!(
+            run_error(&format!("{} let p = Point {{ x: 1 }}; return p.z;", point)),
+            RuntimeError::Other("Struct 'Point' has no field 'z'".to_string())
+        );
+        assert_eq!(parse_error(&format!("{} let p = Point {{ z: 1 }};", point)).message, "Struct 'Point' has no field 'z'");
+        assert_eq!(parse_error(&format!("{} let p = Point {{ x: 1, x: 2 }};", point)).message, "Field 'x' given more than once in struct literal");
+    }
 
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
+    /// Tests nested array literals, chained indexing and declared multi-dimensional arrays.
+    #[test]
+    fn test_multi_dimensional_arrays() {
+        assert_eq!(run("let m = [[1, 2], [3, 4]]; return m[1][0];"), 3);
+        assert_eq!(run("let m = {{1, 2}, {3, 4}}; m[0][1] = m[1][1] * 10; return m[0][1];"), 40);
+        let code = "let m: int[2][3] = {{1, 2}, {3}}; return m;";
+        assert_eq!(run_value(code), run_value("return [[1, 2, 0], [3, 0, 0]];"));
+        assert_eq!(run_value("let m: int[2][2]; m[1][1] = 5; return m;"), run_value("return [[0, 0], [0, 5]];"));
+        assert_eq!(
+            run_error("let m: int[1][2] = {{1, 2, 3}};"),
+            RuntimeError::Other("Too many initializers for 'm': declared size 2, got 3".to_string())
+        );
+    }
 
-        assert_eq!(vm.get_result(), 62);
+    /// Tests `const` declarations, which are evaluated once and can't be assigned.
+    #[test]
+    fn test_const() {
+        assert_eq!(run("const PI = 3; return PI * 2;"), 6);
+        assert_eq!(run_value("const NAME = \"c4\"; const GREETING = \"hi \" + NAME; return GREETING;"), Value::Str("hi c4".to_string()));
+        assert_eq!(run("const N = 2; int f(N) { N = N + 1; return N; } return f(5) + N;"), 8); // A parameter may shadow it
+        let assign = RuntimeError::Other("Cannot assign to constant 'PI'".to_string());
+        assert_eq!(run_error("const PI = 3; PI = 4;"), assign);
+        assert_eq!(run_error("const PI = 3; PI++;"), assign);
+        assert_eq!(run_error("const PI = 3; { PI = 4; }"), assign);
+        assert_eq!(run_error("const PI = [3]; PI[0] = 4;"), assign);
+        assert_eq!(run_error("const PI = [3]; push(PI, 4);"), assign);
+        assert_eq!(run_error("const PI = 3; let p = &PI;"), assign);
+        assert_eq!(run_error("const PI = 3; let PI = 4;"), RuntimeError::Other("Cannot redeclare constant 'PI'".to_string()));
+        assert_eq!(run_error("let PI = 3; const PI = 4;"), RuntimeError::Other("Cannot redeclare 'PI' as a constant".to_string()));
     }
 
-    /// Tests modulus operation in expressions.
+    /// Tests the `**` operator: its precedence, right-associativity and errors.
     #[test]
-    fn test_modulus() {
-        let code = "return 10 % 3;";
-        assert_eq!(run(code), 1);
+    fn test_power() {
+        assert_eq!(run("return 2 ** 10 == 1024;"), 1);
+        assert_eq!(run("return 2 ** 3 ** 2;"), 512); // 2 ** (3 ** 2)
+        assert_eq!(run("return 3 * 2 ** 2;"), 12); // 3 * (2 ** 2)
+        assert_eq!(run("return -2 ** 2;"), -4); // -(2 ** 2)
+        assert_eq!(run("return 2 * -3 ** 2;"), -18);
+        assert_eq!(run("let n = 0; return n ** 0;"), 1);
+        assert_eq!(run_value("return 2.0 ** 0.5 > 1.41;"), Value::Bool(true));
+        assert_eq!(run_error("let e = 0 - 1; return 2 ** e;"), RuntimeError::Other("Negative exponent in integer power: 2 ** -1".to_string()));
+        assert_eq!(run_error("let b = 2; return b ** 31;"), RuntimeError::Other("Integer overflow in 2 ** 31".to_string()));
     }
 
-    /// Tests bitwise operations like AND, OR, XOR, and shifts.
+    /// Tests that `**` still means two dereferences or two pointer levels where an operand or type is expected.
     #[test]
-    fn test_bitwise_operations() {
-        let code = "
-            let a = 6;      // 0b0110
-            let b = 3;      // 0b0011
-            let and = a & b;    // 0b0010 -> 2
-            let or  = a | b;    // 0b0111 -> 7
-            let xor = a ^ b;    // 0b0101 -> 5
-            let shl = a << 1;   // 0b1100 -> 12
-            let shr = a >> 1;   // 0b0011 -> 3
-            return and + or + xor + shl + shr; // 2 + 7 + 5 + 12 + 3 = 29
-        ";
-        assert_eq!(run(code), 29);
+    fn test_double_star_pointers() {
+        assert_eq!(run("let x = 7; let p = &x; let pp = &p; return **pp;"), 7);
+        assert_eq!(run("let x = 7; let p = &x; let pp = &p; return 2 * **pp;"), 14);
+        assert_eq!(run("let x = 5; let p = &x; let q = (**int)(int)&p; return **q;"), 5);
+        assert_eq!(run("let x = 3; let y = 4; let p = &y; return x**p;"), 12); // `x * *p`, not a power
+        assert_eq!(run("let x = 3; let y = 4; let p = &y; let pp = &p; return x***pp;"), 12);
     }
 
-    /// Tests printing an array.
+    /// Tests that every `{ }` block has its own scope, even one holding only declarations.
     #[test]
-    fn test_print_array() {
+    fn test_block_scope() {
+        assert_eq!(run("let x = 5; { let x = 1; x = 2; } return x;"), 5);
+        assert_eq!(run_error("{ let x = 1; x = 2; } return x;"), RuntimeError::UndefinedVariable("x".to_string()));
+        assert_eq!(run_error("{ let y = 1; let z = 2; } return y;"), RuntimeError::UndefinedVariable("y".to_string()));
+        assert_eq!(run("let a = 1, b = 2; return a + b;"), 3); // One `let` declaring several variables isn't a block
+        assert_eq!(run("let n = 0; for (let i = 0, j = 4; i < j; i++) { n++; } return n;"), 4);
+    }
+
+    /// Tests catching runtime errors with `try`/`catch`.
+    #[test]
+    fn test_try_catch() {
         let code = r#"
-            let x = [1, 2, 3];
-            print(x); // should print: [1, 2, 3]
-            return x[1]; // return middle element to confirm indexing
+            let status = "ok";
+            try {
+                let x = 10 / 0;
+                status = "unreachable";
+            } catch (e) {
+                status = e;
+            }
+            return status;
         "#;
+        assert_eq!(run_value(code), Value::Str("Division by zero".to_string()));
+        // The error unwinds out of function calls, and the program continues after the handler
+        let code = "int at(arr, i) { return arr[i]; } let n = 0; try { n = at([1, 2], 5); } catch (err) { n = len(err); } return n + 1;";
+        assert_eq!(run(code), "Array index out of bounds: 5 (length 2)".len() as i32 + 1);
+        assert_eq!(run("let n = 1; try { n = 2; } catch (e) { n = 3; } return n;"), 2); // No error, so the handler is skipped
+        assert_eq!(run("int f() { try { return 1 / 0; } catch (e) { return 7; } } return f();"), 7);
+    }
 
-        let lexer = Lexer::new(code);
+    /// Tests that the error variable is scoped to the handler, and that errors in the handler propagate.
+    #[test]
+    fn test_try_catch_scoping() {
+        assert_eq!(run_error("try { 1 / 0; } catch (e) { } return e;"), RuntimeError::UndefinedVariable("e".to_string()));
+        assert_eq!(run_error("try { 1 / 0; } catch (e) { 1 % 0; }"), RuntimeError::ModuloByZero);
+        assert_eq!(run("try { try { 1 / 0; } catch (e) { 1 % 0; } } catch (e) { return len(e); } return 0;"), 14); // "Modulo by zero"
+        assert_eq!(parse_error("try { } return 1;").message, "Expected 'catch' after 'try' body");
+    }
+
+    /// Tests that runaway recursion fails with an error once `max_call_depth` calls are in progress.
+    #[test]
+    fn test_recursion_limit() {
+        let limited = || Vm::with_config(VmConfig { max_call_depth: 5, ..VmConfig::default() });
+        let mut vm = limited();
+        assert_eq!(vm.run("int down(n) { if (n == 0) { return 0; } return down(n - 1); } return down(4);").unwrap(), Some(Value::Int(0)));
+        let err = limited().run("int forever(n) { return forever(n + 1); } return forever(0);").unwrap_err();
+        assert_eq!(err.to_string(), "Recursion limit exceeded: more than 5 nested function calls at line 1");
+        // The depth unwinds with the error, so calls work again after it is caught
+        let code = "int forever(n) { return forever(n + 1); } int id(n) { return n; } try { forever(0); } catch (e) { } return id(id(id(7)));";
+        assert_eq!(limited().run(code).unwrap(), Some(Value::Int(7)));
+    }
+
+    /// Tests that the default call depth limit is reached before the stack of an ordinary thread
+    /// overflows, also when each call is made from inside nested loops.
+    #[test]
+    fn test_default_recursion_limit_fits_thread() {
+        let run_on_thread = |code: &'static str| {
+            std::thread::Builder::new()
+                .stack_size(2 * 1024 * 1024) // The default for threads spawned by Rust programs
+                .spawn(move || Vm::new().run(code).map(|_| ()).map_err(|err| err.to_string()))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        let expected = format!("Recursion limit exceeded: more than {} nested function calls at line 1", DEFAULT_MAX_CALL_DEPTH);
+        let simple = "int forever(n) { return forever(n + 1) + 1; } return forever(0);";
+        assert_eq!(run_on_thread(simple), Err(expected.clone()));
+        let nested = "int forever(n) { let t = 0; for (let i = 0; i < 1; i++) { if (n >= 0) { while (t == 0) { t = forever(n + 1) + 1; } } } return t; } return forever(0);";
+        assert_eq!(run_on_thread(nested), Err(expected));
+    }
+
+    /// Tests that a call runs the function's body in place rather than copying it.
+    #[test]
+    fn test_recursion_does_not_copy_body() {
+        // Both functions recurse equally deep and run the same statements, but `large` has a much
+        // bigger body, so any per-call copy of the body would show up as extra allocations
         let mut vm = Vm::new();
-        let mut parser = Parser::new(lexer, &mut vm);
-        let stmts = parser.parse();
+        let unused = "print(n + 1);".repeat(100);
+        vm.run(&format!("
+            int small(n) {{ if (n < 0) {{ print(n); }} if (n == 0) return 0; return small(n - 1); }}
+            int large(n) {{ if (n < 0) {{ {} }} if (n == 0) return 0; return large(n - 1); }}
+        ", unused)).unwrap();
+        let mut call = |name: &str| count_allocations(|| {
+            assert_eq!(vm.call_function(name, vec![Value::Int(10)]).unwrap(), Value::Int(0));
+        });
+        call("small"); // The first call allocates some things once, for all later calls
+        assert_eq!(call("large"), call("small"));
+    }
 
-        for stmt in stmts {
-            vm.execute(stmt);
-        }
+    /// Runs a `while` loop for `iterations` iterations and returns the number of allocations it made.
+    fn loop_allocations(iterations: i32) -> usize {
+        let mut vm = Vm::new();
+        let code = format!("int i = 0; int sum = 0; while (i < {}) {{ sum = sum + i % 7; i = i + 1; }}", iterations);
+        let program = vm.compile(&code).unwrap();
+        let allocations = count_allocations(|| vm.execute_program(program).unwrap());
+        assert_eq!(vm.get_variable("i"), Some(&Value::Int(iterations)));
+        allocations
+    }
 
-        assert_eq!(vm.get_result(), 2); // confirm array indexing works
+    /// Tests that running a loop's body again doesn't allocate, so a loop's allocations don't grow with its iterations.
+    #[test]
+    fn test_loop_does_not_allocate() {
+        // Only declaring the variables allocates, however many times the loop runs
+        assert_eq!(loop_allocations(10000), loop_allocations(1));
     }
 
-    /// Tests fake pointer dereferencing.
+    /// Tests that a million-iteration loop allocates no more than a single iteration does; it is slow
+    /// in a debug build, so run it with `cargo test --release -- --ignored`.
     #[test]
-    fn test_pointer_fake_deref() {
-        let code = "
-            let x = 42;
-            let p = &x;
-            let y = *p;
-            return y;
-        ";
-        assert_eq!(run(code), 42);
+    #[ignore]
+    fn bench_loop_allocations() {
+        assert_eq!(loop_allocations(1000000), loop_allocations(1));
     }
 
-    /// Tests identity of pointers.
+    /// Tests variables the resolver locates by scope and slot, alongside ones it leaves to be looked up by name.
     #[test]
-    fn test_pointer_identity() {
+    fn test_resolved_variables() {
         let code = "
-            let x = 123;
-            let ptr = &x;
-            return *ptr + 1;
+            int squares(n) { let total = 0; for (let i = 0; i < n; i++) { let sq = i * i; total = total + sq; } return total; }
+            int scaled(a, b = a * 2) { let c = a + b; { c++; let a = 100; c = c + a; } return c; }
+            return squares(4) * 1000 + scaled(1) + scaled(1, 5);
         ";
-        assert_eq!(run(code), 124);
+        assert_eq!(run(code), 14000 + 104 + 107);
+        // A function still sees its caller's variables, which the resolver can't locate
+        assert_eq!(run("int show() { return y; } int f() { let y = 7; return show(); } return f();"), 7);
+        // From the second iteration on, `x` is the loop's own variable rather than the parameter
+        let code = "int f(x) { let seen = 0; for (let i = 0; i < 2; i++) if (i == 0) let x = 10; else seen = x; return seen + x; } return f(1);";
+        assert_eq!(run(code), 11);
     }
 
-    /// Tests array assignment functionality.
+    /// Tests that a sum loop runs faster with its variables located by the resolver than looking
+    /// them up by name; timings are only meaningful optimized, so run it with `cargo test --release -- --ignored`.
     #[test]
-    fn test_array_assignment() {
-        let code = "
-            let arr = [0, 0, 0];
-            arr[1] = 42;
-            return arr[1];
-        ";
+    #[ignore]
+    fn bench_resolved_variables() {
+        let code = "int sum(n) { int s = 0; int i = 0; while (i < n) { s = s + i % 10; i = i + 1; } return s; } return sum(1000000);";
+        let time = |resolve: bool| {
+            let mut vm = Vm::new();
+            let stmts = Parser::new(Lexer::new(code), &mut vm).parse().unwrap();
+            let mut stmts = fold::fold_program(stmts, &vm.constants);
+            if resolve {
+                stmts = resolver::resolve_program(stmts);
+            }
+            let start = std::time::Instant::now();
+            vm.execute_program(stmts).unwrap();
+            assert_eq!(vm.get_result_value(), Some(&Value::Int(4500000)));
+            start.elapsed()
+        };
+        let by_name = time(false);
+        let resolved = time(true);
+        assert!(resolved < by_name, "resolved lookups took {:?}, lookups by name {:?}", resolved, by_name);
+    }
+
+    /// Tests that `print` leaves the line open and `println` ends it.
+    #[test]
+    fn test_print_and_println() {
+        let out = SharedBuffer::default();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(out.clone()));
+        vm.run(r#"for (let i = 1; i <= 3; i++) { print(i); print(" "); } println("go"); println([1, 2]); print("end");"#).unwrap();
+        assert_eq!(out.contents(), "1 2 3 go\n[1, 2]\nend");
+        assert_eq!(parse_error("println 1;").message, "Expected '(' after 'println'");
+    }
+
+    /// Tests `++` and `--` on pointers, which move them by one element, and walking a block with `*p++`.
+    #[test]
+    fn test_pointer_increment() {
+        let code = "let p = malloc(3); p[0] = 1; p[1] = 2; p[2] = 39; let q = p; let total = 0;
+                    for (let i = 0; i < 3; i++) { total = total + *p++; } free(q); return total;";
         assert_eq!(run(code), 42);
+        assert_eq!(run("let p = malloc(3); let q = p++; return p == q + 1;"), 1); // Postfix returns the old address
+        assert_eq!(run("let p = malloc(3); let q = p; return ++p == q + 1;"), 1); // Prefix returns the new one
+        assert_eq!(run("let p = malloc(3); p = p + 2; let q = p--; return q - p;"), 1);
+        assert_eq!(run("let x = 1; let p = &x; p++; p--; return *p;"), 1);
+        assert_eq!(run_error("let p = (*int)0; p--;"), RuntimeError::Other("Pointer arithmetic out of range: 0 + -1".to_string()));
     }
 
-    /// Tests handling of nested arrays in `sizeof`.
+    /// Tests that integer overflow is a runtime error, the same in debug and release builds, that `try` can catch.
     #[test]
-    #[should_panic(expected = "Nested arrays not supported")]
-    fn test_nested_array_sizeof() {
-        let code = "return sizeof(int[3][2]);";
-        run(code);
+    fn test_integer_overflow() {
+        let overflow = |text: &str| RuntimeError::Other(format!("Integer overflow in {}", text));
+        assert_eq!(run_error("let x = 2147483647; return x + 1;"), overflow("2147483647 + 1"));
+        assert_eq!(run_error("let x = 0 - 2147483647; return x - 2;"), overflow("-2147483647 - 2"));
+        assert_eq!(run_error("let x = 65536; return x * x;"), overflow("65536 * 65536"));
+        assert_eq!(run_error("let x = 0 - 2147483647 - 1; return x / (0 - 1);"), overflow("-2147483648 / -1"));
+        assert_eq!(run_error("let x = 0 - 2147483647 - 1; return x % (0 - 1);"), overflow("-2147483648 % -1"));
+        assert_eq!(run_error("let x = 1; return x << 32;"), overflow("1 << 32"));
+        assert_eq!(run_error("let x = 1; return x >> (0 - 1);"), overflow("1 >> -1"));
+        assert_eq!(run_error("let x = 2147483647; x++;"), overflow("x++"));
+        assert_eq!(run_error("let x = 0 - 2147483647 - 1; --x;"), overflow("--x"));
+        assert_eq!(run("let x = 2147483647; let caught = 0; try { x = x + 1; } catch (e) { caught = 1; } return caught;"), 1);
+
+        // A failed call leaves the call depth where it was, so a REPL session keeps working
+        let mut vm = Vm::new();
+        assert!(vm.run("int f(int x) { return x + 1; } return f(2147483647);").is_err());
+        assert_eq!(vm.call_depth, 0);
     }
 
-    /// Tests pointer casting in expressions.
+    /// Tests that errors in the source text stop parsing with their position, including one in the first token.
     #[test]
-    fn test_pointer_casting() {
-        let code = "
-            let x = 5;
-            let ptr = &x;
-            let val = (int)ptr;
-            return val / 1000;  // should return original value of x
-        ";
-        assert_eq!(run(code), 5);
+    fn test_lex_errors_stop_parsing() {
+        assert_eq!(parse_error("\"open"), ParseError::new("Unterminated string literal", 1, 6));
+        assert_eq!(parse_error("let x = 1;\nlet s = 'ab';"), ParseError::new("Expected closing quote", 2, 11));
+        assert_eq!(parse_error("let x = 0b2;"), ParseError::new("Invalid digit '2' in '0b2'", 1, 9));
+
+        let mut vm = Vm::new();
+        assert_eq!(vm.run("let s = \"open;").unwrap_err().to_string(), "Unterminated string literal at line 1, column 15");
     }
 
-    /// Tests using enums inside functions.
+    /// Tests that `(*` starts a dereference unless a type follows the stars, as in the cast `(*int)0`.
     #[test]
-    fn test_enum_inside_function() {
-        let code = "
-            enum { A = 1, B = 2 };
-            int sum() {
-                return A + B;
-            }
-            return sum();
-        ";
-        assert_eq!(run(code), 3);
+    fn test_parenthesized_dereference() {
+        assert_eq!(run("let x = 4; let p = &x; return (*p) + 1;"), 5);
+        assert_eq!(run("let x = 4; let p = &x; (*p)++; ++(*p); return x;"), 6);
+        assert_eq!(run("let x = 4; let p = &x; return (*p)-- + x;"), 7);
+        assert_eq!(run("let x = 4; let p = &x; let q = &p; return (**q) * 2;"), 8);
+        assert_eq!(run("let p = (**int)0; return p == (**int)0;"), 1);
+        assert_eq!(run_error("let p = (*int)0; (*p)++;"), RuntimeError::Other("Null pointer dereference".to_string()));
+    }
+
+    /// Tests that the memory cell of a variable whose address was taken is released with its scope.
+    #[test]
+    fn test_address_of_releases_cells() {
+        let mut vm = Vm::new();
+        vm.run("int f() { let x = 1; let p = &x; return *p; } f(); f(); f();
+                for (let i = 0; i < 100; i++) { let y = i; let p = &y; }").unwrap();
+        assert_eq!(vm.memory.len(), 1); // Only the unused cell 0: every call's and iteration's cell was released
+        vm.run("let z = 0; let p = &z;").unwrap();
+        assert_eq!(vm.memory.len(), 2);
+        assert_eq!(
+            run_error("let x = 1; let p = &x; let x = 2; let q = &x; return *p;"),
+            RuntimeError::Other("Use after free: address 1 was freed".to_string()) // `q` reuses the cell, not the address
+        );
+        assert_eq!(
+            run_error("int f() { let x = 1; return &x; } let p = f(); return *p;"),
+            RuntimeError::Other("Use after free: address 1 was freed".to_string())
+        );
+    }
+
+    /// Tests that a pointer into a freed block stays invalid after its cells are reused, and that
+    /// `p[i]` can't reach past the end of the block `p` points into.
+    #[test]
+    fn test_freed_and_neighbouring_blocks() {
+        let freed = RuntimeError::Other("Use after free: address 1 was freed".to_string());
+        assert_eq!(run_error("let p = malloc(2); free(p); let q = malloc(2); return p[0];"), freed);
+        assert_eq!(run_error("let p = malloc(2); free(p); let q = malloc(2); *p = 1;"), freed);
+        assert_eq!(run_error("let p = malloc(2); free(p); let q = malloc(2); free(p);"), RuntimeError::Other("Double free of address 1".to_string()));
+        assert_eq!(run_error("let p = malloc(2); let q = malloc(2); return p[2];"), RuntimeError::IndexOutOfBounds { len: 2, idx: 2 });
+        assert_eq!(run_error("let p = malloc(3); let q = p + 1; q[2] = 1;"), RuntimeError::IndexOutOfBounds { len: 2, idx: 2 });
+        assert_eq!(run_error("let p = malloc(2); free(p + 1);"), RuntimeError::Other("free() of address 2 that wasn't returned by malloc".to_string()));
+
+        // Freed cells are merged with their free neighbours, and given back at the end of memory
+        let mut vm = Vm::new();
+        vm.run("let a = malloc(2); let b = malloc(2); let c = malloc(1); free(a); free(b); let d = malloc(4);").unwrap();
+        assert_eq!(vm.memory.len(), 6); // `d` fits in the cells of `a` and `b`
+        vm.run("free(c); free(d); for (let i = 0; i < 100; i++) { free(malloc(3)); }").unwrap();
+        assert_eq!(vm.memory.len(), 1);
     }
 }
@@ -0,0 +1,164 @@
+//! Integration tests that invoke the compiler binary.
+use std::process::{Command, Output};
+
+/// Runs the compiler binary with the given arguments.
+fn compiler(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(args)
+        .output()
+        .expect("Failed to run compiler binary")
+}
+
+/// Tests that `--eval` runs a one-liner and prints its result.
+#[test]
+fn test_eval_prints_result() {
+    let output = compiler(&["--eval", "return 2 + 2;"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "4\n");
+}
+
+/// Tests that `--eval` reports runtime errors on stderr and exits non-zero.
+#[test]
+fn test_eval_runtime_error() {
+    let output = compiler(&["--eval", "return 1 / 0;"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Division by zero"));
+}
+
+/// Tests that `--eval` reports parse errors on stderr and exits non-zero.
+#[test]
+fn test_eval_parse_error() {
+    let output = compiler(&["--eval", "let x = ;"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: Unexpected token"));
+}
+
+/// Tests that an empty program is reported instead of printing a misleading result.
+#[test]
+fn test_eval_empty_program() {
+    for code in &["", "  \n  ", "// only a comment"] {
+        let output = compiler(&["--eval", code]);
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "Program finished. No statements to execute.\n"
+        );
+    }
+}
+
+/// Tests the printed form of each kind of final result.
+#[test]
+fn test_eval_result_kinds() {
+    let cases = [
+        ("return 7;", "7\n"),
+        (r#"return "hi";"#, "\"hi\"\n"),
+        ("return [1, 2, 3];", "[1, 2, 3]\n"),
+        ("return fdiv(1, 2);", "0.5\n"),
+        ("let x = 1;", ""), // No `return`: a void result prints nothing
+    ];
+    for (code, expected) in &cases {
+        let output = compiler(&["--eval", code]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), *expected, "for {}", code);
+    }
+}
+
+/// Tests that `--repl` keeps going after an error on one line.
+#[test]
+fn test_repl_session() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run compiler binary");
+    child.stdin.take().unwrap().write_all(b"let x = 4;\n1 / 0;\nx * 2;\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> > error: Division by zero\n> 8\n> \n");
+}
+
+/// Tests that `--time` reports the statistics on stderr, leaving stdout to the program.
+#[test]
+fn test_time_stats() {
+    let output = compiler(&["--eval", "let x = 1; while (x < 3) { x++; } return x;", "--time"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stats = stderr.trim_end().strip_prefix("time: parse ").expect("missing stats line");
+    let parts: Vec<&str> = stats.split(", ").collect();
+    assert_eq!(parts.len(), 3, "unexpected stats line: {}", stderr);
+    assert!(parts[0].ends_with("ms") && parts[0].trim_end_matches("ms").parse::<f64>().is_ok());
+    assert!(parts[1].starts_with("execute ") && parts[1].ends_with("ms"));
+    assert_eq!(parts[2], "7 statements"); // let, while, twice the body block and `x++;`, return
+}
+
+/// Tests reading the whole program from stdin when it is piped and no file is given.
+#[test]
+fn test_program_from_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run compiler binary");
+    child.stdin.take().unwrap().write_all(b"int twice(n) {\n    return n * 2;\n}\nreturn twice(21);\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Program finished. Final result = 42\n");
+}
+
+/// Tests running the program in a file named on the command line.
+#[test]
+fn test_program_from_file() {
+    let path = std::env::temp_dir().join(format!("c4_cli_test_{}.c4", std::process::id()));
+    std::fs::write(&path, "return 6 * 7;\n").unwrap();
+    let output = compiler(&[path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Program finished. Final result = 42\n");
+}
+
+/// Tests that infinite recursion is reported as an error rather than crashing with a stack overflow.
+#[test]
+fn test_recursion_limit() {
+    let output = compiler(&["--eval", "int forever(n) { return forever(n + 1); } return forever(0);"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Recursion limit exceeded: more than 10000 nested function calls"));
+}
+
+/// Tests that output a `print` leaves mid-line is ended before the result line or the REPL prompt.
+#[test]
+fn test_print_output_ends_before_result() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let output = compiler(&["--eval", "print(5); return 1;"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n1\n");
+    let output = compiler(&["--eval", "println(5); return 1;"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n1\n"); // No blank line after a `println`
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run compiler binary");
+    child.stdin.take().unwrap().write_all(b"print(\"hi\");\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\nProgram finished.\n");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run compiler binary");
+    child.stdin.take().unwrap().write_all(b"print(3);\nprint(4); 5;\nprint(6); 1 / 0;\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> 3\n> 4\n5\n> 6\nerror: Division by zero\n> \n");
+}